@@ -6,12 +6,22 @@ mod utils;
 pub mod middlewares;
 
 use chrono::{DateTime, Utc};
-pub use pubsub::{pg::PgSubscriber, AppEvent, Subscriber};
+#[cfg(feature = "amqp")]
+pub use pubsub::RabbitMqPubSub;
+pub use pubsub::{
+    pg::{PgPublisher, PgSubscriber, APP_EVENT_CHANNEL},
+    AppEvent, AppMessage, CommandReply, InMemoryPubSub, Notification, Publisher, Subscriber,
+    Typing, CHAT_EXCHANGE,
+};
 use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
 use sqlx::FromRow;
 pub use utils::*;
 use utoipa::ToSchema;
 
+/// Cookie chat_server sets a stream ticket in, for notify_server to redeem.
+pub const STREAM_TICKET_COOKIE: &str = "stream_ticket";
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct User {
     pub id: i64,
@@ -22,6 +32,10 @@ pub struct User {
     #[serde(skip)]
     pub password_hash: Option<String>,
     pub created_at: DateTime<Utc>,
+    /// What the user may do when authenticated with an API token, anything when `None`.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Scope>>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -57,6 +71,41 @@ pub struct Chat {
     pub r#type: ChatType,
     pub members: Vec<i64>,
     pub created_at: DateTime<Utc>,
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+}
+
+/// Permissions an API token can be granted.
+#[derive(Debug, Clone, Copy, ToSchema, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "api_token_scope", rename_all = "snake_case")]
+pub enum Scope {
+    ReadMessages,
+    PostMessages,
+    ManageChats,
+}
+
+impl PgHasArrayType for Scope {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_api_token_scope")
+    }
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "presence_status", rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Presence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -65,8 +114,42 @@ pub struct Message {
     pub chat_id: i64,
     pub sender_id: i64,
     pub content: String,
-    pub files: Vec<String>,
+    #[sqlx(json)]
+    pub files: Vec<FileAttachment>,
     pub created_at: DateTime<Utc>,
+    // filled in on published events, so clients can render it without a lookup
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+}
+
+/// A file attached to a message.
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct FileAttachment {
+    pub id: i64,
+    /// The name it was uploaded with.
+    pub name: String,
+    /// Size in bytes.
+    pub size: i64,
+    pub mime: String,
+    pub url: String,
+    /// Dimensions of an image, in pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Smaller versions of an image, to show in place of it.
+    #[serde(default)]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// An image scaled down to fit a square of `size` pixels.
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct Thumbnail {
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
 }
 
 impl User {
@@ -78,6 +161,15 @@ impl User {
             email: email.to_string(),
             password_hash: None,
             created_at: chrono::Utc::now(),
+            scopes: None,
+        }
+    }
+
+    /// Whether the user may act within `scope`.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.contains(&scope),
+            None => true,
         }
     }
 }
@@ -9,13 +9,14 @@ use axum_extra::{
     TypedHeader,
 };
 use std::fmt::Debug;
+use std::future::Future;
 use tracing::warn;
 
 use crate::User;
 
 pub trait TokenVerify {
     type Error: Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    fn verify(&self, token: &str) -> impl Future<Output = Result<User, Self::Error>> + Send;
 }
 
 pub async fn verify_token<T>(State(state): State<T>, req: Request, next: Next) -> Response
@@ -27,7 +28,7 @@ where
         match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
             Ok(TypedHeader(Authorization(bearer))) => {
                 let token = bearer.token();
-                match state.verify(token) {
+                match state.verify(token).await {
                     Ok(user) => {
                         let mut req = Request::from_parts(parts, body);
                         req.extensions_mut().insert(user);
@@ -71,7 +72,7 @@ mod tests {
     impl TokenVerify for AppState {
         type Error = ();
 
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<User, Self::Error> {
             self.0.dk.verify(token).map_err(|_| ())
         }
     }
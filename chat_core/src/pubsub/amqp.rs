@@ -1,48 +1,69 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use dashmap::DashMap;
 use futures::StreamExt as _;
 use lapin::{
     options::{
-        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicRejectOptions,
-        ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+        BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, BasicQosOptions,
+        BasicRejectOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
     },
     types::{AMQPValue, FieldTable},
-    BasicProperties, Connection,
+    BasicProperties, Connection, ConnectionProperties,
 };
 use serde::Serialize;
-use tokio::sync::mpsc;
-use tracing::{error, info};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
 
-use super::{AppMessage, Publisher, Subscriber};
+use super::{AppMessage, Notification, Publisher, Subscriber, CHAT_EXCHANGE};
+
+const DEAD_LETTER_EXCHANGE: &str = "chat-dead-letter.exchange";
+const DEAD_LETTER_QUEUE: &str = "chat-dead-letter.queue";
+/// Max unacked deliveries buffered by one instance.
+const PREFETCH_COUNT: u16 = 100;
+
+type SubscriberMap = DashMap<u64, Vec<mpsc::Sender<AppMessage>>>;
 
 /// A publisher & subscriber that publish & subscribe to events in a RabbitMQ queue.
+///
+/// Every instance consumes from its own exclusive queue bound to [`CHAT_EXCHANGE`], so each event
+/// reaches every notify_server node and is delivered by whichever holds the user's connections.
+#[derive(Clone)]
 pub struct RabbitMqPubSub {
     connection: Arc<Connection>,
-    subscribers: Arc<DashMap<u64, Vec<mpsc::Sender<AppMessage>>>>,
+    subscribers: Arc<SubscriberMap>,
+    /// Stops the consumer task, which is awaited on close.
+    closed: Arc<Notify>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl RabbitMqPubSub {
+    /// Connect to the RabbitMQ server at `url` and create a publisher & subscriber on it.
+    pub async fn connect(url: &str) -> anyhow::Result<Self> {
+        Self::new(Connection::connect(url, ConnectionProperties::default()).await?).await
+    }
+
     /// Create a new RabbitMQ publisher & subscriber.
-    #[allow(unused)]
     pub async fn new(connection: Connection) -> anyhow::Result<Self> {
         let ret = Self {
             connection: Arc::new(connection),
             subscribers: Arc::new(DashMap::new()),
+            closed: Arc::new(Notify::new()),
+            task: Arc::new(Mutex::new(None)),
         };
 
-        ret.init_queue().await?;
+        ret.init_exchange().await?;
         ret.background_task().await?;
         Ok(ret)
     }
 
-    /// Initialize the RabbitMQ queue.
-    async fn init_queue(&self) -> anyhow::Result<()> {
+    /// Initialize the RabbitMQ exchanges shared by all instances.
+    async fn init_exchange(&self) -> anyhow::Result<()> {
         let declare_channel = self.connection.create_channel().await?;
 
         declare_channel
             .exchange_declare(
-                "chat-dead-letter.exchange",
+                DEAD_LETTER_EXCHANGE,
                 lapin::ExchangeKind::Direct,
                 ExchangeDeclareOptions::default(),
                 FieldTable::default(),
@@ -50,7 +71,7 @@ impl RabbitMqPubSub {
             .await?;
         declare_channel
             .queue_declare(
-                "chat-dead-letter.queue",
+                DEAD_LETTER_QUEUE,
                 QueueDeclareOptions {
                     durable: true,
                     ..Default::default()
@@ -60,8 +81,8 @@ impl RabbitMqPubSub {
             .await?;
         declare_channel
             .queue_bind(
-                "chat-dead-letter.queue",
-                "chat-dead-letter.exchange",
+                DEAD_LETTER_QUEUE,
+                DEAD_LETTER_EXCHANGE,
                 "",
                 QueueBindOptions::default(),
                 FieldTable::default(),
@@ -70,8 +91,8 @@ impl RabbitMqPubSub {
 
         declare_channel
             .exchange_declare(
-                "chat.exchange",
-                lapin::ExchangeKind::Direct,
+                CHAT_EXCHANGE,
+                lapin::ExchangeKind::Fanout,
                 ExchangeDeclareOptions {
                     durable: true,
                     ..Default::default()
@@ -79,97 +100,146 @@ impl RabbitMqPubSub {
                 FieldTable::default(),
             )
             .await?;
+        declare_channel.close(0, "declare channel fineshed").await?;
+
+        Ok(())
+    }
+
+    /// Background task to consume messages from this instance's queue.
+    async fn background_task(&self) -> anyhow::Result<()> {
+        let consumer_channel = self.connection.create_channel().await?;
+        consumer_channel
+            .basic_qos(PREFETCH_COUNT, BasicQosOptions::default())
+            .await?;
 
-        // The chat.queue is set up with a dead-letter configuration, so any messages that can't be
-        // processed will be sent to the dead-letter exchange.
+        // The instance queue is exclusive to this connection and removed with it. It is set up
+        // with a dead-letter configuration, so any messages that can't be processed will be sent
+        // to the dead-letter exchange.
         let mut queue_field = FieldTable::default();
         queue_field.insert(
             "x-dead-letter-exchange".into(),
-            AMQPValue::LongString("chat-dead-letter.exchange".into()),
+            AMQPValue::LongString(DEAD_LETTER_EXCHANGE.into()),
         );
-        declare_channel
+        let queue = consumer_channel
             .queue_declare(
-                "chat.queue",
+                "",
                 QueueDeclareOptions {
-                    durable: true,
+                    exclusive: true,
+                    auto_delete: true,
                     ..Default::default()
                 },
                 queue_field,
             )
             .await?;
-        declare_channel
+        let queue_name = queue.name().to_string();
+        consumer_channel
             .queue_bind(
-                "chat.queue",
-                "chat.exchange",
+                &queue_name,
+                CHAT_EXCHANGE,
                 "",
                 QueueBindOptions::default(),
                 FieldTable::default(),
             )
             .await?;
-        declare_channel.close(0, "declare channel fineshed").await?;
 
-        Ok(())
-    }
-
-    /// Background task to consume messages from the RabbitMQ queue.
-    async fn background_task(&self) -> anyhow::Result<()> {
-        let consumer_channel = self.connection.create_channel().await?;
+        let consumer_tag = format!("notify-{}", uuid::Uuid::now_v7());
         let mut consumer = consumer_channel
             .basic_consume(
-                "chat.queue",
-                "consumer",
+                &queue_name,
+                &consumer_tag,
                 BasicConsumeOptions::default(),
                 FieldTable::default(),
             )
             .await?;
 
         let map = Arc::clone(&self.subscribers);
-
-        tokio::spawn(async move {
-            info!("listening to chat.queue");
-
-            while let Some(Ok(delivery)) = consumer.next().await {
-                match serde_json::from_slice::<AppMessage>(delivery.data.as_slice()) {
-                    Ok(app_message) => {
+        let closed = Arc::clone(&self.closed);
+
+        let task = tokio::spawn(async move {
+            info!("listening to {} as {}", queue_name, consumer_tag);
+
+            loop {
+                let delivery = tokio::select! {
+                    _ = closed.notified() => break,
+                    delivery = consumer.next() => match delivery {
+                        Some(Ok(delivery)) => delivery,
+                        _ => break,
+                    },
+                };
+                match Notification::decode(delivery.data.as_slice()) {
+                    Ok(notification) => {
                         info!("received message");
-                        // if subscribers is found by corresponding user_id, send the message to these subscribers
-                        let senders = map.get_mut(&app_message.user_id);
-                        delivery.ack(BasicAckOptions::default()).await.unwrap();
-                        if let Some(senders) = senders {
-                            for sender in senders.iter() {
-                                if let Err(e) = sender.send(app_message.clone()).await {
-                                    error!("Failed to send message: {:?}", e);
-                                }
-                            }
+                        deliver(&map, &notification).await;
+                        // ack only once the message has been handed to local subscribers
+                        if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+                            error!("Failed to ack message: {:?}", e);
                         }
                     }
                     Err(err) => {
                         error!("Reject {}", err);
-                        delivery
-                            .reject(BasicRejectOptions::default())
-                            .await
-                            .unwrap();
+                        if let Err(e) = delivery.reject(BasicRejectOptions::default()).await {
+                            error!("Failed to reject message: {:?}", e);
+                        }
                     }
                 }
             }
+            // dropping the senders ends the streams
+            map.clear();
+            info!("stopped consuming {}", queue_name);
         });
+        *self.task.lock().expect("poisoned lock") = Some(task);
 
         Ok(())
     }
 }
 
+/// Send the notification to every local subscriber of the affected users, dropping closed ones.
+async fn deliver(map: &SubscriberMap, notification: &Notification) {
+    for app_message in notification.messages() {
+        let user_id = app_message.user_id;
+        let senders = match map.get(&user_id) {
+            Some(senders) => senders.clone(),
+            None => continue,
+        };
+        for sender in senders.iter() {
+            if let Err(e) = sender.send(app_message.clone()).await {
+                warn!("Failed to send message: {:?}", e);
+            }
+        }
+
+        if let Some(mut senders) = map.get_mut(&user_id) {
+            senders.retain(|sender| !sender.is_closed());
+        }
+        map.remove_if(&user_id, |_, senders| senders.is_empty());
+    }
+}
+
 impl Subscriber for RabbitMqPubSub {
-    type Stream = impl futures::Stream<Item = AppMessage>;
+    type Stream = impl futures::Stream<Item = AppMessage> + Send + 'static;
     async fn subscribe(&self, user_id: u64) -> anyhow::Result<Self::Stream> {
         let (tx, rx) = mpsc::channel(100);
         self.subscribers.entry(user_id).or_default().push(tx);
 
         Ok(tokio_stream::wrappers::ReceiverStream::new(rx))
     }
+
+    async fn close(&self) {
+        self.closed.notify_one();
+        let task = self.task.lock().expect("poisoned lock").take();
+        if let Some(task) = task {
+            if let Err(e) = task.await {
+                warn!("consumer task failed: {:?}", e);
+            }
+        }
+        // the exclusive queue goes away with the connection
+        if let Err(e) = self.connection.close(0, "shutdown").await {
+            warn!("failed to close connection: {:?}", e);
+        }
+    }
 }
 
 impl Publisher for RabbitMqPubSub {
-    async fn publish<P: Serialize>(&self, topic: &str, payload: P) -> anyhow::Result<()> {
+    async fn publish<P: Serialize + Send>(&self, topic: &str, payload: P) -> anyhow::Result<()> {
         let channel = self.connection.create_channel().await?;
         channel
             .basic_publish(
@@ -188,10 +258,12 @@ impl Publisher for RabbitMqPubSub {
 
 #[cfg(test)]
 mod tests {
-    use crate::{pubsub::AppEvent, ChatType};
+    use crate::{
+        pubsub::{conformance, AppEvent},
+        ChatType,
+    };
 
     use futures::pin_mut;
-    use lapin::ConnectionProperties;
     use tracing::level_filters::LevelFilter;
     use tracing_subscriber::{
         fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _,
@@ -230,7 +302,7 @@ mod tests {
         // this message should be routed to user 1
         pubsub
             .publish(
-                "chat.exchange",
+                CHAT_EXCHANGE,
                 AppMessage {
                     user_id: 1,
                     event: AppEvent::NewChat(crate::Chat {
@@ -239,6 +311,7 @@ mod tests {
                         r#type: ChatType::Group,
                         members: vec![1, 2, 3],
                         created_at: chrono::Utc::now(),
+                        topic: None,
                         name: Some("Test1".to_string()),
                     }),
                 },
@@ -249,7 +322,7 @@ mod tests {
         // this message should be routed to user 2
         pubsub
             .publish(
-                "chat.exchange",
+                CHAT_EXCHANGE,
                 AppMessage {
                     user_id: 2,
                     event: AppEvent::NewChat(crate::Chat {
@@ -258,6 +331,7 @@ mod tests {
                         r#type: ChatType::Group,
                         members: vec![2, 3, 4],
                         created_at: chrono::Utc::now(),
+                        topic: None,
                         name: Some("Test2".to_string()),
                     }),
                 },
@@ -269,4 +343,49 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn rabbitmq_pubsub_should_pass_conformance() -> anyhow::Result<()> {
+        let pubsub = RabbitMqPubSub::connect("amqp://localhost:5672").await?;
+
+        conformance::run(&pubsub, &pubsub, CHAT_EXCHANGE).await
+    }
+
+    #[tokio::test]
+    async fn every_instance_should_receive_events() -> anyhow::Result<()> {
+        let node1 = RabbitMqPubSub::connect("amqp://localhost:5672").await?;
+        let node2 = RabbitMqPubSub::connect("amqp://localhost:5672").await?;
+
+        let subscriber1 = node1.subscribe(6001).await?;
+        let subscriber2 = node2.subscribe(6002).await?;
+        pin_mut!(subscriber1);
+        pin_mut!(subscriber2);
+
+        let event = AppEvent::NewChat(crate::Chat {
+            id: 6,
+            ws_id: 1,
+            r#type: ChatType::Group,
+            members: vec![6001, 6002],
+            created_at: chrono::Utc::now(),
+            topic: None,
+            name: Some("Test6".to_string()),
+        });
+        // published through one node, the users are connected to different nodes
+        node1
+            .publish(
+                CHAT_EXCHANGE,
+                Notification::new([6001, 6002], event.clone()),
+            )
+            .await?;
+
+        let message = subscriber1.next().await.unwrap();
+        assert_eq!(6001, message.user_id);
+        assert_eq!(event, message.event);
+
+        let message = subscriber2.next().await.unwrap();
+        assert_eq!(6002, message.user_id);
+        assert_eq!(event, message.event);
+
+        Ok(())
+    }
 }
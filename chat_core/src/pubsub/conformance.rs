@@ -0,0 +1,181 @@
+//! A conformance suite every pubsub backend should pass, so backends can be swapped by
+//! configuration without changing what notify_server clients observe.
+
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use tokio::time::timeout;
+
+use super::{AppEvent, AppMessage, Notification, Publisher, Subscriber};
+use crate::{Chat, ChatType};
+
+type BoxStream = Pin<Box<dyn Stream<Item = AppMessage> + Send>>;
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Run every conformance case against `subscriber`, publishing to `topic` through `publisher`.
+///
+/// Each case uses its own user ids, so backends sharing a broker don't see each other's leftovers.
+pub(crate) async fn run<S, P>(subscriber: &S, publisher: &P, topic: &str) -> Result<()>
+where
+    S: Subscriber,
+    P: Publisher,
+{
+    routes_to_addressed_users_only(subscriber, publisher, topic).await?;
+    fans_out_to_every_addressed_user(subscriber, publisher, topic).await?;
+    delivers_to_every_stream_of_a_user(subscriber, publisher, topic).await?;
+    preserves_publish_order(subscriber, publisher, topic).await?;
+    accepts_single_app_message(subscriber, publisher, topic).await?;
+    // last, the backend is unusable afterwards
+    close_ends_every_stream(subscriber).await?;
+    Ok(())
+}
+
+async fn routes_to_addressed_users_only<S: Subscriber, P: Publisher>(
+    subscriber: &S,
+    publisher: &P,
+    topic: &str,
+) -> Result<()> {
+    let mut alice = subscribe(subscriber, 1001).await?;
+    let mut bob = subscribe(subscriber, 1002).await?;
+
+    let event = new_chat(1, &[1001, 1003]);
+    publisher
+        .publish(topic, Notification::new([1001], event.clone()))
+        .await?;
+
+    let message = recv(&mut alice).await?;
+    assert_eq!(message.user_id, 1001);
+    assert_eq!(message.event, event);
+    assert_idle(&mut bob).await
+}
+
+async fn fans_out_to_every_addressed_user<S: Subscriber, P: Publisher>(
+    subscriber: &S,
+    publisher: &P,
+    topic: &str,
+) -> Result<()> {
+    let mut alice = subscribe(subscriber, 2001).await?;
+    let mut bob = subscribe(subscriber, 2002).await?;
+
+    let event = new_chat(2, &[2001, 2002]);
+    publisher
+        .publish(topic, Notification::new([2001, 2002], event.clone()))
+        .await?;
+
+    let message = recv(&mut alice).await?;
+    assert_eq!(message.user_id, 2001);
+    assert_eq!(message.event, event);
+    let message = recv(&mut bob).await?;
+    assert_eq!(message.user_id, 2002);
+    assert_eq!(message.event, event);
+    Ok(())
+}
+
+async fn delivers_to_every_stream_of_a_user<S: Subscriber, P: Publisher>(
+    subscriber: &S,
+    publisher: &P,
+    topic: &str,
+) -> Result<()> {
+    let mut phone = subscribe(subscriber, 3001).await?;
+    let mut laptop = subscribe(subscriber, 3001).await?;
+
+    let event = new_chat(3, &[3001, 3002]);
+    publisher
+        .publish(topic, Notification::new([3001], event.clone()))
+        .await?;
+
+    assert_eq!(recv(&mut phone).await?.event, event);
+    assert_eq!(recv(&mut laptop).await?.event, event);
+    Ok(())
+}
+
+async fn preserves_publish_order<S: Subscriber, P: Publisher>(
+    subscriber: &S,
+    publisher: &P,
+    topic: &str,
+) -> Result<()> {
+    let mut alice = subscribe(subscriber, 4001).await?;
+
+    for id in 41..44 {
+        publisher
+            .publish(
+                topic,
+                Notification::new([4001], new_chat(id, &[4001, 4002])),
+            )
+            .await?;
+    }
+
+    for id in 41..44 {
+        match recv(&mut alice).await?.event {
+            AppEvent::NewChat(chat) => assert_eq!(chat.id, id),
+            event => return Err(anyhow!("unexpected event: {:?}", event)),
+        }
+    }
+    Ok(())
+}
+
+async fn accepts_single_app_message<S: Subscriber, P: Publisher>(
+    subscriber: &S,
+    publisher: &P,
+    topic: &str,
+) -> Result<()> {
+    let mut alice = subscribe(subscriber, 5001).await?;
+
+    let message = AppMessage {
+        user_id: 5001,
+        event: new_chat(5, &[5001, 5002]),
+    };
+    publisher.publish(topic, &message).await?;
+
+    assert_eq!(recv(&mut alice).await?, message);
+    Ok(())
+}
+
+async fn close_ends_every_stream<S: Subscriber>(subscriber: &S) -> Result<()> {
+    let mut alice = subscribe(subscriber, 6001).await?;
+    let mut bob = subscribe(subscriber, 6002).await?;
+
+    subscriber.close().await;
+
+    for stream in [&mut alice, &mut bob] {
+        match timeout(RECV_TIMEOUT, stream.next()).await {
+            Ok(None) => {}
+            ret => return Err(anyhow!("stream should end on close: {:?}", ret)),
+        }
+    }
+    Ok(())
+}
+
+async fn subscribe<S: Subscriber>(subscriber: &S, user_id: u64) -> Result<BoxStream> {
+    Ok(Box::pin(subscriber.subscribe(user_id).await?))
+}
+
+async fn recv(stream: &mut BoxStream) -> Result<AppMessage> {
+    timeout(RECV_TIMEOUT, stream.next())
+        .await
+        .map_err(|_| anyhow!("no message received within {:?}", RECV_TIMEOUT))?
+        .ok_or_else(|| anyhow!("stream closed"))
+}
+
+async fn assert_idle(stream: &mut BoxStream) -> Result<()> {
+    match timeout(IDLE_TIMEOUT, stream.next()).await {
+        Err(_) => Ok(()),
+        Ok(message) => Err(anyhow!("unexpected message: {:?}", message)),
+    }
+}
+
+fn new_chat(id: i64, members: &[i64]) -> AppEvent {
+    AppEvent::NewChat(Chat {
+        id,
+        ws_id: 1,
+        name: Some(format!("chat{id}")),
+        r#type: ChatType::Group,
+        members: members.to_vec(),
+        created_at: Default::default(),
+        topic: None,
+    })
+}
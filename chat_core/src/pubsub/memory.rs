@@ -0,0 +1,79 @@
+use dashmap::DashMap;
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use super::{AppMessage, Notification, Publisher, Subscriber};
+
+/// A publisher & subscriber that routes events inside the current process.
+///
+/// It has no broker behind it, so it's only useful when publishers and subscribers share the same
+/// instance, e.g. in tests or single node deployments embedding both chat_server and notify_server.
+#[derive(Clone, Default)]
+pub struct InMemoryPubSub {
+    users: Arc<DashMap<u64, broadcast::Sender<Arc<AppMessage>>>>,
+}
+
+impl InMemoryPubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Publisher for InMemoryPubSub {
+    /// Deliver `payload`, a [`Notification`] or a single [`AppMessage`], to subscribed users.
+    ///
+    /// There is only one in-process route, so `topic` is ignored.
+    async fn publish<P: Serialize + Send>(&self, _topic: &str, payload: P) -> anyhow::Result<()> {
+        let notification = Notification::decode(&serde_json::to_vec(&payload)?)?;
+        for message in notification.messages() {
+            if let Some(tx) = self.users.get(&message.user_id) {
+                info!("sending notification to user: {}", message.user_id);
+                let user_id = message.user_id;
+                if let Err(err) = tx.send(Arc::new(message)) {
+                    warn!(
+                        "failed to send notification to user: {}, err: {:?}",
+                        user_id, err
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Subscriber for InMemoryPubSub {
+    type Stream = impl Stream<Item = AppMessage> + Send + 'static;
+
+    async fn subscribe(&self, user_id: u64) -> anyhow::Result<Self::Stream> {
+        let rx = self
+            .users
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(100).0)
+            .subscribe();
+
+        Ok(tokio_stream::wrappers::BroadcastStream::new(rx)
+            .filter_map(move |result| async move { result.ok().map(|e| (*e).clone()) }))
+    }
+
+    async fn close(&self) {
+        // dropping the senders ends the streams
+        self.users.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::conformance;
+
+    #[tokio::test]
+    async fn in_memory_pubsub_should_pass_conformance() -> anyhow::Result<()> {
+        let pubsub = InMemoryPubSub::new();
+
+        conformance::run(&pubsub, &pubsub, "").await
+    }
+}
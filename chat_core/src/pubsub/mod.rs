@@ -1,25 +1,42 @@
 /// This module is used to publish and subscribe to events in the chat system.
 #[cfg(feature = "amqp")]
 mod amqp;
+#[cfg(test)]
+mod conformance;
+mod memory;
 mod notification;
 pub mod pg;
 
+use std::future::Future;
+
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{Chat, Message, Presence};
+
+#[cfg(feature = "amqp")]
+pub use amqp::RabbitMqPubSub;
+pub use memory::InMemoryPubSub;
+pub use notification::Notification;
 
-use crate::{Chat, Message};
+/// The RabbitMQ fanout exchange events are published to, every instance binds its own queue to it.
+pub const CHAT_EXCHANGE: &str = "chat.fanout";
 
 /// A trait for a subscriber that can subscribe to events.
 pub trait Subscriber {
     type Stream: futures::Stream<Item = AppMessage> + Send + 'static;
-    #[allow(async_fn_in_trait)]
-    async fn subscribe(&self, user_id: u64) -> anyhow::Result<Self::Stream>;
+    fn subscribe(&self, user_id: u64) -> impl Future<Output = anyhow::Result<Self::Stream>> + Send;
+    /// Stop receiving events and end every subscribed stream, e.g. on shutdown.
+    fn close(&self) -> impl Future<Output = ()> + Send;
 }
 
 /// A trait for a publisher that can publish events.
 pub trait Publisher {
-    #[allow(unused)]
-    #[allow(async_fn_in_trait)]
-    async fn publish<P: Serialize>(&self, topic: &str, payload: P) -> anyhow::Result<()>;
+    fn publish<P: Serialize + Send>(
+        &self,
+        topic: &str,
+        payload: P,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
@@ -35,4 +52,62 @@ pub enum AppEvent {
     AddToChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    Typing(Typing),
+    PresenceChanged(Presence),
+    CommandReply(CommandReply),
+}
+
+/// A user started or stopped typing in a chat. It's only relayed, never persisted.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Typing {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub typing: bool,
+}
+
+/// A slash command's reply only its caller sees. It's only relayed, never persisted.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, ToSchema)]
+pub struct CommandReply {
+    pub chat_id: i64,
+    pub command: String,
+    pub text: String,
+}
+
+impl AppEvent {
+    /// Names of every variant, as returned by [`AppEvent::name`].
+    pub const NAMES: [&'static str; 7] = [
+        "NewChat",
+        "AddToChat",
+        "RemoveFromChat",
+        "NewMessage",
+        "Typing",
+        "PresenceChanged",
+        "CommandReply",
+    ];
+
+    /// The variant name, the same as the `event` tag it's serialized with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NewChat(_) => "NewChat",
+            Self::AddToChat(_) => "AddToChat",
+            Self::RemoveFromChat(_) => "RemoveFromChat",
+            Self::NewMessage(_) => "NewMessage",
+            Self::Typing(_) => "Typing",
+            Self::PresenceChanged(_) => "PresenceChanged",
+            Self::CommandReply(_) => "CommandReply",
+        }
+    }
+
+    /// The chat the event happened in, if it's about one.
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            Self::NewChat(chat) | Self::AddToChat(chat) | Self::RemoveFromChat(chat) => {
+                Some(chat.id)
+            }
+            Self::NewMessage(message) => Some(message.chat_id),
+            Self::Typing(typing) => Some(typing.chat_id),
+            Self::CommandReply(reply) => Some(reply.chat_id),
+            Self::PresenceChanged(_) => None,
+        }
+    }
 }
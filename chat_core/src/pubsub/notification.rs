@@ -1,14 +1,16 @@
-use crate::{AppEvent, Chat, Message};
+use crate::{AppEvent, AppMessage, Chat, Message};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use std::sync::Arc;
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub(crate) struct Notification {
+/// An event together with the users it should be delivered to.
+///
+/// This is the payload every [`Publisher`](super::Publisher) backend accepts, subscribers fan it
+/// out into one [`AppMessage`] per affected user.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
     // users are affected, we should send notification
-    pub(crate) user_ids: HashSet<i64>,
-    pub(crate) event: Arc<AppEvent>,
+    pub user_ids: HashSet<i64>,
+    pub event: AppEvent,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,8 +26,22 @@ struct ChatMessageCreated {
     members: Vec<i64>,
 }
 
+/// Payloads accepted on the wire, a single [`AppMessage`] is treated as a notification for one user.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Notification(Notification),
+    Message(AppMessage),
+}
+
 impl Notification {
-    #[allow(unused)]
+    pub fn new(user_ids: impl IntoIterator<Item = i64>, event: AppEvent) -> Self {
+        Self {
+            user_ids: user_ids.into_iter().collect(),
+            event,
+        }
+    }
+
     pub(crate) fn load(channel: &str, payload: &str) -> anyhow::Result<Self> {
         match channel {
             "chat_updated" => {
@@ -38,22 +54,36 @@ impl Notification {
                     "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
                     _ => return Err(anyhow::anyhow!("Invalid operation")),
                 };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+                Ok(Self { user_ids, event })
             }
             "chat_message_created" => {
                 let payload: ChatMessageCreated = serde_json::from_str(payload)?;
                 let user_ids = payload.members.iter().cloned().collect();
                 Ok(Self {
                     user_ids,
-                    event: Arc::new(AppEvent::NewMessage(payload.message)),
+                    event: AppEvent::NewMessage(payload.message),
                 })
             }
+            super::pg::APP_EVENT_CHANNEL => Self::decode(payload.as_bytes()),
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
+
+    /// Decode a published payload, either a [`Notification`] or a single [`AppMessage`].
+    pub(crate) fn decode(data: &[u8]) -> anyhow::Result<Self> {
+        Ok(match serde_json::from_slice(data)? {
+            Payload::Notification(notification) => notification,
+            Payload::Message(message) => Self::new([message.user_id as i64], message.event),
+        })
+    }
+
+    /// Split the notification into one message per affected user.
+    pub fn messages(&self) -> impl Iterator<Item = AppMessage> + '_ {
+        self.user_ids.iter().map(|user_id| AppMessage {
+            user_id: *user_id as u64,
+            event: self.event.clone(),
+        })
+    }
 }
 
 fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<i64> {
@@ -2,21 +2,31 @@ use dashmap::DashMap;
 use futures::{Stream, StreamExt};
 use serde::Serialize;
 use sqlx::postgres::PgListener;
-use sqlx::Executor;
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, Notify};
+use tokio::task::JoinHandle;
 use tracing::{info, warn};
 
 use crate::pubsub::{notification::Notification, AppMessage, Subscriber};
 
 use super::Publisher;
 
+/// The channel carrying [`Notification`]s published through [`PgPublisher`].
+pub const APP_EVENT_CHANNEL: &str = "app_event";
+
+/// Subscribes to postgres notifications, and publishes [`Notification`]s through its own pool.
 #[derive(Clone)]
 pub struct PgSubscriber {
     users: Arc<DashMap<u64, broadcast::Sender<Arc<AppMessage>>>>,
+    /// Every notification received, before it's fanned out to users.
+    all: broadcast::Sender<Arc<Notification>>,
+    publisher: PgPublisher,
+    /// Stops the listener task, which is awaited on close.
+    closed: Arc<Notify>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
-#[allow(dead_code)]
+#[derive(Clone)]
 pub struct PgPublisher {
     pool: sqlx::PgPool,
 }
@@ -28,23 +38,35 @@ impl PgSubscriber {
         let mut listener = PgListener::connect(db_url.as_ref()).await?;
         listener.listen("chat_updated").await?;
         listener.listen("chat_message_created").await?;
+        listener.listen(APP_EVENT_CHANNEL).await?;
 
         let mut stream = listener.into_stream();
         let cloned_users = users.clone();
+        let (all, _) = broadcast::channel(1024);
+        let cloned_all = all.clone();
+        let closed = Arc::new(Notify::new());
+        let cloned_closed = closed.clone();
 
-        tokio::spawn(async move {
-            while let Some(Ok(notification)) = stream.next().await {
+        let task = tokio::spawn(async move {
+            loop {
+                let notification = tokio::select! {
+                    _ = cloned_closed.notified() => break,
+                    notification = stream.next() => match notification {
+                        Some(Ok(notification)) => notification,
+                        _ => break,
+                    },
+                };
                 info!("Received notification: {:?}", notification);
                 match Notification::load(notification.channel(), notification.payload()) {
                     Ok(notification) => {
                         info!("user_ids: {:?}", notification.user_ids);
-                        for user_id in notification.user_ids {
-                            if let Some(tx) = cloned_users.get(&(user_id as u64)) {
-                                info!("sending notification to user: {}", user_id);
-                                if let Err(err) = tx.send(Arc::new(AppMessage {
-                                    user_id: user_id as u64,
-                                    event: (*notification.event).clone(),
-                                })) {
+                        // nobody consuming every notification is fine
+                        let _ = cloned_all.send(Arc::new(notification.clone()));
+                        for message in notification.messages() {
+                            if let Some(tx) = cloned_users.get(&message.user_id) {
+                                info!("sending notification to user: {}", message.user_id);
+                                let user_id = message.user_id;
+                                if let Err(err) = tx.send(Arc::new(message)) {
                                     warn!(
                                         "failed to send notification to user: {}, err: {:?}",
                                         user_id, err
@@ -58,31 +80,68 @@ impl PgSubscriber {
                     }
                 }
             }
+            // dropping the senders ends the streams
+            cloned_users.clear();
+            info!("stopped listening to notifications");
         });
 
-        Ok(Self { users })
+        let publisher = PgPublisher::new(db_url).await?;
+
+        Ok(Self {
+            users,
+            all,
+            publisher,
+            closed,
+            task: Arc::new(Mutex::new(Some(task))),
+        })
+    }
+}
+
+impl PgSubscriber {
+    /// Every notification received from now on, whoever it's addressed to.
+    ///
+    /// Notifications are dropped with a warning if the stream falls too far behind.
+    pub fn notifications(&self) -> impl Stream<Item = Arc<Notification>> + Send + 'static {
+        tokio_stream::wrappers::BroadcastStream::new(self.all.subscribe()).filter_map(
+            |result| async move {
+                result
+                    .map_err(|e| warn!("notifications stream lagged: {:?}", e))
+                    .ok()
+            },
+        )
     }
 }
 
-#[allow(dead_code)]
 impl PgPublisher {
-    async fn new(db_url: impl AsRef<str>) -> anyhow::Result<Self> {
+    pub async fn new(db_url: impl AsRef<str>) -> anyhow::Result<Self> {
         let pool = sqlx::pool::Pool::connect(db_url.as_ref()).await?;
 
         Ok(PgPublisher { pool })
     }
+
+    /// Publish through an existing pool, e.g. the one the events are written with.
+    pub fn with_pool(pool: sqlx::PgPool) -> Self {
+        PgPublisher { pool }
+    }
 }
 
 impl Publisher for PgPublisher {
-    async fn publish<P: Serialize>(&self, topic: &str, payload: P) -> anyhow::Result<()> {
-        let notify = format!("NOTIFY {}, '{}'", topic, serde_json::to_string(&payload)?);
-        println!("{}", notify);
-
-        self.pool.execute(notify.as_ref()).await?;
+    async fn publish<P: Serialize + Send>(&self, topic: &str, payload: P) -> anyhow::Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(topic)
+            .bind(serde_json::to_string(&payload)?)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 }
 
+impl Publisher for PgSubscriber {
+    async fn publish<P: Serialize + Send>(&self, topic: &str, payload: P) -> anyhow::Result<()> {
+        self.publisher.publish(topic, payload).await
+    }
+}
+
 impl Subscriber for PgSubscriber {
     type Stream = impl Stream<Item = AppMessage> + Send + 'static;
 
@@ -98,6 +157,16 @@ impl Subscriber for PgSubscriber {
         Ok(tokio_stream::wrappers::BroadcastStream::new(rx)
             .filter_map(move |result| async move { result.ok().map(|e| (*e).clone()) }))
     }
+
+    async fn close(&self) {
+        self.closed.notify_one();
+        let task = self.task.lock().expect("poisoned lock").take();
+        if let Some(task) = task {
+            if let Err(e) = task.await {
+                warn!("notification listener failed: {:?}", e);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -106,10 +175,12 @@ mod tests {
     use futures::pin_mut;
     use sqlx::Executor as _;
     use sqlx_db_tester::TestPg;
+    use std::time::Duration;
     use tracing::level_filters::LevelFilter;
     use tracing::Level;
 
     use super::*;
+    use crate::pubsub::conformance;
     use crate::pubsub::notification::ChatUpdated;
     use tracing_subscriber::{
         fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _,
@@ -171,6 +242,7 @@ mod tests {
                         r#type: ChatType::Single,
                         members: vec![1, 2],
                         created_at: Default::default(),
+                        topic: None,
                     }),
                 },
             )
@@ -189,6 +261,7 @@ mod tests {
                         r#type: ChatType::Group,
                         members: vec![1, 3, 4],
                         created_at: Default::default(),
+                        topic: None,
                     }),
                 },
             )
@@ -199,6 +272,45 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn pg_pubsub_should_pass_conformance() -> anyhow::Result<()> {
+        let tdb = get_test_pool(None).await;
+
+        let subscriber = PgSubscriber::new(tdb.url()).await?;
+        let publisher = PgPublisher::new(tdb.url()).await?;
+
+        conformance::run(&subscriber, &publisher, APP_EVENT_CHANNEL).await
+    }
+
+    #[tokio::test]
+    async fn notifications_should_include_unsubscribed_users() -> anyhow::Result<()> {
+        let tdb = get_test_pool(None).await;
+        let subscriber = PgSubscriber::new(tdb.url()).await?;
+        let publisher = PgPublisher::new(tdb.url()).await?;
+        let notifications = subscriber.notifications();
+        pin_mut!(notifications);
+
+        let event = AppEvent::NewChat(Chat {
+            id: 1,
+            ws_id: 1,
+            name: None,
+            r#type: ChatType::Single,
+            members: vec![7, 8],
+            created_at: Default::default(),
+            topic: None,
+        });
+        let notification = Notification::new([7, 8], event);
+        publisher
+            .publish(APP_EVENT_CHANNEL, notification.clone())
+            .await?;
+
+        let received = tokio::time::timeout(Duration::from_secs(5), notifications.next())
+            .await?
+            .expect("stream should not end");
+        assert_eq!(notification, *received);
+        Ok(())
+    }
+
     pub async fn get_test_pool(url: Option<&str>) -> TestPg {
         let url = url
             .map(|x| x.to_string())
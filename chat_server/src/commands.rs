@@ -0,0 +1,450 @@
+//! Slash commands, run instead of posting messages starting with `/`.
+//!
+//! Besides the builtins, a workspace can register commands handled by posting to a url. Their
+//! response is either a reply only the caller sees, or a message posted as the command's bot.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chat_core::{AppEvent, Chat, ChatType, CommandReply, Message, Scope, User};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::handlers::{post_message, require_scope};
+use crate::models::{
+    is_valid_name, ChatRepo, CreateMessage, SlashCommand, SlashCommandRepo, UserRepo,
+};
+use crate::webhook::{sign, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::{AppError, AppState};
+
+/// How long an external command has to respond.
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A `/{name} {args}` message.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Invocation<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) args: &'a str,
+}
+
+pub(crate) enum CommandOutcome {
+    /// A message was posted to the chat.
+    Posted(Message),
+    /// A reply only the caller sees.
+    Reply(CommandReply),
+    /// Nothing to show.
+    Done,
+}
+
+/// What an external command is posted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExternalCommandRequest {
+    pub command: String,
+    pub text: String,
+    pub user_id: i64,
+    pub user_name: String,
+    pub chat_id: i64,
+    pub ws_id: i64,
+}
+
+/// What an external command responds with, Slack-compatible.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExternalCommandResponse {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub response_type: ResponseType,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseType {
+    #[default]
+    Ephemeral,
+    InChannel,
+}
+
+/// The command `content` invokes, if it's one.
+///
+/// Text like `/usr/bin` isn't taken as a command, nor is anything starting with `//`.
+pub(crate) fn parse(content: &str) -> Option<Invocation<'_>> {
+    let rest = content.strip_prefix('/')?;
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    is_valid_name(name).then(|| Invocation {
+        name,
+        args: args.trim(),
+    })
+}
+
+/// Run the command `user` invoked in `chat`.
+pub(crate) async fn run(
+    state: &AppState,
+    user: &User,
+    chat: &Chat,
+    invocation: Invocation<'_>,
+) -> Result<CommandOutcome, AppError> {
+    let Invocation { name, args } = invocation;
+    let reply = |text: String| {
+        Ok(CommandOutcome::Reply(CommandReply {
+            chat_id: chat.id,
+            command: name.to_string(),
+            text,
+        }))
+    };
+
+    // external commands are told about the chat and may post to it, so the same goes for them
+    if chat.ws_id != user.ws_id || !chat.members.contains(&user.id) {
+        return reply("you aren't a member of this chat".to_string());
+    }
+
+    match name {
+        "me" => {
+            if args.is_empty() {
+                return reply("usage: /me <action>".to_string());
+            }
+            let input = CreateMessage {
+                content: format!("_{} {}_", user.fullname, args),
+                files: vec![],
+            };
+            let msg = post_message(state, chat, user.id, &user.fullname, input).await?;
+            Ok(CommandOutcome::Posted(msg))
+        }
+        "topic" => {
+            require_scope(user, Scope::ManageChats)?;
+            let topic = (!args.is_empty()).then_some(args);
+            ChatRepo::set_topic(chat.id as _, topic, &state.pool).await?;
+            let content = match topic {
+                Some(topic) => format!("set the topic: {topic}"),
+                None => "cleared the topic".to_string(),
+            };
+            let input = CreateMessage {
+                content,
+                files: vec![],
+            };
+            let msg = post_message(state, chat, user.id, &user.fullname, input).await?;
+            Ok(CommandOutcome::Posted(msg))
+        }
+        "invite" => {
+            require_scope(user, Scope::ManageChats)?;
+            if chat.r#type == ChatType::Single {
+                return reply("nobody can be invited to a direct message".to_string());
+            }
+            if args.is_empty() {
+                return reply("usage: /invite @user [@user...]".to_string());
+            }
+            let mut invited = vec![];
+            for handle in args.split_whitespace() {
+                match UserRepo::find_by_handle(handle, chat.ws_id as _, &state.pool).await? {
+                    Some(invitee) if chat.members.contains(&invitee.id) => {}
+                    Some(invitee) => invited.push(invitee),
+                    None => return reply(format!("no user {handle} in this workspace")),
+                }
+            }
+            if invited.is_empty() {
+                return reply("everyone is already a member".to_string());
+            }
+
+            let ids: Vec<i64> = invited.iter().map(|u| u.id).collect();
+            let updated = ChatRepo::add_members(chat.id as _, &ids, &state.pool).await?;
+            publish_members_changed(state, chat, updated).await;
+            let names: Vec<&str> = invited.iter().map(|u| u.fullname.as_str()).collect();
+            reply(format!("invited {}", names.join(", ")))
+        }
+        "leave" => {
+            if chat.r#type == ChatType::Single {
+                return reply("you can't leave a direct message".to_string());
+            }
+            let updated = ChatRepo::remove_member(chat.id as _, user.id as _, &state.pool).await?;
+            publish_members_changed(state, chat, updated).await;
+            reply("you left the chat".to_string())
+        }
+        "mute" => {
+            let muted = ChatRepo::toggle_mute(chat.id as _, user.id as _, &state.pool).await?;
+            if muted {
+                reply("muted, /mute again to unmute".to_string())
+            } else {
+                reply("unmuted".to_string())
+            }
+        }
+        _ => match SlashCommandRepo::find_by_name(name, chat.ws_id as _, &state.pool).await? {
+            Some(command) => run_external(state, user, chat, command, args).await,
+            None => reply(format!("unknown command /{name}")),
+        },
+    }
+}
+
+/// Tell the old and new members, as the chat trigger does.
+async fn publish_members_changed(state: &AppState, old: &Chat, new: Chat) {
+    let user_ids: HashSet<i64> = old.members.iter().chain(&new.members).copied().collect();
+    state
+        .publisher
+        .publish(user_ids, AppEvent::AddToChat(new))
+        .await;
+}
+
+async fn run_external(
+    state: &AppState,
+    user: &User,
+    chat: &Chat,
+    command: SlashCommand,
+    args: &str,
+) -> Result<CommandOutcome, AppError> {
+    let reply = |text: String| {
+        Ok(CommandOutcome::Reply(CommandReply {
+            chat_id: chat.id,
+            command: command.name.clone(),
+            text,
+        }))
+    };
+
+    let ret = call_external(state, user, chat, &command, args).await;
+    let res = match ret {
+        Ok(res) => res,
+        Err(e) => return reply(format!("/{} failed: {}", command.name, e)),
+    };
+    match res.response_type {
+        _ if res.text.is_empty() => Ok(CommandOutcome::Done),
+        ResponseType::Ephemeral => reply(res.text),
+        ResponseType::InChannel => {
+            let input = CreateMessage {
+                content: res.text,
+                files: vec![],
+            };
+            let msg = post_message(state, chat, command.bot_id, &command.name, input).await?;
+            Ok(CommandOutcome::Posted(msg))
+        }
+    }
+}
+
+/// Post the invocation to the command's url, signed like webhook deliveries.
+async fn call_external(
+    state: &AppState,
+    user: &User,
+    chat: &Chat,
+    command: &SlashCommand,
+    args: &str,
+) -> anyhow::Result<ExternalCommandResponse> {
+    let body = serde_json::to_vec(&ExternalCommandRequest {
+        command: format!("/{}", command.name),
+        text: args.to_string(),
+        user_id: user.id,
+        user_name: user.fullname.clone(),
+        chat_id: chat.id,
+        ws_id: chat.ws_id,
+    })?;
+    state
+        .config
+        .outbound
+        .check_url(&command.url)
+        .await
+        .map_err(anyhow::Error::msg)?;
+    let timestamp = Utc::now().timestamp();
+    let secret = command.secret.as_deref().unwrap_or_default();
+    let signature = sign(secret, timestamp, &body);
+
+    let res = state
+        .http
+        .post(&command.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, format!("sha256={signature}"))
+        .timeout(EXTERNAL_TIMEOUT)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let body = res.bytes().await?;
+    if body.is_empty() {
+        return Ok(ExternalCommandResponse::default());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::send_message_handler;
+    use crate::models::CreateSlashCommand;
+    use crate::EventPublisher;
+    use anyhow::Result;
+    use axum::{
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode},
+        response::IntoResponse,
+        routing::post,
+        Extension, Json, Router,
+    };
+    use chat_core::{InMemoryPubSub, Subscriber};
+    use futures::StreamExt;
+    use http_body_util::BodyExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    async fn send(state: &AppState, user: &User, chat_id: u64, content: &str) -> Result<Response> {
+        let input = CreateMessage {
+            content: content.to_string(),
+            files: vec![],
+        };
+        let ret = send_message_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(chat_id),
+            Json(input),
+        )
+        .await
+        .into_response();
+        Ok(ret)
+    }
+
+    type Response = axum::response::Response;
+
+    async fn json<T: serde::de::DeserializeOwned>(res: Response) -> Result<T> {
+        let body = res.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[test]
+    fn parse_should_only_take_command_names() {
+        assert_eq!(
+            parse("/invite  @bob @alice "),
+            Some(Invocation {
+                name: "invite",
+                args: "@bob @alice"
+            })
+        );
+        assert_eq!(
+            parse("/leave"),
+            Some(Invocation {
+                name: "leave",
+                args: ""
+            })
+        );
+        assert_eq!(parse("//me escaped"), None);
+        assert_eq!(parse("/usr/bin is a path"), None);
+        assert_eq!(parse("hello /me"), None);
+        assert_eq!(parse("/"), None);
+    }
+
+    #[tokio::test]
+    async fn builtin_commands_should_work() -> Result<()> {
+        let pubsub = InMemoryPubSub::new();
+        let (_tdb, state) =
+            AppState::new_for_test_with_publisher(EventPublisher::InMemory(pubsub.clone())).await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let mut events = Box::pin(pubsub.subscribe(user.id as _).await?);
+
+        // chat 4 is the group of users 1, 3 and 4
+        let res = send(&state, &user, 4, "/me waves").await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let msg: Message = json(res).await?;
+        assert_eq!(msg.content, "_Cae Chen waves_");
+
+        send(&state, &user, 4, "/topic release planning").await?;
+        let chat = ChatRepo::get_by_id(4, &state.pool).await?.unwrap();
+        assert_eq!(chat.topic.as_deref(), Some("release planning"));
+
+        let res = send(&state, &user, 4, "/invite @alice @nobody").await?;
+        let reply: CommandReply = json(res).await?;
+        assert_eq!(reply.text, "no user @nobody in this workspace");
+        let res = send(&state, &user, 4, "/invite @alice").await?;
+        let reply: CommandReply = json(res).await?;
+        assert_eq!(reply.text, "invited Alice Chen");
+        let chat = ChatRepo::get_by_id(4, &state.pool).await?.unwrap();
+        assert_eq!(chat.members, [1, 3, 4, 2]);
+
+        let res = send(&state, &user, 4, "/mute").await?;
+        assert_eq!(json::<CommandReply>(res).await?.command, "mute");
+        assert_eq!(ChatRepo::fetch_muted(1, &state.pool).await?, [4]);
+
+        send(&state, &user, 4, "/leave").await?;
+        let chat = ChatRepo::get_by_id(4, &state.pool).await?.unwrap();
+        assert!(!chat.members.contains(&1));
+
+        // the caller saw the replies on their stream, ephemeral ones only reach them
+        let mut replies = 0;
+        while let Ok(Some(message)) = timeout(Duration::from_millis(100), events.next()).await {
+            if let AppEvent::CommandReply(reply) = message.event {
+                assert_eq!(reply.chat_id, 4);
+                replies += 1;
+            }
+        }
+        assert_eq!(replies, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_and_escaped_commands_should_not_be_posted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let res = send(&state, &user, 1, "/nope").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let reply: CommandReply = json(res).await?;
+        assert_eq!(reply.text, "unknown command /nope");
+
+        let res = send(&state, &user, 1, "//nope").await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let msg: Message = json(res).await?;
+        assert_eq!(msg.content, "/nope");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_commands_should_be_called() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let app = Router::new().route(
+            "/deploy",
+            post(
+                |headers: HeaderMap, Json(req): Json<ExternalCommandRequest>| async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    assert!(headers.contains_key(SIGNATURE_HEADER));
+                    let response_type = if req.text == "loudly" {
+                        ResponseType::InChannel
+                    } else {
+                        ResponseType::Ephemeral
+                    };
+                    Json(ExternalCommandResponse {
+                        text: format!("{} deploying", req.user_name),
+                        response_type,
+                    })
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let input = CreateSlashCommand {
+            name: "deploy".to_string(),
+            url: format!("http://{addr}/deploy"),
+        };
+        let command =
+            SlashCommandRepo::create(input, 1, 1, &state.config.outbound, &state.pool).await?;
+
+        let res = send(&state, &user, 1, "/deploy").await?;
+        let reply: CommandReply = json(res).await?;
+        assert_eq!(reply.text, "Cae Chen deploying");
+
+        let res = send(&state, &user, 1, "/deploy loudly").await?;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let msg: Message = json(res).await?;
+        assert_eq!(msg.sender_id, command.bot_id);
+        assert_eq!(msg.content, "Cae Chen deploying");
+
+        // daisy isn't in the private channel, the command isn't called for her
+        let daisy = state.test_user("daisy@cae.org").await?;
+        let res = send(&state, &daisy, 2, "/deploy loudly").await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let reply: CommandReply = json(res).await?;
+        assert_eq!(reply.text, "you aren't a member of this chat");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -2,6 +2,9 @@ use anyhow::Result;
 use chat_core::load_config;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::rate_limit::RateLimiter;
 use tracing::Level;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,6 +17,22 @@ pub struct AppConfig {
         default = "debug_level"
     )]
     pub log_level: Level,
+    #[serde(default)]
+    pub publisher: PublisherConfig,
+    #[serde(default)]
+    pub incoming_webhooks: IncomingWebhookConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub file_urls: FileUrlConfig,
+    #[serde(default)]
+    pub thumbnails: ThumbnailConfig,
+    #[serde(default)]
+    pub file_gc: FileGcConfig,
+    #[serde(default)]
+    pub uploads: UploadConfig,
+    #[serde(default)]
+    pub outbound: OutboundConfig,
 }
 
 fn debug_level() -> Level {
@@ -40,7 +59,16 @@ where
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// Local files, and uploads in progress whatever the storage. Uploads in progress are only
+    /// on the replica which created them, so their requests must be routed to it.
     pub base_dir: PathBuf,
+    /// Max size of an uploaded file, in bytes.
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+}
+
+fn default_max_upload_size() -> u64 {
+    100 * 1024 * 1024
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,6 +77,164 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+/// How domain events reach notify_server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum PublisherConfig {
+    /// Leave it to the database triggers, nothing is published by chat_server.
+    #[default]
+    Trigger,
+    /// Publish to the postgres `app_event` channel on `server.db_url`.
+    Postgres,
+    /// Publish to a RabbitMQ broker, requires the `amqp` feature.
+    Amqp { url: String },
+}
+
+/// Where uploaded files are kept.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// On the local disk, under `server.base_dir`.
+    #[default]
+    Local,
+    /// In a bucket of an S3-compatible service, shared by every chat_server replica. Resumable
+    /// uploads are still received on local disk, see `server.base_dir`.
+    S3(S3Config),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct S3Config {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://localhost:9000` for MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Signed urls, to download files without a token.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileUrlConfig {
+    /// How long a signed url is valid.
+    pub ttl_secs: u64,
+    /// Urls are signed with the first key, any of them is accepted. To rotate, put a new key
+    /// first and drop the old one once its urls expired. No keys, no signed urls.
+    pub keys: Vec<SigningKey>,
+}
+
+impl Default for FileUrlConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 3600,
+            keys: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+/// Thumbnails generated for uploaded images.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThumbnailConfig {
+    /// Side of the square each thumbnail fits in, in pixels. Images smaller than a size don't
+    /// get a thumbnail of it.
+    pub sizes: Vec<u32>,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        Self {
+            sizes: vec![160, 640],
+        }
+    }
+}
+
+/// Collection of files no message has.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileGcConfig {
+    pub interval_secs: u64,
+    /// How long after its last upload a file is kept, for it to be attached to a message.
+    pub grace_secs: u64,
+    /// Only log what would be collected.
+    pub dry_run: bool,
+}
+
+impl Default for FileGcConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 3600,
+            grace_secs: 24 * 3600,
+            dry_run: false,
+        }
+    }
+}
+
+/// Resumable uploads.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// How long an upload is kept without receiving a chunk.
+    pub ttl_secs: u64,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 24 * 3600,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub(crate) fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+/// Requests to the urls of outgoing webhooks and slash commands.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+    /// Let urls reach loopback, private and link-local addresses, only for trusted setups.
+    pub allow_private_networks: bool,
+}
+
+/// Rate limit of messages posted through each incoming webhook.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IncomingWebhookConfig {
+    /// Max messages posted within a window.
+    pub rate_limit: u32,
+    pub window_secs: u64,
+}
+
+impl Default for IncomingWebhookConfig {
+    fn default() -> Self {
+        Self {
+            rate_limit: 30,
+            window_secs: 60,
+        }
+    }
+}
+
+impl IncomingWebhookConfig {
+    pub(crate) fn limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.rate_limit, Duration::from_secs(self.window_secs))
+    }
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
         load_config("CHAT_CONFIG", "app.yaml")
@@ -64,6 +250,49 @@ mod test {
         let cfg = AppConfig::load().unwrap();
 
         assert_eq!(5555, cfg.server.port);
+        assert_eq!(100 * 1024 * 1024, cfg.server.max_upload_size);
         assert_eq!(Level::INFO, cfg.log_level);
+        assert_eq!(PublisherConfig::Trigger, cfg.publisher);
+        assert_eq!(IncomingWebhookConfig::default(), cfg.incoming_webhooks);
+        assert_eq!(StorageConfig::Local, cfg.storage);
+        assert_eq!(3600, cfg.file_urls.ttl_secs);
+        assert_eq!("2026-10", cfg.file_urls.keys[0].id);
+        assert_eq!(ThumbnailConfig::default(), cfg.thumbnails);
+        assert_eq!(FileGcConfig::default(), cfg.file_gc);
+        assert_eq!(UploadConfig::default(), cfg.uploads);
+        assert_eq!(OutboundConfig::default(), cfg.outbound);
+    }
+
+    #[test]
+    fn publisher_config_should_deserialize() {
+        let cfg: PublisherConfig =
+            serde_yaml::from_str("backend: amqp\nurl: amqp://localhost:5672").unwrap();
+        assert_eq!(
+            PublisherConfig::Amqp {
+                url: "amqp://localhost:5672".to_string()
+            },
+            cfg
+        );
+
+        // events published in memory would reach no notify_server
+        assert!(serde_yaml::from_str::<PublisherConfig>("backend: in_memory").is_err());
+    }
+
+    #[test]
+    fn storage_config_should_deserialize() {
+        let cfg: StorageConfig = serde_yaml::from_str(
+            "backend: s3\nendpoint: http://localhost:9000\nbucket: chat\naccess_key: minio\nsecret_key: minio123",
+        )
+        .unwrap();
+        assert_eq!(
+            StorageConfig::S3(S3Config {
+                endpoint: "http://localhost:9000".to_string(),
+                bucket: "chat".to_string(),
+                region: "us-east-1".to_string(),
+                access_key: "minio".to_string(),
+                secret_key: "minio123".to_string(),
+            }),
+            cfg
+        );
     }
 }
@@ -48,6 +48,45 @@ pub enum AppError {
     #[error("{0}")]
     ChatFileError(String),
 
+    #[error("create webhook error: {0}")]
+    CreateWebhookError(String),
+
+    #[error("create bot error: {0}")]
+    CreateBotError(String),
+
+    #[error("create api token error: {0}")]
+    CreateApiTokenError(String),
+
+    #[error("create command error: {0}")]
+    CreateCommandError(String),
+
+    #[error("upload error: {0}")]
+    UploadError(String),
+
+    #[error("upload conflict: {0}")]
+    UploadConflict(String),
+
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("unsupported file type: {0}")]
+    UnsupportedFileType(String),
+
+    #[error("update workspace error: {0}")]
+    UpdateWorkspaceError(String),
+
+    #[error("storage error: {0}")]
+    StorageError(String),
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
+
     #[error("no man's land")]
     StatusNotFound,
 }
@@ -64,6 +103,19 @@ impl IntoResponse for AppError {
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
+            Self::CreateWebhookError(_) => StatusCode::BAD_REQUEST,
+            Self::CreateBotError(_) => StatusCode::BAD_REQUEST,
+            Self::CreateApiTokenError(_) => StatusCode::BAD_REQUEST,
+            Self::CreateCommandError(_) => StatusCode::BAD_REQUEST,
+            Self::UploadError(_) => StatusCode::BAD_REQUEST,
+            Self::UploadConflict(_) => StatusCode::CONFLICT,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::QuotaExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedFileType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::UpdateWorkspaceError(_) => StatusCode::BAD_REQUEST,
+            Self::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             Self::StatusNotFound => StatusCode::NOT_FOUND,
         };
 
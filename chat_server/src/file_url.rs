@@ -0,0 +1,167 @@
+//! Signed file urls, for `<img src>` tags and media players which can't send a token.
+//!
+//! A signed url is bound to the user it was issued to and expires. It carries the id of the key
+//! it was signed with, so keys can be rotated without breaking the urls still in use.
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::models::ChatFile;
+use crate::{AppError, FileUrlConfig};
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SignFile {
+    /// Url of the file, as in a message.
+    pub url: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct SignedFileUrl {
+    /// Relative to `/api`, like the file's url.
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// What a signed url carries in its query.
+#[derive(Debug, Clone, IntoParams, Serialize, Deserialize)]
+#[into_params(parameter_in = Query)]
+pub struct FileSignature {
+    /// Unix timestamp the url expires at.
+    pub expires: i64,
+    /// Id of the user the url was issued to.
+    pub user: i64,
+    /// Id of the key it was signed with.
+    pub kid: String,
+    pub sig: String,
+}
+
+impl FileUrlConfig {
+    /// Sign `file`'s url for `user_id`, valid from `now` for the configured ttl.
+    pub(crate) fn sign(
+        &self,
+        file: &ChatFile,
+        user_id: i64,
+        now: DateTime<Utc>,
+    ) -> Result<SignedFileUrl, AppError> {
+        let key = self
+            .keys
+            .first()
+            .ok_or_else(|| AppError::Forbidden("signed file urls aren't enabled".to_string()))?;
+        let expires = now.timestamp() + self.ttl_secs as i64;
+        let sig = hex::encode(
+            mac(&key.secret, file, user_id, expires)
+                .finalize()
+                .into_bytes(),
+        );
+        let url = format!(
+            "/signed{}?expires={expires}&user={user_id}&kid={}&sig={sig}",
+            file.url(),
+            key.id
+        );
+        Ok(SignedFileUrl {
+            url,
+            expires_at: DateTime::from_timestamp(expires, 0).unwrap_or(now),
+        })
+    }
+
+    /// Check `signature` was issued for `file` by one of the keys and is still valid at `now`.
+    pub(crate) fn verify(
+        &self,
+        file: &ChatFile,
+        signature: &FileSignature,
+        now: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let invalid = || AppError::Forbidden("invalid or expired file signature".to_string());
+        if signature.expires <= now.timestamp() {
+            return Err(invalid());
+        }
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == signature.kid)
+            .ok_or_else(invalid)?;
+        let sig = hex::decode(&signature.sig).map_err(|_| invalid())?;
+        mac(&key.secret, file, signature.user, signature.expires)
+            .verify_slice(&sig)
+            .map_err(|_| invalid())
+    }
+}
+
+fn mac(secret: &str, file: &ChatFile, user_id: i64, expires: i64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(format!("{}\n{user_id}\n{expires}", file.url()).as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SigningKey;
+    use axum::extract::Query;
+    use chrono::Duration;
+
+    fn key(id: &str) -> SigningKey {
+        SigningKey {
+            id: id.to_string(),
+            secret: format!("{id}-secret"),
+        }
+    }
+
+    fn signature(url: &str) -> FileSignature {
+        let uri = url.parse().expect("url should be valid");
+        let Query(sig) = Query::try_from_uri(&uri).expect("query should be a signature");
+        sig
+    }
+
+    #[test]
+    fn signed_url_should_be_verified() {
+        let config = FileUrlConfig {
+            ttl_secs: 60,
+            keys: vec![key("old")],
+        };
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        let now = Utc::now();
+        let signed = config.sign(&file, 1, now).unwrap();
+        assert!(signed.url.starts_with(&format!("/signed{}?", file.url())));
+        let sig = signature(&signed.url);
+        assert!(config.verify(&file, &sig, now).is_ok());
+
+        // once expired
+        let later = now + Duration::seconds(60);
+        assert!(config.verify(&file, &sig, later).is_err());
+        // for another user or file
+        let other = FileSignature {
+            user: 2,
+            ..sig.clone()
+        };
+        assert!(config.verify(&file, &other, now).is_err());
+        let dog = ChatFile::new(1, "dog.png", b"woof");
+        assert!(config.verify(&dog, &sig, now).is_err());
+
+        // the old key is still accepted after a new one is put first, until it's dropped
+        let rotated = FileUrlConfig {
+            ttl_secs: 60,
+            keys: vec![key("new"), key("old")],
+        };
+        assert!(rotated.verify(&file, &sig, now).is_ok());
+        assert_eq!(
+            signature(&rotated.sign(&file, 1, now).unwrap().url).kid,
+            "new"
+        );
+        let dropped = FileUrlConfig {
+            ttl_secs: 60,
+            keys: vec![key("new")],
+        };
+        assert!(dropped.verify(&file, &sig, now).is_err());
+    }
+
+    #[test]
+    fn sign_without_keys_should_fail() {
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        assert!(FileUrlConfig::default().sign(&file, 1, Utc::now()).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+//! Collection of uploaded files no message has.
+//!
+//! A file is collected once no message references it and its content wasn't uploaded for the
+//! grace period, which leaves time to attach an upload to a message. Collecting a file and
+//! uploading or attaching the same content take the same lock, so an upload either finds the
+//! file gone and stores it again, or records itself before the collector checks the file once
+//! more.
+//!
+//! Resumable uploads which expired are purged too, along with what was received of them. And
+//! files uploaded before sizes were kept are measured, for them to count towards the quota.
+//!
+//! The storage is walked for what no file has: content uploaded before files were recorded
+//! which no message had, or whatever a failed upload or collection left. So is the base dir,
+//! for temp files and the content of resumable uploads which are gone. Each replica has its own
+//! base dir, the rest is shared, so only the replica holding the leader lock collects it.
+
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgConnection};
+use tokio::fs;
+use tokio::time::sleep;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::handlers::{remove_upload, uploads_dir};
+use crate::models::{ChatFile, FileMeta, FileRepo, UploadSessionRepo};
+use crate::{AppError, AppState, Storage};
+
+/// Files looked at in a run.
+const BATCH: i64 = 1000;
+/// Advisory lock held by the instance collecting the shared files.
+const LEADER_LOCK: i64 = 0x6669_6c65_5f67_6300;
+
+pub struct FileCollector {
+    state: AppState,
+}
+
+/// What a run collected, or would have.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GcReport {
+    pub dry_run: bool,
+    /// Urls of the files.
+    pub files: Vec<String>,
+    /// Their size, thumbnails aside.
+    pub bytes: u64,
+    /// Ids of the expired uploads.
+    #[serde(default)]
+    pub uploads: Vec<Uuid>,
+    /// Urls of the files measured, which had no size.
+    #[serde(default)]
+    pub measured: Vec<String>,
+    /// Keys in the storage no file has.
+    #[serde(default)]
+    pub keys: Vec<String>,
+    /// Paths in the base dir of this replica, relative to it, uploads left behind.
+    #[serde(default)]
+    pub leftovers: Vec<String>,
+    /// Size of the keys and leftovers.
+    #[serde(default)]
+    pub stray_bytes: u64,
+}
+
+impl FileCollector {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    /// Collect files every `interval_secs` until the process exits, the shared ones while
+    /// holding the leader lock.
+    pub async fn run(self) {
+        let config = &self.state.config.file_gc;
+        // the connection holding the leader lock, which goes away with it
+        let mut leader = None;
+        loop {
+            sleep(Duration::from_secs(config.interval_secs)).await;
+            let ret = match self.lead(&mut leader).await {
+                Ok(true) => self.collect(config.dry_run).await,
+                Ok(false) => self.collect_leftovers(config.dry_run).await,
+                Err(e) => Err(e),
+            };
+            match ret {
+                Ok(report) if report.dry_run => info!(
+                    "{} unreferenced files, {} bytes, {} expired uploads, and {} stray keys and leftovers, {} bytes, would be collected, {} files measured: {:?} {:?} {:?}",
+                    report.files.len(),
+                    report.bytes,
+                    report.uploads.len(),
+                    report.keys.len() + report.leftovers.len(),
+                    report.stray_bytes,
+                    report.measured.len(),
+                    report.files,
+                    report.keys,
+                    report.leftovers
+                ),
+                Ok(report) => info!(
+                    "collected {} unreferenced files, {} bytes, {} expired uploads, and {} stray keys and leftovers, {} bytes, measured {} files",
+                    report.files.len(),
+                    report.bytes,
+                    report.uploads.len(),
+                    report.keys.len() + report.leftovers.len(),
+                    report.stray_bytes,
+                    report.measured.len()
+                ),
+                Err(e) => warn!("failed to collect files: {:?}", e),
+            }
+        }
+    }
+
+    /// Whether this instance holds the leader lock, taking it if it's free.
+    async fn lead(&self, leader: &mut Option<PgConnection>) -> Result<bool, AppError> {
+        if let Some(conn) = leader {
+            if conn.ping().await.is_ok() {
+                return Ok(true);
+            }
+            *leader = None;
+        }
+        // a dedicated connection, so the lock goes away with it
+        let mut conn = PgConnection::connect(&self.state.config.server.db_url).await?;
+        let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+            .bind(LEADER_LOCK)
+            .fetch_one(&mut conn)
+            .await?;
+        if locked {
+            info!("collecting files");
+            *leader = Some(conn);
+        }
+        Ok(locked)
+    }
+
+    /// Delete the files past their grace period no message has, and what no file has, or only
+    /// report them.
+    pub async fn collect(&self, dry_run: bool) -> Result<GcReport, AppError> {
+        let grace_secs = self.state.config.file_gc.grace_secs;
+        let orphans = FileRepo::find_orphans(grace_secs, BATCH, &self.state.pool).await?;
+        let mut report = self.collect_leftovers(dry_run).await?;
+        for meta in orphans {
+            if !dry_run && !self.delete(&meta, grace_secs).await? {
+                continue;
+            }
+            report.files.push(meta.chat_file().url());
+            report.bytes += meta.size.unwrap_or_default() as u64;
+        }
+
+        report.uploads = UploadSessionRepo::purge_expired(BATCH, dry_run, &self.state.pool).await?;
+        if !dry_run {
+            for id in &report.uploads {
+                remove_upload(&self.state, *id).await?;
+            }
+        }
+
+        for meta in FileRepo::find_unmeasured(BATCH, &self.state.pool).await? {
+            let file = meta.chat_file();
+            if !dry_run {
+                // content gone missing takes no space
+                let size = self.state.storage.size(&file.key()).await?.unwrap_or(0);
+                FileRepo::set_size(meta.id, size, &self.state.pool).await?;
+            }
+            report.measured.push(file.url());
+        }
+
+        self.collect_keys(&mut report).await?;
+        Ok(report)
+    }
+
+    /// Delete the keys in the storage no file has, unless they were stored within the grace
+    /// period: an upload stores its content before recording it.
+    async fn collect_keys(&self, report: &mut GcReport) -> Result<(), AppError> {
+        let cutoff = Utc::now() - Duration::from_secs(self.state.config.file_gc.grace_secs);
+        let mut objects = self.state.storage.list();
+        while let Some(object) = objects.try_next().await? {
+            // the base dir of local storage has more than files
+            let Some((file, is_thumbnail)) = ChatFile::from_key(&object.key) else {
+                continue;
+            };
+            if object.modified > cutoff {
+                continue;
+            }
+            let stray = if report.dry_run {
+                !FileRepo::is_recorded(&file, is_thumbnail, &self.state.pool).await?
+            } else {
+                let mut tx = self.state.pool.begin().await?;
+                FileRepo::lock(&file, &mut tx).await?;
+                let stray = !FileRepo::is_recorded(&file, is_thumbnail, &mut *tx).await?;
+                if stray {
+                    self.state.storage.delete(&object.key).await?;
+                }
+                tx.commit().await?;
+                stray
+            };
+            if stray {
+                report.keys.push(object.key);
+                report.stray_bytes += object.size;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete what uploads left in the base dir of this replica: temp files older than the
+    /// grace period, and the content of resumable uploads which are gone.
+    pub async fn collect_leftovers(&self, dry_run: bool) -> Result<GcReport, AppError> {
+        let mut report = GcReport {
+            dry_run,
+            ..Default::default()
+        };
+        let base_dir = &self.state.config.server.base_dir;
+        let cutoff = Utc::now() - Duration::from_secs(self.state.config.file_gc.grace_secs);
+        for (path, metadata) in read_dir(&base_dir.join("tmp")).await? {
+            if DateTime::<Utc>::from(metadata.modified()?) < cutoff {
+                self.remove_leftover(&path, metadata.len(), &mut report)
+                    .await?;
+            }
+        }
+        for (path, metadata) in read_dir(&uploads_dir(&self.state)).await? {
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str()?.parse().ok());
+            let gone = match id {
+                Some(id) => !UploadSessionRepo::exists(id, &self.state.pool).await?,
+                None => true,
+            };
+            if gone {
+                self.remove_leftover(&path, metadata.len(), &mut report)
+                    .await?;
+            }
+        }
+        Ok(report)
+    }
+
+    async fn remove_leftover(
+        &self,
+        path: &Path,
+        size: u64,
+        report: &mut GcReport,
+    ) -> Result<(), AppError> {
+        if !report.dry_run {
+            fs::remove_file(path).await?;
+        }
+        let path = path
+            .strip_prefix(&self.state.config.server.base_dir)
+            .unwrap_or(path);
+        report.leftovers.push(path.display().to_string());
+        report.stray_bytes += size;
+        Ok(())
+    }
+
+    /// Delete a file and its thumbnails, unless it was attached or uploaded since it was found.
+    async fn delete(&self, meta: &FileMeta, grace_secs: u64) -> Result<bool, AppError> {
+        let file = meta.chat_file();
+        let mut tx = self.state.pool.begin().await?;
+        FileRepo::lock(&file, &mut tx).await?;
+        if !FileRepo::delete_orphan(meta.id, grace_secs, &mut tx).await? {
+            return Ok(false);
+        }
+        // the row is back if deleting the content fails
+        for thumbnail in &meta.thumbnails {
+            let key = file.thumbnail_key(thumbnail.size, &thumbnail.ext);
+            self.state.storage.delete(&key).await?;
+        }
+        self.state.storage.delete(&file.key()).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+/// The files in `dir`, none if it's missing.
+async fn read_dir(dir: &Path) -> Result<Vec<(std::path::PathBuf, std::fs::Metadata)>, AppError> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.push((entry.path(), metadata));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateMessage, CreateUpload};
+    use anyhow::Result;
+    use std::time::SystemTime;
+
+    async fn upload(state: &AppState, name: &str, data: &'static [u8]) -> Result<FileMeta> {
+        let file = ChatFile::new(1, name, data);
+        state.storage.put(&file.key(), data.into()).await?;
+        Ok(FileRepo::create(&file, name, data.len() as _, 1, &state.pool).await?)
+    }
+
+    async fn age(state: &AppState, meta: &FileMeta) -> Result<()> {
+        sqlx::query("UPDATE files SET last_uploaded_at = now() - interval '2 days' WHERE id = $1")
+            .bind(meta.id)
+            .execute(&state.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Make `path` in the base dir look written 2 days ago.
+    fn age_path(state: &AppState, path: &str) -> Result<()> {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(state.config.server.base_dir.join(path))?;
+        file.set_modified(SystemTime::now() - Duration::from_secs(2 * 24 * 3600))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreferenced_files_should_be_collected_after_grace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let orphan = upload(&state, "orphan.txt", b"nobody wants me").await?;
+        let attached = upload(&state, "attached.txt", b"in a message").await?;
+        let recent = upload(&state, "recent.txt", b"about to be sent").await?;
+        age(&state, &orphan).await?;
+        age(&state, &attached).await?;
+        let input = CreateMessage {
+            content: "see attached".to_string(),
+            files: vec![attached.chat_file().url()],
+        };
+        state
+            .message
+            .create_message(&state.pool, input, 1, 1)
+            .await?;
+
+        let collector = state.file_collector();
+        let report = collector.collect(true).await?;
+        assert_eq!(report.files, [orphan.chat_file().url()]);
+        assert_eq!(report.bytes, 15);
+        assert!(state.storage.exists(&orphan.chat_file().key()).await?);
+
+        let report = collector.collect(false).await?;
+        assert_eq!(report.files, [orphan.chat_file().url()]);
+        assert!(!state.storage.exists(&orphan.chat_file().key()).await?);
+        assert!(FileRepo::find(&orphan.chat_file(), &state.pool)
+            .await?
+            .is_none());
+        for meta in [attached, recent] {
+            assert!(state.storage.exists(&meta.chat_file().key()).await?);
+        }
+        assert_eq!(collector.collect(false).await?, GcReport::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_uploaded_again_should_be_kept() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let meta = upload(&state, "again.txt", b"same content").await?;
+        age(&state, &meta).await?;
+        let collector = state.file_collector();
+        let orphans = FileRepo::find_orphans(3600, BATCH, &state.pool).await?;
+        assert_eq!(orphans, std::slice::from_ref(&meta));
+
+        // the same content is uploaded between finding the orphan and deleting it
+        upload(&state, "again.txt", b"same content").await?;
+        assert!(!collector.delete(&meta, 3600).await?);
+        assert!(state.storage.exists(&meta.chat_file().key()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_without_a_size_should_be_measured() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let meta = upload(&state, "legacy.png", b"not quite a png").await?;
+        let input = CreateMessage {
+            content: "from before sizes were kept".to_string(),
+            files: vec![meta.chat_file().url()],
+        };
+        let message = state
+            .message
+            .create_message(&state.pool, input, 1, 1)
+            .await?;
+        sqlx::query("UPDATE files SET size = NULL WHERE id = $1")
+            .bind(meta.id)
+            .execute(&state.pool)
+            .await?;
+        let url = meta.chat_file().url();
+
+        let collector = state.file_collector();
+        let report = collector.collect(true).await?;
+        assert_eq!(report.measured, std::slice::from_ref(&url));
+        let legacy = FileRepo::find(&meta.chat_file(), &state.pool).await?;
+        assert_eq!(legacy.and_then(|meta| meta.size), None);
+
+        let report = collector.collect(false).await?;
+        assert_eq!(report.measured, [url]);
+        let measured = FileRepo::find(&meta.chat_file(), &state.pool).await?;
+        assert_eq!(measured.and_then(|meta| meta.size), Some(15));
+        let size: i64 =
+            sqlx::query_scalar("SELECT (files->0->>'size')::bigint FROM messages WHERE id = $1")
+                .bind(message.id)
+                .fetch_one(&state.pool)
+                .await?;
+        assert_eq!(size, 15);
+        assert!(collector.collect(false).await?.measured.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn what_no_file_has_should_be_collected() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let base_dir = &state.config.server.base_dir;
+        let kept = upload(&state, "kept.png", b"recorded").await?;
+        let kept_thumbnail = kept.chat_file().thumbnail_key(160, "webp");
+        state.storage.put(&kept_thumbnail, "kept".into()).await?;
+        let legacy = ChatFile::new(1, "legacy.txt", b"before files were recorded");
+        let legacy_thumbnail = legacy.thumbnail_key(160, "webp");
+        let recent = ChatFile::new(1, "recent.txt", b"being uploaded");
+        for key in [legacy.key(), legacy_thumbnail.clone(), recent.key()] {
+            state.storage.put(&key, "stray".into()).await?;
+        }
+        for key in [
+            &kept.chat_file().key(),
+            &kept_thumbnail,
+            &legacy.key(),
+            &legacy_thumbnail,
+        ] {
+            age_path(&state, key)?;
+        }
+
+        let user = state.test_user("cae@cae.org").await?;
+        let input = CreateUpload {
+            filename: "video.mp4".to_string(),
+            size: 10,
+        };
+        let ttl = state.config.uploads.ttl();
+        let session = UploadSessionRepo::create(input, &user, ttl, &state.pool).await?;
+        let gone = Uuid::now_v7();
+        std::fs::create_dir_all(uploads_dir(&state))?;
+        std::fs::write(uploads_dir(&state).join(session.id.to_string()), "live")?;
+        std::fs::write(uploads_dir(&state).join(gone.to_string()), "gone")?;
+        std::fs::create_dir_all(base_dir.join("tmp"))?;
+        std::fs::write(base_dir.join("tmp/old"), "old")?;
+        std::fs::write(base_dir.join("tmp/new"), "new")?;
+        age_path(&state, "tmp/old")?;
+
+        let collector = state.file_collector();
+        let mut keys = vec![legacy.key(), legacy_thumbnail.clone()];
+        keys.sort();
+        let mut leftovers = vec!["tmp/old".to_string(), format!("uploads/{gone}")];
+        leftovers.sort();
+        for dry_run in [true, false] {
+            let mut report = collector.collect(dry_run).await?;
+            report.keys.sort();
+            report.leftovers.sort();
+            assert_eq!(report.keys, keys);
+            assert_eq!(report.leftovers, leftovers);
+            assert_eq!(report.stray_bytes, 5 + 5 + 3 + 4);
+            assert_eq!(state.storage.exists(&legacy.key()).await?, dry_run);
+        }
+        assert!(!state.storage.exists(&legacy_thumbnail).await?);
+        for key in [kept.chat_file().key(), kept_thumbnail, recent.key()] {
+            assert!(state.storage.exists(&key).await?, "{key} should be kept");
+        }
+        assert!(!base_dir.join("tmp/old").exists());
+        assert!(base_dir.join("tmp/new").exists());
+        assert!(uploads_dir(&state).join(session.id.to_string()).exists());
+
+        let report = collector.collect(false).await?;
+        assert!(report.keys.is_empty() && report.leftovers.is_empty());
+        Ok(())
+    }
+}
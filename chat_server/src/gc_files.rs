@@ -0,0 +1,13 @@
+use anyhow::Result;
+use chat_server::{AppConfig, AppState};
+
+/// Collect the files no message has once, `--dry-run` only reports them.
+#[tokio::main]
+async fn main() -> Result<()> {
+    let dry_run = std::env::args().skip(1).any(|arg| arg == "--dry-run");
+    let config = AppConfig::load()?;
+    let state = AppState::try_new(config).await?;
+    let report = state.file_collector().collect(dry_run).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -1,9 +1,16 @@
-use crate::models::UserRepo;
+use crate::handlers::require_scope;
+use crate::models::{StreamTicketRepo, UserRepo, STREAM_TICKET_TTL};
 use crate::{
     models::{CreateUser, SigninUser},
     AppError, AppState, ErrorOutput,
 };
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Scope, User, STREAM_TICKET_COOKIE};
 use serde::{Deserialize, Serialize};
 use tracing::{info, instrument};
 use utoipa::ToSchema;
@@ -61,9 +68,42 @@ pub(crate) async fn signin_handler(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/stream-tickets",
+    responses(
+        (status = 201, description = "Single-use ticket to open a notify stream with", body = StreamTicket),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "auth",
+)]
+#[instrument(level = "debug", skip(state))]
+pub(crate) async fn create_stream_ticket_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let ticket = StreamTicketRepo::create(user.id as _, &state.pool).await?;
+    // also set as a cookie, so an EventSource on the same host sends it without any code
+    let cookie = format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Strict",
+        STREAM_TICKET_COOKIE,
+        ticket.ticket,
+        STREAM_TICKET_TTL.as_secs()
+    );
+    Ok((
+        StatusCode::CREATED,
+        [(header::SET_COOKIE, cookie)],
+        Json(ticket),
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::StreamTicket;
     use anyhow::Result;
     use http_body_util::BodyExt;
 
@@ -130,4 +170,21 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn create_stream_ticket_should_set_cookie() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let ret = create_stream_ticket_handler(Extension(user), State(state))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let cookie = ret.headers()[header::SET_COOKIE].to_str()?.to_string();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: StreamTicket = serde_json::from_slice(&body)?;
+        assert!(cookie.starts_with(&format!("stream_ticket={};", ret.ticket)));
+        assert!(cookie.contains("HttpOnly"));
+
+        Ok(())
+    }
 }
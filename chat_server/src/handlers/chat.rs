@@ -1,3 +1,4 @@
+use crate::handlers::require_scope;
 use crate::models::ChatRepo;
 use crate::{models::CreateChat, AppError, AppState};
 use axum::{
@@ -6,7 +7,7 @@ use axum::{
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{AppEvent, Scope, User};
 use tracing::info;
 
 #[utoipa::path(
@@ -24,6 +25,7 @@ pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
     let chat = ChatRepo::fetch_all(user.ws_id as _, &state.pool).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
@@ -44,8 +46,13 @@ pub(crate) async fn create_chat_handler(
     State(state): State<AppState>,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
     // TODO: validate whether all members are existed
     let chat = ChatRepo::create(input, user.ws_id as _, &state.pool).await?;
+    state
+        .publisher
+        .publish(chat.members.clone(), AppEvent::NewChat(chat.clone()))
+        .await;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
@@ -65,9 +72,11 @@ pub(crate) async fn create_chat_handler(
     tag = "chat",
 )]
 pub(crate) async fn get_chat_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
 ) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
     let chat = ChatRepo::get_by_id(id as _, &state.pool).await?;
     match chat {
         Some(chat) => Ok(Json(chat)),
@@ -75,21 +84,46 @@ pub(crate) async fn get_chat_handler(
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/mutes",
+    responses(
+        (status = 200, description = "Ids of the chats the user muted", body = Vec<i64>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "chat",
+)]
+pub(crate) async fn list_muted_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let muted = ChatRepo::fetch_muted(user.id as _, &state.pool).await?;
+    Ok(Json(muted))
+}
+
 pub(crate) async fn update_chat_handler(Path(_id): Path<u64>) -> impl IntoResponse {
     "update chat"
 }
 
 pub(crate) async fn delete_chat_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
 ) -> impl IntoResponse {
-    let chat = ChatRepo::get_by_id(id as _, &state.pool).await?;
-    if chat.is_none() {
+    require_scope(&user, Scope::ManageChats)?;
+    let Some(chat) = ChatRepo::get_by_id(id as _, &state.pool).await? else {
         return Err(AppError::NotFound(format!("chat id {id}")));
-    }
+    };
 
     let id = ChatRepo::delete_by_id(id as _, &state.pool).await?;
     info!("delete chat: {id}");
+    state
+        .publisher
+        .publish(chat.members.clone(), AppEvent::RemoveFromChat(chat))
+        .await;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -0,0 +1,87 @@
+use crate::handlers::require_scope;
+use crate::models::{CreateSlashCommand, SlashCommandRepo};
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Scope, User};
+
+#[utoipa::path(
+    get,
+    path = "/api/commands",
+    responses(
+        (status = 200, description = "Slash commands registered in the workspace", body = Vec<SlashCommand>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "command",
+)]
+pub(crate) async fn list_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let commands = SlashCommandRepo::fetch_all(user.ws_id as _, &state.pool).await?;
+    Ok(Json(commands))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/commands",
+    responses(
+        (status = 201, description = "Slash command registered, along with its secret", body = SlashCommand),
+        (status = 400, description = "Invalid slash command", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "command",
+)]
+pub(crate) async fn create_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateSlashCommand>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    let command = SlashCommandRepo::create(
+        input,
+        user.ws_id as _,
+        user.id as _,
+        &state.config.outbound,
+        &state.pool,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(command)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/commands/{id}",
+    params(
+        ("id" = u64, Path, description = "Slash command id")
+    ),
+    responses(
+        (status = 204, description = "Slash command removed"),
+        (status = 404, description = "Slash command not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "command",
+)]
+pub(crate) async fn delete_command_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    if SlashCommandRepo::delete(id, user.ws_id as _, &state.pool).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("command id {id}")))
+    }
+}
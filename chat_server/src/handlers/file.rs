@@ -0,0 +1,599 @@
+use std::ops::Range;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        header::{
+            ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_SECURITY_POLICY, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+            X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chat_core::{Scope, User};
+use chrono::Utc;
+
+use crate::file_url::{FileSignature, SignFile};
+use crate::handlers::require_scope;
+use crate::models::{ChatFile, FileQuery, FileRepo, UserRepo};
+use crate::{AppError, AppState, Storage};
+
+/// File urls are content-addressed, what's behind one never changes.
+const IMMUTABLE: &str = "private, max-age=31536000, immutable";
+
+/// Image types shown inline, those which can't carry scripts, unlike `image/svg+xml`.
+const INLINE_IMAGES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+/// What a `Range` header asks for.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No range, or one that isn't supported, e.g. multiple ranges: the whole file is sent.
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Download a file, or one of its thumbnails, with caching and range requests.
+pub(crate) async fn file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    Query(query): Query<FileQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let file = find_file(&state, &user, ws_id, &path).await?;
+    serve_file(&state, &file, query.size, IMMUTABLE, method, headers).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/signed-urls",
+    request_body = SignFile,
+    responses(
+        (status = 200, description = "Url to download the file without a token", body = SignedFileUrl),
+        (status = 403, description = "Signed urls aren't enabled", body = ErrorOutput),
+        (status = 404, description = "File not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn sign_file_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<SignFile>,
+) -> Result<impl IntoResponse, AppError> {
+    let (ws_id, path) = input
+        .url
+        .strip_prefix("/files/")
+        .and_then(|url| url.split_once('/'))
+        .and_then(|(ws_id, path)| Some((ws_id.parse().ok()?, path)))
+        .ok_or_else(|| AppError::NotFound(format!("File {} doesn't exist", input.url)))?;
+    let file = find_file(&state, &user, ws_id, path).await?;
+    if !state.storage.exists(&file.key()).await? {
+        return Err(AppError::NotFound("File doesn't exist".to_string()));
+    }
+    let signed = state.config.file_urls.sign(&file, user.id, Utc::now())?;
+    Ok(Json(signed))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/signed/files/{ws_id}/{path}",
+    params(
+        ("ws_id" = i64, Path, description = "Workspace id"),
+        ("path" = String, Path, description = "Path of the file"),
+        FileSignature,
+        FileQuery,
+    ),
+    responses(
+        (status = 200, description = "Content of the file"),
+        (status = 206, description = "Part of the file asked for with `Range`"),
+        (status = 403, description = "Invalid or expired signature", body = ErrorOutput),
+        (status = 404, description = "File not found", body = ErrorOutput),
+    ),
+    tag = "message",
+)]
+pub(crate) async fn signed_file_handler(
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(i64, String)>,
+    Query(signature): Query<FileSignature>,
+    Query(query): Query<FileQuery>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let now = Utc::now();
+    let file: ChatFile = format!("/files/{ws_id}/{path}").parse()?;
+    state.config.file_urls.verify(&file, &signature, now)?;
+    // the url is only as good as its user's access
+    let user = UserRepo::find_by_id(signature.user as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::Forbidden("invalid file signature".to_string()))?;
+    let file = find_file(&state, &user, ws_id, &path).await?;
+
+    // not cached past the url's expiry
+    let max_age = signature.expires - now.timestamp();
+    let cache_control = format!("private, max-age={max_age}");
+    serve_file(&state, &file, query.size, &cache_control, method, headers).await
+}
+
+/// A file of `ws_id` at `path`, if `user` uploaded it or is in a chat it was shared in.
+async fn find_file(
+    state: &AppState,
+    user: &User,
+    ws_id: i64,
+    path: &str,
+) -> Result<ChatFile, AppError> {
+    require_scope(user, Scope::ReadMessages)?;
+    let not_found =
+        || AppError::NotFound("File doesn't exist or you don't have permission".to_string());
+    if user.ws_id != ws_id {
+        return Err(not_found());
+    }
+    let file: ChatFile = format!("/files/{ws_id}/{path}")
+        .parse()
+        .map_err(|_| not_found())?;
+    if !FileRepo::can_access(&file, user.id as _, &state.pool).await? {
+        return Err(not_found());
+    }
+    Ok(file)
+}
+
+async fn serve_file(
+    state: &AppState,
+    file: &ChatFile,
+    thumbnail: Option<u32>,
+    cache_control: &str,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let not_found = || AppError::NotFound("File doesn't exist".to_string());
+    let meta = FileRepo::find(file, &state.pool).await?;
+    // the file, or one of its thumbnails
+    let (key, etag, mime, disposition) = match thumbnail {
+        None => {
+            let key = file.key();
+            let (mime, disposition) = match &meta {
+                Some(meta) => (
+                    meta.mime.clone(),
+                    Some(content_disposition(&meta.name, &meta.mime)),
+                ),
+                None => {
+                    let mime = mime_guess::from_path(&key).first_or_octet_stream();
+                    let name = key.rsplit('/').next().unwrap_or_default();
+                    let disposition = content_disposition(name, mime.essence_str());
+                    (mime.to_string(), Some(disposition))
+                }
+            };
+            (key, format!("\"{}\"", file.hash), mime, disposition)
+        }
+        Some(size) => {
+            let thumbnail = meta
+                .iter()
+                .flat_map(|meta| &meta.thumbnails)
+                .find(|thumbnail| thumbnail.size == size)
+                .ok_or_else(|| AppError::NotFound(format!("File has no {size}px thumbnail")))?;
+            let key = file.thumbnail_key(size, &thumbnail.ext);
+            let mime = mime_guess::from_ext(&thumbnail.ext).first_or_octet_stream();
+            (
+                key,
+                format!("\"{}.{size}\"", file.hash),
+                mime.to_string(),
+                None,
+            )
+        }
+    };
+    let size = state.storage.size(&key).await?.ok_or_else(not_found)?;
+
+    // whatever was uploaded, it's never sniffed into or run as a page of the api's origin
+    let res = Response::builder()
+        .header(X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(CONTENT_SECURITY_POLICY, "sandbox")
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, cache_control)
+        .header(ACCEPT_RANGES, "bytes");
+    if matches_etag(headers.get(IF_NONE_MATCH), &etag) {
+        let res = res.status(StatusCode::NOT_MODIFIED).body(Body::empty());
+        return Ok(res.expect("response should be valid"));
+    }
+
+    let mut res = res.header(CONTENT_TYPE, mime);
+    if let Some(disposition) = disposition {
+        res = res.header(CONTENT_DISPOSITION, disposition);
+    }
+
+    // a range is only honoured while the file is the one the client has part of
+    let if_range = headers.get(IF_RANGE);
+    let range = match headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) if if_range.is_none() || matches_etag(if_range, &etag) => {
+            parse_range(range, size)
+        }
+        _ => ByteRange::Full,
+    };
+    let head = method == Method::HEAD;
+    let res = match range {
+        ByteRange::Full => {
+            let body = match head {
+                true => Body::empty(),
+                false => Body::from_stream(state.storage.get(&key).await?.ok_or_else(not_found)?),
+            };
+            res.header(CONTENT_LENGTH, size).body(body)
+        }
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{size}", range.start, range.end - 1);
+            let len = range.end - range.start;
+            let body = match head {
+                true => Body::empty(),
+                false => {
+                    let stream = state.storage.get_range(&key, range).await?;
+                    Body::from_stream(stream.ok_or_else(not_found)?)
+                }
+            };
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, content_range)
+                .header(CONTENT_LENGTH, len)
+                .body(body)
+        }
+        ByteRange::Unsatisfiable => res
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{size}"))
+            .body(Body::empty()),
+    };
+    Ok(res.expect("response should be valid"))
+}
+
+/// Whether an `If-None-Match` or `If-Range` header matches `etag`, weakly.
+fn matches_etag(header: Option<&axum::http::HeaderValue>, etag: &str) -> bool {
+    let Some(header) = header.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Parse a `Range` header of a file of `size` bytes, only single byte ranges are supported.
+fn parse_range(header: &str, size: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(size),
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        (Err(_), Ok(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+        _ => return ByteRange::Full,
+    };
+    if range.start >= size || range.is_empty() {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// Raster images, video and audio are shown inline, anything else downloaded, under the name it
+/// was uploaded with.
+fn content_disposition(name: &str, mime: &str) -> String {
+    let inline = INLINE_IMAGES.contains(&mime)
+        || ["video/", "audio/"]
+            .iter()
+            .any(|prefix| mime.starts_with(prefix));
+    let disposition = if inline { "inline" } else { "attachment" };
+    let ascii: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String = name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect();
+    format!("{disposition}; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_url::SignedFileUrl;
+    use crate::handlers::messages::tests::{multipart, upload_body};
+    use crate::handlers::{store_file, upload_handler};
+    use crate::models::CreateMessage;
+    use anyhow::Result;
+    use http_body_util::BodyExt;
+    use image::{ImageBuffer, ImageFormat, Rgb};
+    use std::io::Cursor;
+
+    #[test]
+    fn range_should_be_parsed() {
+        assert_eq!(parse_range("bytes=0-4", 10), ByteRange::Partial(0..5));
+        assert_eq!(parse_range("bytes=5-", 10), ByteRange::Partial(5..10));
+        assert_eq!(parse_range("bytes=-3", 10), ByteRange::Partial(7..10));
+        assert_eq!(parse_range("bytes=8-100", 10), ByteRange::Partial(8..10));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 10), ByteRange::Full);
+        assert_eq!(parse_range("bytes=4-1", 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn content_disposition_should_carry_the_name() {
+        assert_eq!(
+            content_disposition("été \"1\".png", "image/png"),
+            "inline; filename=\"_t_ _1_.png\"; filename*=UTF-8''%C3%A9t%C3%A9%20%221%22.png"
+        );
+        assert!(content_disposition("clip.mp4", "video/mp4").starts_with("inline;"));
+        assert!(content_disposition("logo.svg", "image/svg+xml").starts_with("attachment;"));
+        assert!(content_disposition("page.html", "text/html").starts_with("attachment;"));
+    }
+
+    #[tokio::test]
+    async fn file_download_should_support_caching_and_ranges() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let file = ChatFile::new(1, "clip.mp4", b"0123456789");
+        state.storage.put(&file.key(), "0123456789".into()).await?;
+        FileRepo::create(&file, "clip.mp4", 10, 1, &state.pool).await?;
+        let path = file.key().split_once('/').unwrap().1.to_string();
+
+        let get = |method: Method, headers: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            file_handler(
+                Extension(user.clone()),
+                State(state.clone()),
+                Path((1, path.clone())),
+                Query(FileQuery { size: None }),
+                method,
+                map,
+            )
+        };
+
+        let res = get(Method::GET, &[]).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[ETAG].to_str()?.to_string();
+        assert_eq!(etag, format!("\"{}\"", file.hash));
+        assert_eq!(res.headers()[CACHE_CONTROL], IMMUTABLE);
+        assert_eq!(res.headers()[CONTENT_TYPE], "video/mp4");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "10");
+
+        let res = get(Method::GET, &[("if-none-match", &etag)]).await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let res = get(Method::GET, &[("range", "bytes=2-5")]).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes 2-5/10");
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "2345");
+
+        // the range is for another version of the file
+        let res = get(
+            Method::GET,
+            &[("range", "bytes=2-5"), ("if-range", "\"old\"")],
+        )
+        .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = get(Method::GET, &[("range", "bytes=20-")]).await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[CONTENT_RANGE], "bytes */10");
+
+        let res = get(Method::HEAD, &[]).await?;
+        assert_eq!(res.headers()[CONTENT_LENGTH], "10");
+        let body = res.into_body().collect().await?.to_bytes();
+        assert!(body.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_url_should_download_without_a_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let file = ChatFile::new(1, "cat.png", b"meow");
+        state.storage.put(&file.key(), "meow".into()).await?;
+        FileRepo::create(&file, "cat.png", 4, 1, &state.pool).await?;
+
+        let input = SignFile { url: file.url() };
+        let res = sign_file_handler(Extension(user.clone()), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = res.into_body().collect().await?.to_bytes();
+        let signed: SignedFileUrl = serde_json::from_slice(&body)?;
+
+        let uri: axum::http::Uri = signed.url.parse()?;
+        let Query(signature) = Query::<FileSignature>::try_from_uri(&uri)?;
+        let path = file.key().split_once('/').unwrap().1.to_string();
+        let download = |signature: FileSignature| {
+            signed_file_handler(
+                State(state.clone()),
+                Path((1, path.clone())),
+                Query(signature),
+                Query(FileQuery { size: None }),
+                Method::GET,
+                HeaderMap::new(),
+            )
+        };
+        let res = download(signature.clone()).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(res.headers()[CONTENT_SECURITY_POLICY], "sandbox");
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "meow");
+
+        let forged = FileSignature {
+            user: 2,
+            ..signature
+        };
+        let res = download(forged).await.into_response();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // only files of the user's workspace are signed
+        let input = SignFile {
+            url: "/files/2/abc/def/0123456789.png".to_string(),
+        };
+        let res = sign_file_handler(Extension(user), State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_download_should_be_limited_to_chat_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "dm.txt", b"between us");
+        state.storage.put(&file.key(), "between us".into()).await?;
+        FileRepo::create(&file, "dm.txt", 10, 1, &state.pool).await?;
+        let path = file.key().split_once('/').unwrap().1.to_string();
+
+        let download = |email: &'static str, path: String| {
+            let state = state.clone();
+            async move {
+                let user = UserRepo::find_by_email(email, &state.pool)
+                    .await?
+                    .expect("user should exist");
+                let res = file_handler(
+                    Extension(user),
+                    State(state),
+                    Path((1, path)),
+                    Query(FileQuery { size: None }),
+                    Method::GET,
+                    HeaderMap::new(),
+                )
+                .await
+                .into_response();
+                anyhow::Ok(res.status())
+            }
+        };
+        assert_eq!(download("cae@cae.org", path.clone()).await?, StatusCode::OK);
+        assert_eq!(
+            download("alice@cae.org", path.clone()).await?,
+            StatusCode::NOT_FOUND
+        );
+
+        // shared in the single chat of users 1 and 2
+        let input = CreateMessage {
+            content: "here".to_string(),
+            files: vec![file.url()],
+        };
+        state
+            .message
+            .create_message(&state.pool, input, 3, 1)
+            .await?;
+        assert_eq!(
+            download("alice@cae.org", path.clone()).await?,
+            StatusCode::OK
+        );
+        assert_eq!(
+            download("bob@cae.org", path.clone()).await?,
+            StatusCode::NOT_FOUND
+        );
+
+        for path in [
+            "../../../etc/passwd",
+            "aaf/4c6/../../../../app.yaml",
+            "aaf/4c6/..%2F..%2Fapp.yaml",
+        ] {
+            let status = download("cae@cae.org", path.to_string()).await?;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{path} should be refused");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn image_uploads_should_get_thumbnails() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let mut png = Cursor::new(Vec::new());
+        ImageBuffer::from_pixel(800, 600, Rgb([10u8, 20, 30]))
+            .write_to(&mut png, ImageFormat::Png)?;
+        let png = png.into_inner();
+        let tmp = state.config.server.base_dir.join("tmp");
+        std::fs::create_dir_all(&tmp)?;
+        let tmp = tmp.join(uuid::Uuid::now_v7().to_string());
+        std::fs::write(&tmp, &png)?;
+
+        let file = ChatFile::new(1, "photo.png", &png);
+        let mut tx = state.pool.begin().await?;
+        let meta = store_file(&state, &mut tx, &file, "photo.png", png.len() as _, 1, &tmp).await?;
+        tx.commit().await?;
+        let attachment = meta.attachment();
+        assert_eq!(attachment.width, Some(800));
+        assert_eq!(attachment.height, Some(600));
+        let sizes: Vec<_> = attachment.thumbnails.iter().map(|t| t.size).collect();
+        assert_eq!(sizes, [160, 640]);
+        let thumbnail = &attachment.thumbnails[0];
+        assert_eq!((thumbnail.width, thumbnail.height), (160, 120));
+        assert_eq!(thumbnail.url, format!("{}?size=160", file.url()));
+
+        let path = file.key().split_once('/').unwrap().1.to_string();
+        let get = |size: u32| {
+            file_handler(
+                Extension(user.clone()),
+                State(state.clone()),
+                Path((1, path.clone())),
+                Query(FileQuery { size: Some(size) }),
+                Method::GET,
+                HeaderMap::new(),
+            )
+        };
+        let res = get(160).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/jpeg");
+        assert_eq!(res.headers()[ETAG], format!("\"{}.160\"", file.hash));
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(image::load_from_memory(&body)?.width(), 160);
+
+        let res = get(100).await.into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn svg_uploads_should_be_downloaded() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(1)</script></svg>"#;
+        let form = multipart(&upload_body(&[("x.svg", svg)])).await?;
+        let res = upload_handler(Extension(user.clone()), State(state.clone()), form)
+            .await?
+            .into_response();
+        let body = res.into_body().collect().await?.to_bytes();
+        let urls: Vec<String> = serde_json::from_slice(&body)?;
+        let path = urls[0].strip_prefix("/files/1/").unwrap().to_string();
+
+        let res = file_handler(
+            Extension(user),
+            State(state),
+            Path((1, path)),
+            Query(FileQuery { size: None }),
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await?;
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/svg+xml");
+        let disposition = res.headers()[CONTENT_DISPOSITION].to_str()?;
+        assert!(disposition.starts_with("attachment;"));
+        assert_eq!(res.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(res.headers()[CONTENT_SECURITY_POLICY], "sandbox");
+        Ok(())
+    }
+}
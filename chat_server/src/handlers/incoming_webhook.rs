@@ -0,0 +1,262 @@
+use crate::handlers::{post_message, require_scope};
+use crate::models::{
+    ChatRepo, CreateIncomingWebhook, CreateMessage, IncomingMessage, IncomingWebhookRepo,
+};
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Chat, Scope, User};
+
+/// The chat, if it exists and `user` is one of its members.
+async fn member_chat(id: u64, user: &User, state: &AppState) -> Result<Chat, AppError> {
+    require_scope(user, Scope::ManageChats)?;
+    match ChatRepo::get_by_id(id, &state.pool).await? {
+        Some(chat) if chat.ws_id == user.ws_id && chat.members.contains(&user.id) => Ok(chat),
+        _ => Err(AppError::NotFound(format!("chat id {id}"))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/chats/{id}/incoming-webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 200, description = "Incoming webhooks of the chat", body = Vec<IncomingWebhook>),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn list_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    member_chat(id, &user, &state).await?;
+    let webhooks = IncomingWebhookRepo::fetch_all(id, &state.pool).await?;
+    Ok(Json(webhooks))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/chats/{id}/incoming-webhooks",
+    params(
+        ("id" = u64, Path, description = "Chat id")
+    ),
+    responses(
+        (status = 201, description = "Webhook created, along with its token", body = IncomingWebhook),
+        (status = 400, description = "Invalid webhook", body = ErrorOutput),
+        (status = 404, description = "Chat not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn create_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Json(input): Json<CreateIncomingWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    member_chat(id, &user, &state).await?;
+    let webhook =
+        IncomingWebhookRepo::create(input, id, user.ws_id as _, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/chats/{id}/incoming-webhooks/{hook_id}",
+    params(
+        ("id" = u64, Path, description = "Chat id"),
+        ("hook_id" = u64, Path, description = "Incoming webhook id")
+    ),
+    responses(
+        (status = 204, description = "Webhook revoked"),
+        (status = 404, description = "Webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn delete_incoming_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((id, hook_id)): Path<(u64, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    member_chat(id, &user, &state).await?;
+    if IncomingWebhookRepo::delete(hook_id, id, &state.pool).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("incoming webhook id {hook_id}")))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/hooks/{token}",
+    params(
+        ("token" = String, Path, description = "Incoming webhook token")
+    ),
+    request_body = IncomingMessage,
+    responses(
+        (status = 201, description = "Message posted", body = Message),
+        (status = 400, description = "Empty message", body = ErrorOutput),
+        (status = 404, description = "Unknown or revoked webhook", body = ErrorOutput),
+        (status = 429, description = "Rate limited", body = ErrorOutput),
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn post_incoming_webhook_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(input): Json<IncomingMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(webhook) = IncomingWebhookRepo::find_by_token(&token, &state.pool).await? else {
+        return Err(AppError::NotFound("incoming webhook".to_string()));
+    };
+    if !state.incoming_limiter.check(webhook.id) {
+        return Err(AppError::TooManyRequests(format!(
+            "incoming webhook {}",
+            webhook.name
+        )));
+    }
+    let Some(chat) = ChatRepo::get_by_id(webhook.chat_id as _, &state.pool).await? else {
+        return Err(AppError::NotFound(format!("chat id {}", webhook.chat_id)));
+    };
+
+    let input = CreateMessage {
+        content: input.content(),
+        files: vec![],
+    };
+    let msg = post_message(&state, &chat, webhook.bot_id, &webhook.name, input).await?;
+    Ok((StatusCode::CREATED, Json(msg)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::IncomingWebhook;
+    use crate::EventPublisher;
+    use anyhow::Result;
+    use chat_core::{AppEvent, InMemoryPubSub, Subscriber};
+    use futures::StreamExt;
+    use http_body_util::BodyExt;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn text(text: &str) -> Json<IncomingMessage> {
+        Json(IncomingMessage {
+            text: Some(text.to_string()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_post_as_bot_until_revoked() -> Result<()> {
+        let pubsub = InMemoryPubSub::new();
+        let (_tdb, state) =
+            AppState::new_for_test_with_publisher(EventPublisher::InMemory(pubsub.clone())).await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let mut bob = Box::pin(pubsub.subscribe(3).await?);
+
+        // chat 2 is the private channel of users 1, 2 and 3
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+        };
+        let ret = create_incoming_webhook_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(2),
+            Json(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let webhook: IncomingWebhook = serde_json::from_slice(&body)?;
+        let token = webhook.token.clone().expect("token should be returned");
+
+        let ret = post_incoming_webhook_handler(
+            State(state.clone()),
+            Path(token.clone()),
+            text("build passed"),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let message = timeout(Duration::from_secs(1), bob.next())
+            .await?
+            .expect("bob should receive the message");
+        match message.event {
+            AppEvent::NewMessage(msg) => {
+                assert_eq!(msg.chat_id, 2);
+                assert_eq!(msg.sender_id, webhook.bot_id);
+                assert_eq!(msg.content, "build passed");
+                assert_eq!(msg.sender_name.as_deref(), Some("CI"));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        let ret =
+            post_incoming_webhook_handler(State(state.clone()), Path(token.clone()), text(""))
+                .await
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+
+        delete_incoming_webhook_handler(
+            Extension(user),
+            State(state.clone()),
+            Path((2, webhook.id as _)),
+        )
+        .await?;
+        let ret = post_incoming_webhook_handler(State(state), Path(token), text("build passed"))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_be_rate_limited() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateIncomingWebhook {
+            name: "alerts".to_string(),
+        };
+        let webhook = IncomingWebhookRepo::create(input, 1, 1, 1, &state.pool).await?;
+        let token = webhook.token.expect("token should be returned");
+
+        for _ in 0..state.config.incoming_webhooks.rate_limit {
+            post_incoming_webhook_handler(State(state.clone()), Path(token.clone()), text("alert"))
+                .await?;
+        }
+        let ret = post_incoming_webhook_handler(State(state), Path(token), text("alert"))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_members_should_manage_incoming_webhooks() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // daisy isn't in chat 2
+        let user = state.test_user("daisy@cae.org").await?;
+        let ret = list_incoming_webhook_handler(Extension(user), State(state), Path(2))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -1,31 +1,91 @@
-use crate::models::{ChatFile, CreateMessage, ListMessages};
-use crate::{AppError, AppState};
-use axum::body::Body;
+use crate::commands::{self, CommandOutcome};
+use crate::handlers::require_scope;
+use crate::models::{
+    ChatFile, ChatRepo, CreateMessage, FileMeta, FileRepo, ListMessages, StoragePolicy,
+    WorkspaceRepo,
+};
+use crate::thumbnail::{self, Thumbnails};
+use crate::{AppError, AppState, Storage};
 use axum::extract::Query;
 use axum::http::StatusCode;
 use axum::{
-    extract::{Multipart, Path, State},
-    http::HeaderMap,
-    response::IntoResponse,
+    extract::{
+        multipart::{Field, MultipartError},
+        Multipart, Path, State,
+    },
+    response::{IntoResponse, Response},
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{AppEvent, Chat, Message, Scope, User};
+use sha1::{Digest, Sha1};
+use sqlx::PgConnection;
 use tokio::fs;
-use tokio_util::io::ReaderStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task;
 use tracing::{info, warn};
+use uuid::Uuid;
 
+/// Post a message to the chat, or run the slash command it starts with.
+///
+/// A message starting with `//` is posted as text, without its first `/`.
 pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
-    Json(input): Json<CreateMessage>,
-) -> Result<impl IntoResponse, AppError> {
+    Json(mut input): Json<CreateMessage>,
+) -> Result<Response, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
+    let Some(chat) = ChatRepo::get_by_id(id as _, &state.pool).await? else {
+        return Err(AppError::NotFound(format!("chat id {id}")));
+    };
+
+    if let Some(invocation) = commands::parse(&input.content) {
+        let ret = match commands::run(&state, &user, &chat, invocation).await? {
+            CommandOutcome::Posted(msg) => (StatusCode::CREATED, Json(msg)).into_response(),
+            CommandOutcome::Reply(reply) => {
+                state
+                    .publisher
+                    .publish_relayed(
+                        &state.pool,
+                        [user.id],
+                        AppEvent::CommandReply(reply.clone()),
+                    )
+                    .await;
+                Json(reply).into_response()
+            }
+            CommandOutcome::Done => StatusCode::NO_CONTENT.into_response(),
+        };
+        return Ok(ret);
+    }
+    if input.content.starts_with("//") {
+        input.content.remove(0);
+    }
+
+    let msg = post_message(&state, &chat, user.id, &user.fullname, input).await?;
+    Ok((StatusCode::CREATED, Json(msg)).into_response())
+}
+
+/// Store a message from `sender_id` and publish it to the chat members.
+pub(crate) async fn post_message(
+    state: &AppState,
+    chat: &Chat,
+    sender_id: i64,
+    sender_name: &str,
+    input: CreateMessage,
+) -> Result<Message, AppError> {
     let msg = state
         .message
-        .create_message(&state.pool, input, id, user.id as _)
+        .create_message(&state.pool, input, chat.id as _, sender_id as _)
         .await?;
 
-    Ok((StatusCode::CREATED, Json(msg)))
+    let mut event = msg.clone();
+    event.sender_name = Some(sender_name.to_string());
+    state
+        .publisher
+        .publish(chat.members.clone(), AppEvent::NewMessage(event))
+        .await;
+
+    Ok(msg)
 }
 
 #[utoipa::path(
@@ -46,41 +106,16 @@ pub(crate) async fn send_message_handler(
     tag = "message",
 )]
 pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<u64>,
     Query(input): Query<ListMessages>,
 ) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
     let messages = state.message.list_messages(&state.pool, input, id).await?;
     Ok(Json(messages))
 }
 
-pub(crate) async fn file_handler(
-    Extension(user): Extension<User>,
-    State(state): State<AppState>,
-    Path((ws_id, path)): Path<(i64, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
-        return Err(AppError::NotFound(
-            "File doesn't exist or you don't have permission".to_string(),
-        ));
-    }
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
-        return Err(AppError::NotFound("File doesn't exist".to_string()));
-    }
-
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-
-    let file = fs::File::open(path).await?;
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-
-    let mut headers = HeaderMap::new();
-    headers.insert("content-type", mime.to_string().parse().unwrap());
-    Ok((headers, body))
-}
-
 #[utoipa::path(
     post,
     path = "/api/upload",
@@ -88,7 +123,10 @@ pub(crate) async fn file_handler(
         content_type = "multipart/form-data", content = FileForm,
     ),
     responses(
-        (status = 400, description = "Chat file error", body = ErrorOutput),
+        (status = 200, description = "Urls of the uploaded files", body = Vec<String>),
+        (status = 400, description = "Malformed upload", body = ErrorOutput),
+        (status = 413, description = "File larger than the max upload size, or over the workspace's quota", body = ErrorOutput),
+        (status = 415, description = "File type not allowed in the workspace", body = ErrorOutput),
     ),
     security(
         ("token" = [])
@@ -100,26 +138,304 @@ pub(crate) async fn upload_handler(
     State(state): State<AppState>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
+    let policy = WorkspaceRepo::settings(ws_id, &state.pool).await?.storage;
     let mut files = vec![];
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let filename = field.file_name().map(|name| name.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
-            warn!("Failed to read multipart field");
+    while let Some(field) = multipart.next_field().await.map_err(multipart_error)? {
+        let Some(filename) = field.file_name().map(|name| name.to_string()) else {
+            warn!("Skipping multipart field without a file name");
             continue;
         };
+        policy.check_name(&filename)?;
 
-        let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
-        }
-        files.push(file.url());
+        let meta = store_upload(&state, &policy, ws_id, user.id as _, &filename, field).await?;
+        files.push(meta.chat_file().url());
     }
 
     Ok(Json(files))
 }
+
+/// Stream an uploaded file to a temp file, hashing it on the way, then move it to its key.
+async fn store_upload(
+    state: &AppState,
+    policy: &StoragePolicy,
+    ws_id: u64,
+    user_id: u64,
+    filename: &str,
+    mut field: Field<'_>,
+) -> Result<FileMeta, AppError> {
+    let tmp_dir = state.config.server.base_dir.join("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+    let tmp = tmp_dir.join(Uuid::now_v7().to_string());
+
+    let max_size = policy.max_file_size(state.config.server.max_upload_size);
+    let ret = match write_field(&mut field, &tmp, max_size).await {
+        Ok((hash, size)) => {
+            let file = ChatFile::with_hash(ws_id, filename, hash);
+            let mut tx = state.pool.begin().await?;
+            let meta = store_file(state, &mut tx, &file, filename, size, user_id, &tmp).await?;
+            tx.commit().await?;
+            Ok(meta)
+        }
+        Err(e) => Err(e),
+    };
+    if ret.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    ret
+}
+
+/// Move the complete local file at `path` to the storage, unless the same content is there,
+/// and record it was uploaded as `name` within `tx`, which the caller commits. Only new content
+/// counts towards the workspace's quota.
+pub(crate) async fn store_file(
+    state: &AppState,
+    tx: &mut PgConnection,
+    file: &ChatFile,
+    name: &str,
+    size: u64,
+    uploaded_by: u64,
+    path: &std::path::Path,
+) -> Result<FileMeta, AppError> {
+    let key = file.key();
+    let policy = WorkspaceRepo::settings(file.ws_id, &mut *tx).await?.storage;
+    policy.check_content(name, &read_head(path).await?)?;
+    if policy.quota_bytes.is_some() {
+        WorkspaceRepo::lock_storage(file.ws_id, &mut *tx).await?;
+    }
+    // kept from the file collector until it's recorded as uploaded again
+    FileRepo::lock(file, &mut *tx).await?;
+    // content stored before files were recorded is stored again, for its thumbnails
+    let recorded = FileRepo::find(file, &mut *tx).await?.is_some();
+    if recorded && state.storage.exists(&key).await? {
+        info!("File {} already exists", key);
+        fs::remove_file(path).await?;
+        return FileRepo::create(file, name, size, uploaded_by, &mut *tx).await;
+    }
+    if !recorded {
+        let usage = WorkspaceRepo::storage_usage(file.ws_id, &mut *tx).await?;
+        policy.check_quota(usage.used(), size)?;
+    }
+
+    let image = thumbnails(state, path).await;
+    state.storage.put_file(&key, path).await?;
+    let mut meta = FileRepo::create(file, name, size, uploaded_by, &mut *tx).await?;
+    if let Some(image) = image {
+        let mut thumbnails = Vec::with_capacity(image.thumbnails.len());
+        for (thumbnail, data) in image.thumbnails {
+            let key = file.thumbnail_key(thumbnail.size, &thumbnail.ext);
+            state.storage.put(&key, data.into()).await?;
+            thumbnails.push(thumbnail);
+        }
+        meta =
+            FileRepo::set_image(meta.id, image.width, image.height, &thumbnails, &mut *tx).await?;
+    }
+    Ok(meta)
+}
+
+/// The first bytes of the file at `path`, enough to tell its type.
+async fn read_head(path: &std::path::Path) -> Result<Vec<u8>, AppError> {
+    let mut head = Vec::with_capacity(512);
+    fs::File::open(path)
+        .await?
+        .take(512)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(head)
+}
+
+/// Thumbnails of the file at `path` if it's an image, failing to make them isn't an error.
+async fn thumbnails(state: &AppState, path: &std::path::Path) -> Option<Thumbnails> {
+    let sizes = state.config.thumbnails.sizes.clone();
+    let path = path.to_path_buf();
+    match task::spawn_blocking(move || thumbnail::generate(&path, &sizes)).await {
+        Ok(Ok(image)) => image,
+        Ok(Err(e)) => {
+            warn!("failed to generate thumbnails: {}", e);
+            None
+        }
+        Err(e) => {
+            warn!("failed to generate thumbnails: {}", e);
+            None
+        }
+    }
+}
+
+/// Write `field` to `path`, returning its hex SHA-1 and size.
+async fn write_field(
+    field: &mut Field<'_>,
+    path: &std::path::Path,
+    max_size: u64,
+) -> Result<(String, u64), AppError> {
+    let mut file = fs::File::create(path).await?;
+    let mut hasher = Sha1::new();
+    let mut size = 0;
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(AppError::PayloadTooLarge(format!(
+                "files are limited to {max_size} bytes"
+            )));
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+fn multipart_error(e: MultipartError) -> AppError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        AppError::PayloadTooLarge(e.body_text())
+    } else {
+        AppError::UploadError(e.body_text())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::handlers::file_handler;
+    use crate::models::FileQuery;
+    use crate::EventPublisher;
+    use anyhow::Result;
+    use axum::body::Body;
+    use axum::extract::FromRequest;
+    use axum::http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, Method,
+    };
+    use chat_core::{InMemoryPubSub, Subscriber};
+    use futures::StreamExt;
+    use http_body_util::BodyExt;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    pub(crate) async fn multipart(body: &str) -> Result<Multipart> {
+        let req = axum::http::Request::builder()
+            .header("content-type", "multipart/form-data; boundary=X")
+            .body(Body::from(body.to_string()))?;
+        Ok(Multipart::from_request(req, &()).await?)
+    }
+
+    /// A multipart body uploading `files`, named with their content.
+    pub(crate) fn upload_body(files: &[(&str, &str)]) -> String {
+        let mut body = String::new();
+        for (name, content) in files {
+            body.push_str(&format!(
+                "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\r\n{content}\r\n"
+            ));
+        }
+        body + "--X--\r\n"
+    }
+
+    const HELLO_UPLOAD: &str = "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"hello.txt\"\r\n\r\nhello\r\n--X--\r\n";
+
+    #[tokio::test]
+    async fn send_message_should_publish_to_members() -> Result<()> {
+        let pubsub = InMemoryPubSub::new();
+        let (_tdb, state) =
+            AppState::new_for_test_with_publisher(EventPublisher::InMemory(pubsub.clone())).await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let mut bob = Box::pin(pubsub.subscribe(3).await?);
+        let mut daisy = Box::pin(pubsub.subscribe(5).await?);
+
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+        };
+        // chat 2 is the private channel of users 1, 2 and 3
+        send_message_handler(Extension(user), State(state), Path(2), Json(input)).await?;
+
+        let message = timeout(Duration::from_secs(1), bob.next())
+            .await?
+            .expect("bob should receive the message");
+        assert_eq!(message.user_id, 3);
+        match message.event {
+            AppEvent::NewMessage(msg) => {
+                assert_eq!(msg.chat_id, 2);
+                assert_eq!(msg.content, "hello");
+                assert_eq!(msg.sender_name.as_deref(), Some("Cae Chen"));
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(timeout(Duration::from_millis(100), daisy.next())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_message_to_unknown_chat_should_404() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let input = CreateMessage {
+            content: "hello".to_string(),
+            files: vec![],
+        };
+        let ret = send_message_handler(Extension(user), State(state), Path(100), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_should_stream_files_to_storage() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let ret = upload_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            multipart(HELLO_UPLOAD).await?,
+        )
+        .await?
+        .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let files: Vec<String> = serde_json::from_slice(&body)?;
+
+        let file = ChatFile::new(1, "hello.txt", b"hello");
+        assert_eq!(files, [file.url()]);
+        assert!(state.storage.exists(&file.key()).await?);
+
+        let path = file.key().split_once('/').unwrap().1.to_string();
+        let ret = file_handler(
+            Extension(user),
+            State(state),
+            Path((1, path)),
+            Query(FileQuery { size: None }),
+            Method::GET,
+            HeaderMap::new(),
+        )
+        .await?;
+        assert_eq!(ret.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(
+            ret.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"hello.txt\"; filename*=UTF-8''hello.txt"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_or_too_large_upload_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let truncated =
+            "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nhel";
+        let ret = upload_handler(Extension(user), State(state), multipart(truncated).await?)
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+
+        let mut multipart = multipart(HELLO_UPLOAD).await?;
+        let mut field = multipart.next_field().await?.expect("field should exist");
+        let tmp = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let ret = write_field(&mut field, &tmp, 4).await;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+        let _ = std::fs::remove_file(tmp);
+        Ok(())
+    }
+}
@@ -1,13 +1,53 @@
 mod auth;
 mod chat;
+mod command;
+mod file;
+mod incoming_webhook;
 mod messages;
+mod presence;
+mod token;
+mod upload;
+mod webhook;
+mod workspace;
 
 use axum::response::IntoResponse;
+use chat_core::{Scope, User};
+
+use crate::AppError;
 
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use command::*;
+pub(crate) use file::*;
+pub(crate) use incoming_webhook::*;
 pub(crate) use messages::*;
+pub(crate) use presence::*;
+pub(crate) use token::*;
+pub(crate) use upload::*;
+pub(crate) use webhook::*;
+pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
     "index"
 }
+
+/// Fail unless `user` may act within `scope`.
+pub(crate) fn require_scope(user: &User, scope: Scope) -> Result<(), AppError> {
+    if user.has_scope(scope) {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden(format!(
+            "token lacks the {scope:?} scope"
+        )))
+    }
+}
+
+/// Fail if `user` is authenticated with an API token rather than signed in.
+pub(crate) fn require_session(user: &User) -> Result<(), AppError> {
+    match user.scopes {
+        None => Ok(()),
+        Some(_) => Err(AppError::Forbidden(
+            "not available to api tokens".to_string(),
+        )),
+    }
+}
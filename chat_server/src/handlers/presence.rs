@@ -0,0 +1,25 @@
+use crate::handlers::require_scope;
+use crate::models::PresenceRepo;
+use crate::{AppError, AppState};
+use axum::{extract::State, response::IntoResponse, Extension, Json};
+use chat_core::{Scope, User};
+
+#[utoipa::path(
+    get,
+    path = "/api/presence",
+    responses(
+        (status = 200, description = "Presence of the workspace users", body = Vec<Presence>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "presence",
+)]
+pub(crate) async fn list_presence_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let presences = PresenceRepo::fetch_all(user.ws_id as _, &state.pool).await?;
+    Ok(Json(presences))
+}
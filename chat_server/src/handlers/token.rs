@@ -0,0 +1,189 @@
+use crate::handlers::require_session;
+use crate::models::{ApiTokenRepo, CreateApiToken, CreateBot, UserRepo};
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+#[utoipa::path(
+    get,
+    path = "/api/bots",
+    responses(
+        (status = 200, description = "Bots of the workspace", body = Vec<User>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "token",
+)]
+pub(crate) async fn list_bot_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_session(&user)?;
+    let bots = UserRepo::fetch_bots(user.ws_id as _, &state.pool).await?;
+    Ok(Json(bots))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/bots",
+    responses(
+        (status = 201, description = "Bot created", body = User),
+        (status = 400, description = "Invalid bot", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "token",
+)]
+pub(crate) async fn create_bot_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateBot>,
+) -> Result<impl IntoResponse, AppError> {
+    require_session(&user)?;
+    let bot = UserRepo::create_bot(&input.name, user.ws_id as _, user.id as _, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tokens",
+    responses(
+        (status = 200, description = "API tokens created by the user", body = Vec<ApiToken>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "token",
+)]
+pub(crate) async fn list_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_session(&user)?;
+    let tokens = ApiTokenRepo::fetch_all(user.id as _, &state.pool).await?;
+    Ok(Json(tokens))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/tokens",
+    responses(
+        (status = 201, description = "API token created, along with the token itself", body = ApiToken),
+        (status = 400, description = "Invalid API token", body = ErrorOutput),
+        (status = 404, description = "Bot not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "token",
+)]
+pub(crate) async fn create_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateApiToken>,
+) -> Result<impl IntoResponse, AppError> {
+    require_session(&user)?;
+    let token = ApiTokenRepo::create(input, &user, &state.pool).await?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/tokens/{id}",
+    params(
+        ("id" = u64, Path, description = "API token id")
+    ),
+    responses(
+        (status = 204, description = "API token revoked"),
+        (status = 404, description = "API token not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "token",
+)]
+pub(crate) async fn delete_api_token_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_session(&user)?;
+    if ApiTokenRepo::delete(id, user.id as _, &state.pool).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("api token id {id}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::{list_chat_handler, send_message_handler};
+    use crate::models::{ApiToken, CreateMessage};
+    use anyhow::Result;
+    use chat_core::{middlewares::TokenVerify, Scope};
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn bot_token_should_be_limited_to_its_scopes() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let input = CreateBot {
+            name: "deploy".to_string(),
+        };
+        let ret = create_bot_handler(Extension(user.clone()), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let bot: User = serde_json::from_slice(&body)?;
+
+        let input = CreateApiToken {
+            name: "deploy".to_string(),
+            scopes: vec![Scope::PostMessages],
+            user_id: Some(bot.id),
+        };
+        let ret = create_api_token_handler(Extension(user), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let token: ApiToken = serde_json::from_slice(&body)?;
+        let token = token.token.expect("token should be returned");
+
+        let bot = state.verify(&token).await?;
+        let input = CreateMessage {
+            content: "deployed".to_string(),
+            files: vec![],
+        };
+        let ret = send_message_handler(
+            Extension(bot.clone()),
+            State(state.clone()),
+            Path(1),
+            Json(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let ret = list_chat_handler(Extension(bot.clone()), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        // tokens can't issue more tokens
+        let ret = list_api_token_handler(Extension(bot), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        assert!(state.verify("chat_invalid").await.is_err());
+        Ok(())
+    }
+}
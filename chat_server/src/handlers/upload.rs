@@ -0,0 +1,519 @@
+//! Resumable uploads, a simplified tus flow.
+//!
+//! An upload is created with the file's name and size, then its content is sent in chunks, each
+//! `PATCH` carrying the offset it starts at. A chunk cut short is kept up to what was received,
+//! so the client resumes from the offset the session reports. Once all of it is received, the
+//! upload is completed into a regular chat file. An upload not advanced for `uploads.ttl_secs`
+//! expires and is purged by the file collector. Until then its size counts towards the
+//! workspace's quota.
+//!
+//! The content received so far is kept on the local disk of the replica the upload was created
+//! on, whatever the storage, so with several replicas `/api/uploads/{id}` requests must be
+//! routed to the same one, e.g. by a load balancer hashing the path.
+
+use std::io::{ErrorKind, SeekFrom};
+use std::path::PathBuf;
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{header::LOCATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Scope, User};
+use futures::StreamExt;
+use sha1::{Digest, Sha1};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::handlers::{require_scope, store_file};
+use crate::models::{ChatFile, CreateUpload, UploadSession, UploadSessionRepo, WorkspaceRepo};
+use crate::{AppError, AppState};
+
+/// Offset of the chunk in a request, and of the next one expected in a response.
+pub const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+#[utoipa::path(
+    post,
+    path = "/api/uploads",
+    responses(
+        (status = 201, description = "Upload created", body = UploadSession),
+        (status = 400, description = "Invalid upload", body = ErrorOutput),
+        (status = 413, description = "File larger than the max upload size, or over the workspace's quota", body = ErrorOutput),
+        (status = 415, description = "File type not allowed in the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn create_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateUpload>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
+    let ws_id = user.ws_id as u64;
+    let policy = WorkspaceRepo::settings(ws_id, &state.pool).await?.storage;
+    policy.check_name(&input.filename)?;
+    let max_size = policy.max_file_size(state.config.server.max_upload_size);
+    if input.size > max_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "files are limited to {max_size} bytes"
+        )));
+    }
+    // checked again once complete, the content may turn out to be stored already
+    let mut tx = state.pool.begin().await?;
+    if policy.quota_bytes.is_some() {
+        WorkspaceRepo::lock_storage(ws_id, &mut tx).await?;
+    }
+    let usage = WorkspaceRepo::storage_usage(ws_id, &mut *tx).await?;
+    policy.check_quota(usage.used(), input.size)?;
+
+    let ttl = state.config.uploads.ttl();
+    let session = UploadSessionRepo::create(input, &user, ttl, &mut *tx).await?;
+    tx.commit().await?;
+    let path = upload_path(&state, session.id);
+    fs::create_dir_all(path.parent().expect("upload path should have a parent")).await?;
+    fs::File::create(path).await?;
+
+    let location = format!("/api/uploads/{}", session.id);
+    Ok((StatusCode::CREATED, [(LOCATION, location)], Json(session)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id")
+    ),
+    responses(
+        (status = 200, description = "Upload, with the offset to resume from", body = UploadSession),
+        (status = 404, description = "Upload not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn get_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    let session = find_session(&state, &user, id).await?;
+    Ok(Json(session))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("upload-offset" = u64, Header, description = "Offset the chunk starts at")
+    ),
+    request_body(
+        content_type = "application/offset+octet-stream", content = Vec<u8>,
+    ),
+    responses(
+        (status = 204, description = "Chunk received, `upload-offset` is the next one's offset"),
+        (status = 400, description = "Missing offset or chunk cut short", body = ErrorOutput),
+        (status = 404, description = "Upload not found", body = ErrorOutput),
+        (status = 409, description = "Offset isn't the upload's", body = ErrorOutput),
+        (status = 413, description = "Chunk past the file's size", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn patch_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
+    // held until the chunk is recorded, a concurrent one at the same offset waits, then conflicts
+    let mut tx = state.pool.begin().await?;
+    let session = UploadSessionRepo::lock(id, user.id as _, &mut tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("upload id {id}")))?;
+    let offset: i64 = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| AppError::UploadError(format!("missing {UPLOAD_OFFSET_HEADER} header")))?;
+    if offset != session.offset {
+        return Err(AppError::UploadConflict(format!(
+            "upload is at offset {}",
+            session.offset
+        )));
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(upload_path(&state, id))
+        .await
+        .map_err(|e| content_error(e, id))?;
+    // drop whatever a failed chunk left past the recorded offset
+    file.set_len(offset as u64).await?;
+    file.seek(SeekFrom::End(0)).await?;
+
+    let remaining = session.size - offset;
+    let mut received = 0;
+    let mut stream = body.into_data_stream();
+    let mut ret = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                ret = Err(AppError::UploadError(format!("chunk cut short: {e}")));
+                break;
+            }
+        };
+        if received + chunk.len() as i64 > remaining {
+            return Err(AppError::PayloadTooLarge(format!(
+                "upload is {} bytes",
+                session.size
+            )));
+        }
+        file.write_all(&chunk).await?;
+        received += chunk.len() as i64;
+    }
+    file.sync_all().await?;
+
+    let ttl = state.config.uploads.ttl();
+    if !UploadSessionRepo::advance(id, offset, received, ttl, &mut *tx).await? {
+        return Err(AppError::UploadConflict(
+            "another chunk was received meanwhile".to_string(),
+        ));
+    }
+    tx.commit().await?;
+    ret?;
+    let next = (offset + received).to_string();
+    Ok((StatusCode::NO_CONTENT, [(UPLOAD_OFFSET_HEADER, next)]))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/uploads/{id}/complete",
+    params(
+        ("id" = String, Path, description = "Upload id")
+    ),
+    responses(
+        (status = 200, description = "Url of the uploaded file", body = String),
+        (status = 404, description = "Upload not found, or already completed", body = ErrorOutput),
+        (status = 409, description = "Upload isn't fully received", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn complete_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
+    // taken out for the transaction, so it no longer counts towards the quota and a concurrent
+    // completion waits for this one, then finds nothing
+    let mut tx = state.pool.begin().await?;
+    let Some(session) = UploadSessionRepo::take(id, user.id as _, &mut *tx).await? else {
+        return Err(AppError::NotFound(format!("upload id {id}")));
+    };
+    if session.offset != session.size {
+        return Err(AppError::UploadConflict(format!(
+            "{} of {} bytes received",
+            session.offset, session.size
+        )));
+    }
+
+    let path = upload_path(&state, id);
+    let mut file = fs::File::open(&path)
+        .await
+        .map_err(|e| content_error(e, id))?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    let hash = hex::encode(hasher.finalize());
+    let file = ChatFile::with_hash(session.ws_id as _, &session.filename, hash);
+    let meta = store_file(
+        &state,
+        &mut tx,
+        &file,
+        &session.filename,
+        session.size as _,
+        session.user_id as _,
+        &path,
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Json(meta.chat_file().url()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/uploads/{id}",
+    params(
+        ("id" = String, Path, description = "Upload id")
+    ),
+    responses(
+        (status = 204, description = "Upload cancelled, along with what was received"),
+        (status = 404, description = "Upload not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "message",
+)]
+pub(crate) async fn cancel_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::PostMessages)?;
+    if UploadSessionRepo::take(id, user.id as _, &state.pool)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::NotFound(format!("upload id {id}")));
+    }
+    remove_upload(&state, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn find_session(state: &AppState, user: &User, id: Uuid) -> Result<UploadSession, AppError> {
+    require_scope(user, Scope::PostMessages)?;
+    UploadSessionRepo::find(id, user.id as _, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("upload id {id}")))
+}
+
+/// Where the content received so far of uploads to this replica is kept.
+pub(crate) fn uploads_dir(state: &AppState) -> PathBuf {
+    state.config.server.base_dir.join("uploads")
+}
+
+fn upload_path(state: &AppState, id: Uuid) -> PathBuf {
+    uploads_dir(state).join(id.to_string())
+}
+
+/// Remove what was received of an upload, if it's on this replica.
+pub(crate) async fn remove_upload(state: &AppState, id: Uuid) -> Result<(), AppError> {
+    match fs::remove_file(upload_path(state, id)).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The content of an upload is missing if the request reached another replica than its own.
+fn content_error(e: std::io::Error, id: Uuid) -> AppError {
+    if e.kind() == ErrorKind::NotFound {
+        AppError::NotFound(format!("content of upload id {id} on this server"))
+    } else {
+        e.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+    use anyhow::Result;
+    use http_body_util::BodyExt;
+
+    async fn patch(
+        state: &AppState,
+        user: &User,
+        id: Uuid,
+        offset: i64,
+        data: &'static [u8],
+    ) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert(UPLOAD_OFFSET_HEADER, offset.into());
+        patch_upload_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(id),
+            headers,
+            Body::from(data),
+        )
+        .await
+        .into_response()
+        .status()
+    }
+
+    async fn create(
+        state: &AppState,
+        user: &User,
+        filename: &str,
+        size: u64,
+    ) -> Result<UploadSession, AppError> {
+        let input = CreateUpload {
+            filename: filename.to_string(),
+            size,
+        };
+        let ret = create_upload_handler(Extension(user.clone()), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await.unwrap().to_bytes();
+        Ok(serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn resumable_upload_should_become_a_chat_file() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+
+        let input = CreateUpload {
+            filename: "hello.txt".to_string(),
+            size: 11,
+        };
+        let ret = create_upload_handler(Extension(user.clone()), State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let session: UploadSession = serde_json::from_slice(&body)?;
+        let id = session.id;
+
+        assert_eq!(
+            patch(&state, &user, id, 0, b"hello").await,
+            StatusCode::NO_CONTENT
+        );
+        // a retried chunk is refused, the client resumes from the reported offset
+        assert_eq!(
+            patch(&state, &user, id, 0, b"hello").await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            patch(&state, &user, id, 5, b" world!").await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        let ret = complete_upload_handler(Extension(user.clone()), State(state.clone()), Path(id))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            patch(&state, &user, id, 5, b" world").await,
+            StatusCode::NO_CONTENT
+        );
+
+        let ret = complete_upload_handler(Extension(user.clone()), State(state.clone()), Path(id))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let url: String = serde_json::from_slice(&body)?;
+        let file = ChatFile::new(1, "hello.txt", b"hello world");
+        assert_eq!(url, file.url());
+        assert!(state.storage.exists(&file.key()).await?);
+
+        let ret = get_upload_handler(Extension(user), State(state), Path(id))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_chunks_should_not_interleave() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let session = create(&state, &user, "race.bin", 10).await?;
+
+        // each chunk is sent in two parts, the other one's parts written in between
+        let chunk = |part: &'static [u8], delays: [u64; 2]| {
+            let parts = futures::stream::iter(delays).then(move |delay| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                Ok::<_, std::io::Error>(part)
+            });
+            let mut headers = HeaderMap::new();
+            headers.insert(UPLOAD_OFFSET_HEADER, 0.into());
+            patch_upload_handler(
+                Extension(user.clone()),
+                State(state.clone()),
+                Path(session.id),
+                headers,
+                Body::from_stream(parts),
+            )
+        };
+        let (a, b) = tokio::join!(chunk(b"aaaaa", [10, 100]), chunk(b"bbbbb", [50, 10]));
+        let mut statuses = [a.into_response().status(), b.into_response().status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::NO_CONTENT, StatusCode::CONFLICT]);
+
+        let ret = complete_upload_handler(Extension(user), State(state), Path(session.id))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let url: String = serde_json::from_slice(&body)?;
+        let sent =
+            [b"aaaaaaaaaa", b"bbbbbbbbbb"].map(|data| ChatFile::new(1, "race.bin", data).url());
+        assert!(sent.contains(&url));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_uploads_should_count_towards_the_quota() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let usage = WorkspaceRepo::storage_usage(1, &state.pool).await?;
+        let mut settings = WorkspaceRepo::settings(1, &state.pool).await?;
+        settings.storage.quota_bytes = Some(usage.used() + 20);
+        WorkspaceRepo::update_settings(1, settings, &state.pool).await?;
+
+        let session = create(&state, &user, "a.bin", 15).await?;
+        let ret = create(&state, &user, "b.bin", 10).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+
+        let path = upload_path(&state, session.id);
+        assert!(path.exists());
+        let ret = cancel_upload_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(session.id),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(!path.exists());
+        create(&state, &user, "b.bin", 10).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_completions_should_not_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let session = create(&state, &user, "race.txt", 4).await?;
+        let id = session.id;
+        assert_eq!(
+            patch(&state, &user, id, 0, b"race").await,
+            StatusCode::NO_CONTENT
+        );
+
+        let complete = || async {
+            complete_upload_handler(Extension(user.clone()), State(state.clone()), Path(id))
+                .await
+                .into_response()
+                .status()
+        };
+        let (a, b) = tokio::join!(complete(), complete());
+        let mut statuses = [a, b];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::NOT_FOUND]);
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use crate::handlers::require_scope;
+use crate::models::{CreateWebhook, WebhookRepo};
+use crate::{AppError, AppState};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::{Scope, User};
+
+/// Deliveries listed per webhook.
+const DELIVERY_LOG_LIMIT: i64 = 100;
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks",
+    responses(
+        (status = 200, description = "Outgoing webhooks of the workspace", body = Vec<Webhook>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn list_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    let webhooks = WebhookRepo::fetch_all(user.ws_id as _, &state.pool).await?;
+    Ok(Json(webhooks))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/webhooks",
+    responses(
+        (status = 201, description = "Webhook created, along with its secret", body = Webhook),
+        (status = 400, description = "Invalid webhook", body = ErrorOutput),
+        (status = 403, description = "Only the workspace owner can subscribe to every chat", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn create_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateWebhook>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    let webhook = WebhookRepo::create(
+        input,
+        user.ws_id as _,
+        user.id as _,
+        &state.config.outbound,
+        &state.pool,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{id}",
+    params(
+        ("id" = u64, Path, description = "Webhook id")
+    ),
+    responses(
+        (status = 204, description = "Webhook deleted"),
+        (status = 404, description = "Webhook not found", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn delete_webhook_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    if WebhookRepo::delete(id, user.ws_id as _, user.id as _, &state.pool).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("webhook id {id}")))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks/{id}/deliveries",
+    params(
+        ("id" = u64, Path, description = "Webhook id")
+    ),
+    responses(
+        (status = 200, description = "Latest deliveries of the webhook", body = Vec<WebhookDelivery>),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "webhook",
+)]
+pub(crate) async fn list_webhook_delivery_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    let deliveries = WebhookRepo::fetch_deliveries(
+        id,
+        user.ws_id as _,
+        user.id as _,
+        DELIVERY_LOG_LIMIT,
+        &state.pool,
+    )
+    .await?;
+    Ok(Json(deliveries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{Webhook, WebhookDelivery, WorkspaceRepo};
+    use anyhow::Result;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn webhook_secret_should_only_be_returned_on_create() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let input = CreateWebhook::new("https://ci.example.com/hook", &[], &[1]);
+
+        let ret =
+            create_webhook_handler(Extension(user.clone()), State(state.clone()), Json(input))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let webhook: Webhook = serde_json::from_slice(&body)?;
+        assert!(webhook.secret.is_some());
+
+        let ret = list_webhook_handler(Extension(user.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert!(!String::from_utf8(body.to_vec())?.contains("secret"));
+
+        let ret = delete_webhook_handler(Extension(user), State(state), Path(webhook.id as _))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_creator_should_manage_a_webhook() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let alice = state.test_user("alice@cae.org").await?;
+        let input = CreateWebhook::new("https://ci.example.com/hook", &[], &[1]);
+        let webhook =
+            WebhookRepo::create(input, 1, user.id as _, &state.config.outbound, &state.pool)
+                .await?;
+        sqlx::query("INSERT INTO webhook_deliveries (webhook_id, event, payload) VALUES ($1, 'NewMessage', '{}')")
+            .bind(webhook.id)
+            .execute(&state.pool)
+            .await?;
+        let deliveries = |user: &User| {
+            list_webhook_delivery_handler(
+                Extension(user.clone()),
+                State(state.clone()),
+                Path(webhook.id as _),
+            )
+        };
+
+        let ret = deliveries(&alice).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(body, "[]");
+        let ret = delete_webhook_handler(
+            Extension(alice.clone()),
+            State(state.clone()),
+            Path(webhook.id as _),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        // the workspace owner manages every webhook
+        WorkspaceRepo::update_owner(1, alice.id as _, &state.pool).await?;
+        let ret = deliveries(&alice).await?.into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let found: Vec<WebhookDelivery> = serde_json::from_slice(&body)?;
+        assert_eq!(found.len(), 1);
+        let ret = delete_webhook_handler(Extension(alice), State(state), Path(webhook.id as _))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+}
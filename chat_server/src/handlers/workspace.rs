@@ -0,0 +1,216 @@
+use crate::handlers::require_scope;
+use crate::models::{WorkspaceRepo, WorkspaceSettings};
+use crate::{AppError, AppState};
+use axum::{extract::State, response::IntoResponse, Extension, Json};
+use chat_core::{Scope, User};
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/settings",
+    responses(
+        (status = 200, description = "Settings of the workspace", body = WorkspaceSettings),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "workspace",
+)]
+pub(crate) async fn get_workspace_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let settings = WorkspaceRepo::settings(user.ws_id as _, &state.pool).await?;
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/workspace/settings",
+    responses(
+        (status = 200, description = "Settings updated", body = WorkspaceSettings),
+        (status = 400, description = "Invalid settings", body = ErrorOutput),
+        (status = 403, description = "Not the owner of the workspace", body = ErrorOutput),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "workspace",
+)]
+pub(crate) async fn update_workspace_settings_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<WorkspaceSettings>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ManageChats)?;
+    let ws_id = user.ws_id as u64;
+    let Some(ws) = WorkspaceRepo::find_by_id(ws_id, &state.pool).await? else {
+        return Err(AppError::NotFound(format!("workspace id {ws_id}")));
+    };
+    if ws.owner_id != user.id {
+        return Err(AppError::Forbidden(
+            "only the workspace owner can change its settings".to_string(),
+        ));
+    }
+    let settings = WorkspaceRepo::update_settings(ws_id, input, &state.pool).await?;
+    Ok(Json(settings))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/workspace/usage",
+    responses(
+        (status = 200, description = "Storage the workspace's files take", body = StorageUsage),
+    ),
+    security(
+        ("token" = [])
+    ),
+    tag = "workspace",
+)]
+pub(crate) async fn get_storage_usage_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    require_scope(&user, Scope::ReadMessages)?;
+    let ws_id = user.ws_id as u64;
+    let mut usage = WorkspaceRepo::storage_usage(ws_id, &state.pool).await?;
+    usage.quota_bytes = WorkspaceRepo::settings(ws_id, &state.pool)
+        .await?
+        .storage
+        .quota_bytes;
+    Ok(Json(usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::messages::tests::{multipart, upload_body};
+    use crate::handlers::upload_handler;
+    use crate::models::{StoragePolicy, StorageUsage};
+    use anyhow::Result;
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt;
+    use uuid::Uuid;
+
+    async fn upload(state: &AppState, user: &User, files: &[(&str, &str)]) -> Result<(), AppError> {
+        let form = multipart(&upload_body(files))
+            .await
+            .expect("body should be multipart");
+        upload_handler(Extension(user.clone()), State(state.clone()), form).await?;
+        Ok(())
+    }
+
+    async fn usage(state: &AppState, user: &User) -> Result<StorageUsage> {
+        let ret = get_storage_usage_handler(Extension(user.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_should_change_settings() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.test_user("cae@cae.org").await?;
+        let alice = state.test_user("alice@cae.org").await?;
+        WorkspaceRepo::update_owner(1, owner.id as _, &state.pool).await?;
+
+        let settings = WorkspaceSettings {
+            storage: StoragePolicy {
+                quota_bytes: Some(1024),
+                ..Default::default()
+            },
+        };
+        let ret = update_workspace_settings_handler(
+            Extension(alice),
+            State(state.clone()),
+            Json(settings.clone()),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+
+        let ret = update_workspace_settings_handler(
+            Extension(owner),
+            State(state.clone()),
+            Json(settings.clone()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(WorkspaceRepo::settings(1, &state.pool).await?, settings);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uploads_should_follow_the_workspace_policy() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let alice = state.test_user("alice@cae.org").await?;
+        let before = usage(&state, &user).await?;
+        let settings = WorkspaceSettings {
+            storage: StoragePolicy {
+                quota_bytes: Some(before.bytes as u64 + 20),
+                max_file_size: Some(16),
+                allowed_mime_types: vec!["text/*".to_string()],
+                blocked_extensions: vec!["csv".to_string()],
+            },
+        };
+        WorkspaceRepo::update_settings(1, settings, &state.pool).await?;
+
+        let ret = upload(&state, &user, &[("run.exe", "MZ")]).await;
+        assert!(matches!(ret, Err(AppError::UnsupportedFileType(_))));
+        let ret = upload(&state, &user, &[("data.csv", "a,b")]).await;
+        assert!(matches!(ret, Err(AppError::UnsupportedFileType(_))));
+        // the name is allowed, the content isn't
+        let ret = upload(&state, &user, &[("notes.txt", "MZ not a text")]).await;
+        assert!(matches!(ret, Err(AppError::UnsupportedFileType(_))));
+        let ret = upload(&state, &user, &[("long.txt", "seventeen bytes!!")]).await;
+        assert!(matches!(ret, Err(AppError::PayloadTooLarge(_))));
+
+        // 15 of the 20 bytes, uploading the same content again takes none
+        let content = Uuid::now_v7().simple().to_string();
+        let content = &content[..15];
+        upload(&state, &user, &[("a.txt", content)]).await?;
+        upload(&state, &alice, &[("b.txt", content)]).await?;
+        let ret = upload(&state, &user, &[("c.txt", "six b!")]).await;
+        assert!(matches!(ret, Err(AppError::QuotaExceeded(_))));
+
+        let after = usage(&state, &user).await?;
+        assert_eq!(after.quota_bytes, Some(before.bytes as u64 + 20));
+        assert_eq!(after.files, before.files + 1);
+        assert_eq!(after.bytes, before.bytes + 15);
+        assert_eq!(after.uploaded_bytes, before.uploaded_bytes + 30);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_should_not_exceed_the_quota() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let before = usage(&state, &user).await?;
+        let settings = WorkspaceSettings {
+            storage: StoragePolicy {
+                quota_bytes: Some(before.bytes as u64 + 20),
+                ..Default::default()
+            },
+        };
+        WorkspaceRepo::update_settings(1, settings, &state.pool).await?;
+
+        // 15 random bytes each, only one fits
+        let (a, b) = (
+            Uuid::now_v7().simple().to_string(),
+            Uuid::now_v7().simple().to_string(),
+        );
+        let (a, b) = ([("a.txt", &a[17..])], [("b.txt", &b[17..])]);
+        let (a, b) = tokio::join!(upload(&state, &user, &a), upload(&state, &user, &b));
+        let exceeded = match (a, b) {
+            (Ok(()), ret) | (ret, Ok(())) => ret,
+            (Err(e), Err(_)) => panic!("one upload should succeed: {e}"),
+        };
+        assert!(matches!(exceeded, Err(AppError::QuotaExceeded(_))));
+
+        let after = usage(&state, &user).await?;
+        assert_eq!(after.bytes, before.bytes + 15);
+        Ok(())
+    }
+}
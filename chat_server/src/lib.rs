@@ -1,8 +1,17 @@
+mod commands;
 mod config;
 mod error;
+mod file_url;
+mod gc;
 mod handlers;
 mod models;
 mod openapi;
+mod outbound;
+mod publisher;
+mod rate_limit;
+mod storage;
+mod thumbnail;
+mod webhook;
 
 use anyhow::Context;
 
@@ -14,19 +23,30 @@ use std::{ops::Deref, sync::Arc};
 
 use axum::http::StatusCode;
 use axum::{
+    extract::DefaultBodyLimit,
     middleware::from_fn_with_state,
-    routing::{get, patch, post},
+    routing::{delete, get, patch, post},
     Router,
 };
 use chat_core::middlewares::{set_layer, verify_token, TokenVerify};
 use chat_core::{DecodingKey, EncodingKey, User};
-pub use config::AppConfig;
+pub use config::{
+    AppConfig, FileGcConfig, FileUrlConfig, IncomingWebhookConfig, OutboundConfig, PublisherConfig,
+    S3Config, ServerConfig, SigningKey, StorageConfig, ThumbnailConfig,
+};
 pub use error::AppError;
 pub use error::ErrorOutput;
+pub use gc::{FileCollector, GcReport};
 pub use models::MessageRepo;
+use models::{ApiTokenRepo, API_TOKEN_PREFIX};
 pub use openapi::ApiDoc;
+pub use publisher::EventPublisher;
+use rate_limit::RateLimiter;
 use sqlx::pool::PoolOptions;
 use sqlx::postgres::PgConnectOptions;
+pub use storage::{FileStorage, Storage, StoredObject};
+use tracing::warn;
+pub use webhook::WebhookDispatcher;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -40,12 +60,24 @@ pub struct AppStateInner {
     pub(crate) ek: EncodingKey,
     pub(crate) pool: PgPool,
     pub(crate) message: MessageRepo,
+    pub(crate) publisher: EventPublisher,
+    pub(crate) storage: FileStorage,
+    /// Messages posted through each incoming webhook.
+    pub(crate) incoming_limiter: RateLimiter,
+    /// Client calling external slash commands.
+    pub(crate) http: reqwest::Client,
 }
 
 impl TokenVerify for AppState {
     type Error = AppError;
 
-    fn verify(&self, token: &str) -> Result<User, Self::Error> {
+    /// Accept either a JWT issued on sign in or an API token.
+    async fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        if token.starts_with(API_TOKEN_PREFIX) {
+            return ApiTokenRepo::verify(token, &self.pool)
+                .await?
+                .ok_or_else(|| AppError::Forbidden("invalid api token".to_string()));
+        }
         self.dk.verify(token).map_err(AppError::AnyhowError)
     }
 }
@@ -61,9 +93,61 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/chats/:id/messages", get(list_message_handler))
-        .route("/upload", post(upload_handler))
+        .route("/mutes", get(list_muted_chat_handler))
+        .route("/presence", get(list_presence_handler))
+        .route(
+            "/workspace/settings",
+            get(get_workspace_settings_handler).put(update_workspace_settings_handler),
+        )
+        .route("/workspace/usage", get(get_storage_usage_handler))
+        .route("/stream-tickets", post(create_stream_ticket_handler))
+        .route(
+            "/webhooks",
+            get(list_webhook_handler).post(create_webhook_handler),
+        )
+        .route(
+            "/chats/:id/incoming-webhooks",
+            get(list_incoming_webhook_handler).post(create_incoming_webhook_handler),
+        )
+        .route(
+            "/chats/:id/incoming-webhooks/:hook_id",
+            delete(delete_incoming_webhook_handler),
+        )
+        .route("/webhooks/:id", delete(delete_webhook_handler))
+        .route(
+            "/webhooks/:id/deliveries",
+            get(list_webhook_delivery_handler),
+        )
+        .route("/bots", get(list_bot_handler).post(create_bot_handler))
+        .route(
+            "/tokens",
+            get(list_api_token_handler).post(create_api_token_handler),
+        )
+        .route("/tokens/:id", delete(delete_api_token_handler))
+        .route(
+            "/commands",
+            get(list_command_handler).post(create_command_handler),
+        )
+        .route("/commands/:id", delete(delete_command_handler))
+        // files are limited one by one as they're streamed
+        .route(
+            "/upload",
+            post(upload_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/uploads", post(create_upload_handler))
+        .route(
+            "/uploads/:id",
+            get(get_upload_handler)
+                .patch(patch_upload_handler)
+                .delete(cancel_upload_handler)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route("/uploads/:id/complete", post(complete_upload_handler))
         .route("/files/:ws_id/*path", get(file_handler))
+        .route("/signed-urls", post(sign_file_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+        .route("/signed/files/:ws_id/*path", get(signed_file_handler))
+        .route("/hooks/:token", post(post_incoming_webhook_handler))
         .route("/signin", post(signin_handler))
         .route("/signup", post(signup_handler));
 
@@ -90,7 +174,11 @@ impl AppState {
         let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
 
         let opts: PgConnectOptions = config.server.db_url.parse()?;
-        let opts = opts.log_statements(log::LevelFilter::Debug);
+        let mut opts = opts.log_statements(log::LevelFilter::Debug);
+        if config.publisher != PublisherConfig::Trigger {
+            // events are published after commit, keep the triggers from notifying them twice
+            opts = opts.options([("chat.notify_trigger", "off")]);
+        }
 
         let pool = PoolOptions::new();
         let pool = pool.acquire_timeout(Duration::from_secs(5));
@@ -100,7 +188,10 @@ impl AppState {
             .await
             .context("connect to db failed")?;
 
-        let base_dir = config.server.base_dir.clone();
+        let storage = FileStorage::try_new(&config.storage, &config.server)?;
+        let publisher = EventPublisher::try_new(&config.publisher, &pool).await?;
+        let incoming_limiter = config.incoming_webhooks.limiter();
+        let http = config.outbound.client();
 
         Ok(Self {
             inner: Arc::new(AppStateInner {
@@ -108,27 +199,75 @@ impl AppState {
                 ek,
                 dk,
                 pool,
-                message: MessageRepo::new(base_dir),
+                message: MessageRepo,
+                publisher,
+                storage,
+                incoming_limiter,
+                http,
             }),
         })
     }
+
+    /// A dispatcher delivering this server's events to outgoing webhooks.
+    ///
+    /// Events are read from the postgres channel, so only the trigger and postgres publishers
+    /// feed it.
+    pub fn webhook_dispatcher(&self) -> WebhookDispatcher {
+        if !matches!(
+            self.config.publisher,
+            PublisherConfig::Trigger | PublisherConfig::Postgres
+        ) {
+            warn!("outgoing webhooks only see events published through postgres");
+        }
+        WebhookDispatcher::new(
+            &self.config.server.db_url,
+            self.pool.clone(),
+            self.config.outbound.clone(),
+        )
+    }
+
+    /// A collector of the files no message has, run as configured by `file_gc`.
+    pub fn file_collector(&self) -> FileCollector {
+        FileCollector::new(self.clone())
+    }
 }
 
 #[cfg(feature = "test-util")]
 mod test_util {
     use super::*;
+    use crate::models::UserRepo;
     use sqlx::{Executor, PgPool};
     use sqlx_db_tester::TestPg;
+    use uuid::Uuid;
 
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
-            let config = AppConfig::load()?;
+            Self::new_for_test_with_publisher(EventPublisher::Trigger).await
+        }
+
+        /// The user of the test data with `email`, e.g. `cae@cae.org`.
+        pub async fn test_user(&self, email: &str) -> Result<User, AppError> {
+            let user = UserRepo::find_by_email(email, &self.pool).await?;
+            Ok(user.expect("user should exist"))
+        }
+
+        /// Like [`AppState::new_for_test`], publishing events through `publisher`.
+        pub async fn new_for_test_with_publisher(
+            publisher: EventPublisher,
+        ) -> Result<(TestPg, Self), AppError> {
+            let mut config = AppConfig::load()?;
+            // test receivers listen on loopback
+            config.outbound.allow_private_networks = true;
+            // the file collector walks what each test stored
+            config.server.base_dir = config.server.base_dir.join(Uuid::now_v7().to_string());
             let dk = DecodingKey::load(&config.auth.pk).context("load pk failed")?;
             let ek = EncodingKey::load(&config.auth.sk).context("load sk failed")?;
             let server_url = config.server.db_url.rsplit_once('/').unwrap().0;
             let (tdb, pool) = get_test_pool(Some(server_url)).await;
 
-            let base_dir = config.server.base_dir.clone();
+            let storage = FileStorage::try_new(&config.storage, &config.server)?;
+            let incoming_limiter = config.incoming_webhooks.limiter();
+            let http = config.outbound.client();
 
             let state = Self {
                 inner: Arc::new(AppStateInner {
@@ -136,7 +275,11 @@ mod test_util {
                     ek,
                     dk,
                     pool,
-                    message: MessageRepo::new(base_dir),
+                    message: MessageRepo,
+                    publisher,
+                    storage,
+                    incoming_limiter,
+                    http,
                 }),
             };
             Ok((tdb, state))
@@ -15,6 +15,8 @@ async fn main() -> Result<()> {
     let addr = format!("0.0.0.0:{}", config.server.port);
 
     let state = AppState::try_new(config).await?;
+    tokio::spawn(state.webhook_dispatcher().run());
+    tokio::spawn(state.file_collector().run());
 
     let app = get_router(state).await?;
     let listener = TcpListener::bind(&addr).await?;
@@ -0,0 +1,209 @@
+use crate::models::{hash_token, random_secret};
+use crate::AppError;
+use chat_core::{Scope, User};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+
+/// Prefix of API tokens, telling them apart from JWTs.
+pub const API_TOKEN_PREFIX: &str = "chat_";
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ApiToken {
+    pub id: i64,
+    /// The user or bot the token authenticates as.
+    pub user_id: i64,
+    pub name: String,
+    /// Only returned on creation.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub scopes: Vec<Scope>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// A bot created by the caller, the caller themselves if not set.
+    #[serde(default)]
+    pub user_id: Option<i64>,
+}
+
+pub struct ApiTokenRepo;
+
+impl ApiTokenRepo {
+    /// Issue a token on behalf of `user`, for them or a bot they created.
+    pub async fn create(
+        input: CreateApiToken,
+        user: &User,
+        pool: &PgPool,
+    ) -> Result<ApiToken, AppError> {
+        let name = input.name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(AppError::CreateApiTokenError(
+                "name must be 1 to 64 characters".to_string(),
+            ));
+        }
+        let mut scopes = input.scopes;
+        scopes.sort_by_key(|scope| *scope as u8);
+        scopes.dedup();
+        if scopes.is_empty() {
+            return Err(AppError::CreateApiTokenError(
+                "at least one scope is required".to_string(),
+            ));
+        }
+
+        let user_id = input.user_id.unwrap_or(user.id);
+        if user_id != user.id {
+            let bot: Option<(i64,)> =
+                sqlx::query_as("SELECT id FROM users WHERE id = $1 AND is_bot AND created_by = $2")
+                    .bind(user_id)
+                    .bind(user.id)
+                    .fetch_optional(pool)
+                    .await?;
+            if bot.is_none() {
+                return Err(AppError::NotFound(format!("bot id {user_id}")));
+            }
+        }
+
+        let token = format!("{API_TOKEN_PREFIX}{}", random_secret());
+        let mut api_token: ApiToken = sqlx::query_as(
+            r#"
+        INSERT INTO api_tokens (user_id, name, token_hash, scopes, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, user_id, name, scopes, created_by, created_at, last_used_at
+        "#,
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(hash_token(&token))
+        .bind(scopes)
+        .bind(user.id)
+        .fetch_one(pool)
+        .await?;
+
+        api_token.token = Some(token);
+        Ok(api_token)
+    }
+
+    /// Tokens `user_id` created, for them or their bots.
+    pub async fn fetch_all(user_id: u64, pool: &PgPool) -> Result<Vec<ApiToken>, AppError> {
+        let tokens = sqlx::query_as(
+            r#"
+        SELECT id, user_id, name, scopes, created_by, created_at, last_used_at
+        FROM api_tokens
+        WHERE created_by = $1
+        ORDER BY id
+        "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Revoke a token `user_id` created.
+    pub async fn delete(id: u64, user_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM api_tokens WHERE id = $1 AND created_by = $2")
+            .bind(id as i64)
+            .bind(user_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// The user `token` authenticates as, limited to the token's scopes.
+    pub async fn verify(token: &str, pool: &PgPool) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+        WITH token AS (
+          UPDATE api_tokens SET last_used_at = now()
+          WHERE token_hash = $1
+          RETURNING user_id, scopes
+        )
+        SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at, token.scopes
+        FROM token JOIN users u ON u.id = token.user_id
+        "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await?;
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserRepo;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn api_token_should_verify_with_its_scopes_until_revoked() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let bot = UserRepo::create_bot("deploy", 1, user.id as _, &state.pool).await?;
+
+        let input = CreateApiToken {
+            name: "deploy".to_string(),
+            scopes: vec![
+                Scope::PostMessages,
+                Scope::ReadMessages,
+                Scope::PostMessages,
+            ],
+            user_id: Some(bot.id),
+        };
+        let api_token = ApiTokenRepo::create(input, &user, &state.pool).await?;
+        assert_eq!(api_token.scopes, [Scope::ReadMessages, Scope::PostMessages]);
+        let token = api_token.token.clone().expect("token should be returned");
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+
+        let verified = ApiTokenRepo::verify(&token, &state.pool)
+            .await?
+            .expect("token should be valid");
+        assert_eq!(verified.id, bot.id);
+        assert!(verified.has_scope(Scope::PostMessages));
+        assert!(!verified.has_scope(Scope::ManageChats));
+
+        let tokens = ApiTokenRepo::fetch_all(user.id as _, &state.pool).await?;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].token, None);
+        assert!(tokens[0].last_used_at.is_some());
+
+        assert!(ApiTokenRepo::delete(api_token.id as _, user.id as _, &state.pool).await?);
+        assert!(ApiTokenRepo::verify(&token, &state.pool).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_token_should_only_be_issued_for_own_bots() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let alice = state.test_user("alice@cae.org").await?;
+        let bot = UserRepo::create_bot("deploy", 1, 1, &state.pool).await?;
+
+        for user_id in [bot.id, 1] {
+            let input = CreateApiToken {
+                name: "deploy".to_string(),
+                scopes: vec![Scope::ReadMessages],
+                user_id: Some(user_id),
+            };
+            let ret = ApiTokenRepo::create(input, &alice, &state.pool).await;
+            assert!(matches!(ret, Err(AppError::NotFound(_))));
+        }
+
+        let input = CreateApiToken {
+            name: "deploy".to_string(),
+            scopes: vec![],
+            user_id: None,
+        };
+        let ret = ApiTokenRepo::create(input, &alice, &state.pool).await;
+        assert!(matches!(ret, Err(AppError::CreateApiTokenError(_))));
+        Ok(())
+    }
+}
@@ -65,7 +65,7 @@ impl ChatRepo {
             r#"
             INSERT INTO chats (ws_id, name, type, members)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            RETURNING id, ws_id, name, type, members, created_at, topic
             "#,
         )
         .bind(ws_id as i64)
@@ -81,7 +81,7 @@ impl ChatRepo {
     pub async fn fetch_all(ws_id: u64, pool: &PgPool) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at, topic
             FROM chats
             WHERE ws_id = $1
             "#,
@@ -96,7 +96,7 @@ impl ChatRepo {
     pub async fn get_by_id(id: u64, pool: &PgPool) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at, topic
             FROM chats
             WHERE id = $1
             "#,
@@ -123,6 +123,88 @@ impl ChatRepo {
 
         Ok(res.id)
     }
+
+    /// Set the topic of a chat, clearing it with `None`.
+    pub async fn set_topic(id: u64, topic: Option<&str>, pool: &PgPool) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats SET topic = $2
+            WHERE id = $1
+            RETURNING id, ws_id, name, type, members, created_at, topic
+            "#,
+        )
+        .bind(id as i64)
+        .bind(topic)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Add the users who aren't members yet.
+    pub async fn add_members(id: u64, user_ids: &[i64], pool: &PgPool) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = members || array(SELECT unnest($2::bigint[]) EXCEPT SELECT unnest(members))
+            WHERE id = $1
+            RETURNING id, ws_id, name, type, members, created_at, topic
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_ids)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    pub async fn remove_member(id: u64, user_id: u64, pool: &PgPool) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats SET members = array_remove(members, $2)
+            WHERE id = $1
+            RETURNING id, ws_id, name, type, members, created_at, topic
+            "#,
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Mute the chat for the user, or unmute it if it is muted. Returns whether it's muted now.
+    pub async fn toggle_mute(id: u64, user_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM chat_mutes WHERE chat_id = $1 AND user_id = $2")
+            .bind(id as i64)
+            .bind(user_id as i64)
+            .execute(pool)
+            .await?;
+        if ret.rows_affected() > 0 {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "INSERT INTO chat_mutes (chat_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(id as i64)
+        .bind(user_id as i64)
+        .execute(pool)
+        .await?;
+        Ok(true)
+    }
+
+    /// Ids of the chats the user muted.
+    pub async fn fetch_muted(user_id: u64, pool: &PgPool) -> Result<Vec<i64>, AppError> {
+        let ids: Vec<(i64,)> =
+            sqlx::query_as("SELECT chat_id FROM chat_mutes WHERE user_id = $1 ORDER BY chat_id")
+                .bind(user_id as i64)
+                .fetch_all(pool)
+                .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
 }
 
 #[cfg(test)]
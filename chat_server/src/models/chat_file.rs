@@ -1,70 +1,87 @@
 use crate::models::ChatFile;
 use crate::AppError;
-use sha1::{Digest, Sha1};
-use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 impl ChatFile {
-    pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
-        let hash = Sha1::digest(data);
+    /// A file whose content has the hex SHA-1 `hash`.
+    pub fn with_hash(ws_id: u64, filename: &str, hash: String) -> Self {
+        let ext = filename.split('.').next_back().unwrap_or_default();
+        let ext = if is_valid_ext(ext) { ext } else { "bin" };
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
-            hash: hex::encode(hash),
+            ext: ext.to_string(),
+            hash,
         }
     }
 
     pub fn url(&self) -> String {
-        format!("/files/{}", self.hash_to_path())
+        format!("/files/{}", self.key())
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+    /// Where the file is kept in the storage, its hash split in 3 parts, the first 2 of 3 chars.
+    pub fn key(&self) -> String {
+        let (part1, part2) = self.hash.split_at(3);
+        let (part2, part3) = part2.split_at(3);
+        format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
     }
 
-    // split hash into 3 parts, first 2 with 3 chars
-    fn hash_to_path(&self) -> String {
+    /// Where its thumbnail of `size` is kept, next to it. Not a file key, so it can't be
+    /// downloaded as one.
+    pub fn thumbnail_key(&self, size: u32, ext: &str) -> String {
         let (part1, part2) = self.hash.split_at(3);
         let (part2, part3) = part2.split_at(3);
-        format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
+        format!(
+            "{}/{}/{}/{}.{}.{}",
+            self.ws_id, part1, part2, part3, size, ext
+        )
+    }
+
+    /// The file kept at storage `key`, and whether `key` is one of its thumbnails rather than
+    /// its content. The ext of a thumbnail is its own, not the file's.
+    pub fn from_key(key: &str) -> Option<(Self, bool)> {
+        if let Ok(file) = format!("/files/{key}").parse() {
+            return Some((file, false));
+        }
+        let (rest, ext) = key.rsplit_once('.')?;
+        let (rest, size) = rest.rsplit_once('.')?;
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let file = format!("/files/{rest}.{ext}").parse().ok()?;
+        Some((file, true))
     }
 }
 
 impl FromStr for ChatFile {
     type Err = AppError;
 
-    // convert /files/s/339/807/e635afbeab088ce33206fdf4223a6bb156.png to ChatFile
+    // convert /files/1/339/807/e635afbeab088ce33206fdf4223a6bb156.png to ChatFile, nothing else
+    // is accepted: the path ends up in a storage key
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let Some(s) = s.strip_prefix("/files/") else {
-            return Err(AppError::ChatFileError(format!(
-                "Invalid chat file path: {}",
-                s
-            )));
+        let invalid = || AppError::ChatFileError(format!("Invalid chat file path: {}", s));
+        let parts: Vec<&str> = s
+            .strip_prefix("/files/")
+            .ok_or_else(invalid)?
+            .split('/')
+            .collect();
+        let [ws_id, part1, part2, name] = parts[..] else {
+            return Err(invalid());
         };
+        let (part3, ext) = name.split_once('.').ok_or_else(invalid)?;
 
-        let parts: Vec<&str> = s.split('/').collect();
-        if parts.len() != 4 {
-            return Err(AppError::ChatFileError(format!(
-                "File path {} does not valid",
-                s
-            )));
+        if ws_id.is_empty() || !ws_id.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let ws_id = ws_id.parse::<u64>().map_err(|_| invalid())?;
+        let hash = format!("{}{}{}", part1, part2, part3);
+        let is_hex = hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if part1.len() != 3 || part2.len() != 3 || hash.len() != 40 || !is_hex {
+            return Err(invalid());
+        }
+        if !is_valid_ext(ext) {
+            return Err(invalid());
         }
 
-        let Ok(ws_id) = parts[0].parse::<u64>() else {
-            return Err(AppError::ChatFileError(format!(
-                "Invalid workspace id: {}",
-                parts[1]
-            )));
-        };
-
-        let Some((part3, ext)) = parts[3].split_once('.') else {
-            return Err(AppError::ChatFileError(format!(
-                "Invalid file name: {}",
-                parts[3]
-            )));
-        };
-
-        let hash = format!("{}{}{}", parts[1], parts[2], part3);
         Ok(Self {
             ws_id,
             ext: ext.to_string(),
@@ -73,9 +90,20 @@ impl FromStr for ChatFile {
     }
 }
 
+fn is_valid_ext(ext: &str) -> bool {
+    (1..=64).contains(&ext.len()) && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sha1::{Digest, Sha1};
+
+    impl ChatFile {
+        pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
+            Self::with_hash(ws_id, filename, hex::encode(Sha1::digest(data)))
+        }
+    }
 
     #[test]
     fn chat_file_new_should_work() {
@@ -83,4 +111,59 @@ mod tests {
         assert_eq!(file.ext, "txt");
         assert_eq!(file.hash, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
     }
+
+    #[test]
+    fn chat_file_should_round_trip_through_its_url() {
+        let file = ChatFile::new(1, "photo.JPG", b"hello");
+        assert_eq!(file.url().parse::<ChatFile>().unwrap(), file);
+        // odd extensions aren't kept
+        assert_eq!(ChatFile::new(1, "notes.tar gz", b"hello").ext, "bin");
+        assert_eq!(ChatFile::new(1, "../x/..", b"hello").ext, "bin");
+
+        let thumbnail = format!("/files/{}", file.thumbnail_key(160, "jpg"));
+        assert!(thumbnail.parse::<ChatFile>().is_err());
+    }
+
+    #[test]
+    fn chat_file_should_be_found_from_its_keys() {
+        let file = ChatFile::new(1, "photo.png", b"hello");
+        assert_eq!(ChatFile::from_key(&file.key()), Some((file.clone(), false)));
+        let (thumbnail, is_thumbnail) =
+            ChatFile::from_key(&file.thumbnail_key(160, "webp")).unwrap();
+        assert!(is_thumbnail);
+        assert_eq!((thumbnail.ws_id, thumbnail.hash), (1, file.hash));
+        for key in [
+            "tmp/0190a5b2-7c1e-7d4a-9b2e-1f4c2d3e4f50",
+            "uploads/x.1.png",
+        ] {
+            assert_eq!(ChatFile::from_key(key), None, "{key} isn't a file");
+        }
+    }
+
+    #[test]
+    fn malformed_chat_file_paths_should_be_rejected() {
+        let hash = "aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d";
+        for path in [
+            "/files/1/../../../etc/passwd".to_string(),
+            "/files/1/aaf/4c6/../../../../etc/passwd.txt".to_string(),
+            "/files/1/aaf/4c6/..%2F..%2Fetc%2Fpasswd.txt".to_string(),
+            format!("/files/1/{hash}.txt/../../secret.txt"),
+            format!("/files/1/{hash}./"),
+            format!("/files/1/{hash}.tx/t"),
+            format!("/files/1/{hash}.t.t"),
+            format!("/files/1/{hash}"),
+            format!("/files/+1/{hash}.txt"),
+            format!("/files//{hash}.txt"),
+            format!("/files/1/{}.txt", hash.to_uppercase()),
+            "/files/1/aaf/4c61/ddcc5e8a2dabede0f3b482cd9aea9434d.txt".to_string(),
+            format!("/files/1/{hash}.txt\\..\\x"),
+            format!("files/1/{hash}.txt"),
+        ] {
+            assert!(
+                path.parse::<ChatFile>().is_err(),
+                "{path} should be rejected"
+            );
+        }
+        assert!(format!("/files/1/{hash}.txt").parse::<ChatFile>().is_ok());
+    }
 }
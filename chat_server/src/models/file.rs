@@ -0,0 +1,402 @@
+use crate::models::ChatFile;
+use crate::AppError;
+use chat_core::{FileAttachment, Thumbnail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use utoipa::IntoParams;
+
+/// What's known of an uploaded file.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct FileMeta {
+    pub id: i64,
+    pub ws_id: i64,
+    pub hash: String,
+    pub ext: String,
+    pub name: String,
+    /// None for content uploaded before sizes were kept, until the file collector measures it.
+    pub size: Option<i64>,
+    pub mime: String,
+    pub uploaded_by: i64,
+    pub created_at: DateTime<Utc>,
+    /// Dimensions of an image, in pixels.
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    #[sqlx(json)]
+    pub thumbnails: Vec<ThumbnailMeta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ThumbnailMeta {
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Of its key, which gives its format.
+    pub ext: String,
+}
+
+#[derive(Debug, Clone, IntoParams, Serialize, Deserialize)]
+#[into_params(parameter_in = Query)]
+pub struct FileQuery {
+    /// Size of the thumbnail to get rather than the file.
+    pub size: Option<u32>,
+}
+
+impl FileMeta {
+    pub fn chat_file(&self) -> ChatFile {
+        ChatFile {
+            ws_id: self.ws_id as _,
+            ext: self.ext.clone(),
+            hash: self.hash.clone(),
+        }
+    }
+
+    pub fn attachment(&self) -> FileAttachment {
+        let url = self.chat_file().url();
+        let thumbnails = self
+            .thumbnails
+            .iter()
+            .map(|t| Thumbnail {
+                size: t.size,
+                width: t.width,
+                height: t.height,
+                url: format!("{url}?size={}", t.size),
+            })
+            .collect();
+        FileAttachment {
+            id: self.id,
+            name: self.name.clone(),
+            size: self.size.unwrap_or_default(),
+            mime: self.mime.clone(),
+            url,
+            width: self.width.map(|w| w as _),
+            height: self.height.map(|h| h as _),
+            thumbnails,
+        }
+    }
+}
+
+pub struct FileRepo;
+
+impl FileRepo {
+    /// Record `file` uploaded as `name`, the first upload of the same content is kept.
+    pub async fn create<'e>(
+        file: &ChatFile,
+        name: &str,
+        size: u64,
+        uploaded_by: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<FileMeta, AppError> {
+        if file.ext.len() > 64 {
+            return Err(AppError::ChatFileError(format!(
+                "file extension too long: {}",
+                file.ext
+            )));
+        }
+        let name = sanitize_name(name);
+        let mime = mime_guess::from_path(&name).first_or_octet_stream();
+
+        // the update returns the existing row, whoever uploaded it gets access to it
+        let meta = sqlx::query_as(
+            r#"
+        WITH file AS (
+          INSERT INTO files (ws_id, hash, ext, name, size, mime, uploaded_by)
+          VALUES ($1, $2, $3, $4, $5, $6, $7)
+          ON CONFLICT (ws_id, hash, ext) DO UPDATE SET last_uploaded_at = now()
+          RETURNING id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+            thumbnails
+        ), owner AS (
+          INSERT INTO file_owners (file_id, user_id)
+          SELECT id, $7 FROM file
+          ON CONFLICT DO NOTHING
+        )
+        SELECT id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+          thumbnails
+        FROM file
+        "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(name)
+        .bind(size as i64)
+        .bind(mime.to_string())
+        .bind(uploaded_by as i64)
+        .fetch_one(executor)
+        .await?;
+        Ok(meta)
+    }
+
+    pub async fn find<'e>(
+        file: &ChatFile,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<Option<FileMeta>, AppError> {
+        let meta = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+          thumbnails
+        FROM files
+        WHERE ws_id = $1 AND hash = $2 AND ext = $3
+        "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .fetch_optional(executor)
+        .await?;
+        Ok(meta)
+    }
+
+    /// Record the dimensions of an image, and the thumbnails stored for it.
+    pub async fn set_image<'e>(
+        id: i64,
+        width: u32,
+        height: u32,
+        thumbnails: &[ThumbnailMeta],
+        executor: impl PgExecutor<'e>,
+    ) -> Result<FileMeta, AppError> {
+        let meta = sqlx::query_as(
+            r#"
+        UPDATE files
+        SET width = $2, height = $3, thumbnails = $4
+        WHERE id = $1
+        RETURNING id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+          thumbnails
+        "#,
+        )
+        .bind(id)
+        .bind(width as i32)
+        .bind(height as i32)
+        .bind(Json(thumbnails))
+        .fetch_one(executor)
+        .await?;
+        Ok(meta)
+    }
+
+    /// Files whose size wasn't kept when they were uploaded.
+    pub async fn find_unmeasured(limit: i64, pool: &PgPool) -> Result<Vec<FileMeta>, AppError> {
+        let files = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+          thumbnails
+        FROM files
+        WHERE size IS NULL
+        ORDER BY id
+        LIMIT $1
+        "#,
+        )
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+        Ok(files)
+    }
+
+    /// Record the size of file `id`, in the attachments of the messages it's in too.
+    pub async fn set_size(id: i64, size: u64, pool: &PgPool) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+        WITH file AS (
+          UPDATE files SET size = $2 WHERE id = $1
+        )
+        UPDATE messages m
+        SET files = (
+          SELECT jsonb_agg(
+            CASE WHEN a->'id' = to_jsonb($1) THEN jsonb_set(a, '{size}', to_jsonb($2)) ELSE a END
+            ORDER BY ord)
+          FROM jsonb_array_elements(m.files) WITH ORDINALITY AS t(a, ord)
+        )
+        WHERE m.files @> jsonb_build_array(jsonb_build_object('id', $1))
+        "#,
+        )
+        .bind(id)
+        .bind(size as i64)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Hold the content of `file`, whatever its ext, until the end of the transaction: it's
+    /// uploaded, attached or collected, one at a time.
+    pub async fn lock(file: &ChatFile, tx: &mut PgConnection) -> Result<(), AppError> {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(format!("{}/{}", file.ws_id, file.hash))
+            .execute(tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether `file` is recorded, or with `any_ext` whether its content is, as for thumbnails.
+    pub async fn is_recorded<'e>(
+        file: &ChatFile,
+        any_ext: bool,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<bool, AppError> {
+        let (recorded,): (bool,) = sqlx::query_as(
+            r#"
+        SELECT EXISTS (
+          SELECT 1 FROM files WHERE ws_id = $1 AND hash = $2 AND ($3 OR ext = $4)
+        )
+        "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(any_ext)
+        .bind(&file.ext)
+        .fetch_one(executor)
+        .await?;
+        Ok(recorded)
+    }
+
+    /// Files no message has, and whose content wasn't uploaded for `grace_secs`.
+    pub async fn find_orphans(
+        grace_secs: u64,
+        limit: i64,
+        pool: &PgPool,
+    ) -> Result<Vec<FileMeta>, AppError> {
+        let files = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, hash, ext, name, size, mime, uploaded_by, created_at, width, height,
+          thumbnails
+        FROM files f
+        WHERE f.last_uploaded_at < now() - make_interval(secs => $1)
+        AND NOT EXISTS (
+          SELECT 1 FROM messages m
+          WHERE m.files @> jsonb_build_array(jsonb_build_object('id', f.id))
+        )
+        ORDER BY id
+        LIMIT $2
+        "#,
+        )
+        .bind(grace_secs as f64)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+        Ok(files)
+    }
+
+    /// Delete file `id` if it's still an orphan, false if it was attached or uploaded meanwhile.
+    pub async fn delete_orphan(
+        id: i64,
+        grace_secs: u64,
+        tx: &mut PgConnection,
+    ) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+        DELETE FROM files f
+        WHERE f.id = $1
+        AND f.last_uploaded_at < now() - make_interval(secs => $2)
+        AND NOT EXISTS (
+          SELECT 1 FROM messages m
+          WHERE m.files @> jsonb_build_array(jsonb_build_object('id', f.id))
+        )
+        "#,
+        )
+        .bind(id)
+        .bind(grace_secs as f64)
+        .execute(tx)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Whether `user_id` uploaded `file`, or is a member of a chat it's attached to.
+    pub async fn can_access<'e>(
+        file: &ChatFile,
+        user_id: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<bool, AppError> {
+        let (allowed,): (bool,) = sqlx::query_as(
+            r#"
+        SELECT EXISTS (
+          SELECT 1
+          FROM files f
+          WHERE f.ws_id = $1 AND f.hash = $2 AND f.ext = $3
+          AND (
+            EXISTS (SELECT 1 FROM file_owners o WHERE o.file_id = f.id AND o.user_id = $4)
+            OR EXISTS (
+              SELECT 1
+              FROM messages m JOIN chats c ON c.id = m.chat_id
+              WHERE m.files @> jsonb_build_array(jsonb_build_object('id', f.id))
+              AND $4 = ANY(c.members)
+            )
+          )
+        )
+        "#,
+        )
+        .bind(file.ws_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .bind(user_id as i64)
+        .fetch_one(executor)
+        .await?;
+        Ok(allowed)
+    }
+}
+
+/// The last component of a client-provided file name, at most 255 bytes.
+fn sanitize_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    let mut end = name.len().min(255);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    match &name[..end] {
+        "" => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateMessage;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[test]
+    fn file_name_should_be_sanitized() {
+        assert_eq!(sanitize_name("C:\\Users\\cae\\report.pdf"), "report.pdf");
+        assert_eq!(sanitize_name("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("dir/"), "file");
+        assert_eq!(sanitize_name(&"é".repeat(200)).len(), 254);
+    }
+
+    #[tokio::test]
+    async fn first_upload_metadata_should_be_kept() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "report.pdf", b"hello");
+        let meta = FileRepo::create(&file, "report.pdf", 5, 1, &state.pool).await?;
+        assert_eq!(meta.mime, "application/pdf");
+        assert_eq!(meta.chat_file().url(), file.url());
+
+        let again = FileRepo::create(&file, "copy.pdf", 5, 2, &state.pool).await?;
+        assert_eq!(again, meta);
+        assert_eq!(FileRepo::find(&file, &state.pool).await?, Some(meta));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_access_should_follow_its_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = ChatFile::new(1, "secret.txt", b"psst");
+        FileRepo::create(&file, "secret.txt", 4, 1, &state.pool).await?;
+        // uploading the same content gives access too
+        FileRepo::create(&file, "secret.txt", 4, 5, &state.pool).await?;
+        assert!(FileRepo::can_access(&file, 1, &state.pool).await?);
+        assert!(FileRepo::can_access(&file, 5, &state.pool).await?);
+        assert!(!FileRepo::can_access(&file, 2, &state.pool).await?);
+
+        // shared in the single chat of users 1 and 2
+        let input = CreateMessage {
+            content: "here".to_string(),
+            files: vec![file.url()],
+        };
+        state
+            .message
+            .create_message(&state.pool, input, 3, 1)
+            .await?;
+        assert!(FileRepo::can_access(&file, 2, &state.pool).await?);
+        assert!(!FileRepo::can_access(&file, 3, &state.pool).await?);
+        Ok(())
+    }
+}
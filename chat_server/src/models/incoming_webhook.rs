@@ -0,0 +1,213 @@
+use crate::models::{hash_token, random_secret, UserRepo};
+use crate::AppError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct IncomingWebhook {
+    pub id: i64,
+    pub chat_id: i64,
+    /// The bot user messages are posted as.
+    pub bot_id: i64,
+    pub name: String,
+    /// Only returned on creation, the webhook is posted to at `/api/hooks/{token}`.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateIncomingWebhook {
+    /// Shown as the sender of the posted messages.
+    pub name: String,
+}
+
+/// A Slack-compatible message payload.
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct IncomingMessage {
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct Attachment {
+    pub fallback: Option<String>,
+    pub pretext: Option<String>,
+    pub title: Option<String>,
+    pub title_link: Option<String>,
+    pub text: Option<String>,
+    #[serde(default)]
+    pub fields: Vec<AttachmentField>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct AttachmentField {
+    pub title: String,
+    pub value: String,
+}
+
+pub struct IncomingWebhookRepo;
+
+impl IncomingWebhookRepo {
+    /// Create a webhook posting into `chat_id`, along with its bot user.
+    pub async fn create(
+        input: CreateIncomingWebhook,
+        chat_id: u64,
+        ws_id: u64,
+        user_id: u64,
+        pool: &PgPool,
+    ) -> Result<IncomingWebhook, AppError> {
+        let token = random_secret();
+        let mut tx = pool.begin().await?;
+        let bot = UserRepo::create_bot(&input.name, ws_id, user_id, &mut *tx).await?;
+
+        let mut webhook: IncomingWebhook = sqlx::query_as(
+            r#"
+        INSERT INTO incoming_webhooks (chat_id, bot_id, name, token_hash, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, chat_id, bot_id, name, created_by, created_at
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(bot.id)
+        .bind(&bot.fullname)
+        .bind(hash_token(&token))
+        .bind(user_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        webhook.token = Some(token);
+        Ok(webhook)
+    }
+
+    pub async fn fetch_all(chat_id: u64, pool: &PgPool) -> Result<Vec<IncomingWebhook>, AppError> {
+        let webhooks = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, bot_id, name, created_by, created_at
+        FROM incoming_webhooks
+        WHERE chat_id = $1
+        ORDER BY id
+        "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(webhooks)
+    }
+
+    /// Find the webhook `token` was issued for.
+    pub async fn find_by_token(
+        token: &str,
+        pool: &PgPool,
+    ) -> Result<Option<IncomingWebhook>, AppError> {
+        let webhook = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, bot_id, name, created_by, created_at
+        FROM incoming_webhooks
+        WHERE token_hash = $1
+        "#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(pool)
+        .await?;
+        Ok(webhook)
+    }
+
+    /// Revoke a webhook, its bot user stays as the sender of what it posted.
+    pub async fn delete(id: u64, chat_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM incoming_webhooks WHERE id = $1 AND chat_id = $2")
+            .bind(id as i64)
+            .bind(chat_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+}
+
+impl IncomingMessage {
+    /// Render the text and attachments as plain message content.
+    pub fn content(&self) -> String {
+        let mut lines: Vec<String> = self.text.iter().cloned().collect();
+        for attachment in &self.attachments {
+            let start = lines.len();
+            lines.extend(attachment.pretext.iter().cloned());
+            match (&attachment.title, &attachment.title_link) {
+                (Some(title), Some(link)) => lines.push(format!("{title} ({link})")),
+                (Some(title), None) => lines.push(title.clone()),
+                _ => {}
+            }
+            lines.extend(attachment.text.iter().cloned());
+            lines.extend(
+                attachment
+                    .fields
+                    .iter()
+                    .map(|field| format!("{}: {}", field.title, field.value)),
+            );
+            if lines.len() == start {
+                lines.extend(attachment.fallback.iter().cloned());
+            }
+        }
+        lines.retain(|line| !line.trim().is_empty());
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[test]
+    fn slack_payload_should_render_as_content() -> Result<()> {
+        let input: IncomingMessage = serde_json::from_str(
+            r##"{
+              "text": "Build finished",
+              "attachments": [
+                {"title": "#42", "title_link": "https://ci.example.com/42", "fields": [{"title": "Status", "value": "passed"}]},
+                {"fallback": "deployed to staging"}
+              ]
+            }"##,
+        )?;
+        assert_eq!(
+            input.content(),
+            "Build finished\n#42 (https://ci.example.com/42)\nStatus: passed\ndeployed to staging"
+        );
+        assert_eq!(IncomingMessage::default().content(), "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incoming_webhook_should_be_found_by_token_until_deleted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateIncomingWebhook {
+            name: "CI".to_string(),
+        };
+        let webhook = IncomingWebhookRepo::create(input, 1, 1, 1, &state.pool).await?;
+        let token = webhook.token.clone().expect("token should be returned");
+
+        let found = IncomingWebhookRepo::find_by_token(&token, &state.pool)
+            .await?
+            .expect("webhook should exist");
+        assert_eq!(found.id, webhook.id);
+        assert_eq!(found.token, None);
+        assert_eq!(
+            IncomingWebhookRepo::fetch_all(1, &state.pool).await?.len(),
+            1
+        );
+
+        assert!(!IncomingWebhookRepo::delete(webhook.id as _, 2, &state.pool).await?);
+        assert!(IncomingWebhookRepo::delete(webhook.id as _, 1, &state.pool).await?);
+        assert!(IncomingWebhookRepo::find_by_token(&token, &state.pool)
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
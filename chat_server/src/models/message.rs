@@ -1,15 +1,16 @@
-use crate::models::ChatFile;
+use crate::models::{ChatFile, FileRepo};
 use crate::AppError;
 use chat_core::Message;
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 use sqlx::PgPool;
-use std::path::PathBuf;
 use std::str::FromStr;
 use utoipa::{IntoParams, ToSchema};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
+    /// Urls of uploaded files.
     pub files: Vec<String>,
 }
 
@@ -19,15 +20,7 @@ pub struct ListMessages {
     pub limit: u64,
 }
 
-pub struct MessageRepo {
-    base_dir: PathBuf,
-}
-
-impl MessageRepo {
-    pub fn new(base_dir: PathBuf) -> Self {
-        Self { base_dir }
-    }
-}
+pub struct MessageRepo;
 
 impl MessageRepo {
     pub async fn create_message(
@@ -37,7 +30,6 @@ impl MessageRepo {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        let base_dir = &self.base_dir;
         // verify content - not empty
         if input.content.is_empty() {
             return Err(AppError::CreateMessageError(
@@ -45,15 +37,35 @@ impl MessageRepo {
             ));
         }
 
-        // verify files exist
-        for s in &input.files {
-            let file = ChatFile::from_str(s)?;
-            if !file.path(base_dir).exists() {
+        let chat_files = input
+            .files
+            .iter()
+            .map(|s| ChatFile::from_str(s))
+            .collect::<Result<Vec<_>, _>>()?;
+        // the files can't be collected until the message has them, they're locked in order for
+        // messages with the same files not to wait on each other
+        let mut tx = pool.begin().await?;
+        let mut locks: Vec<_> = chat_files.iter().collect();
+        locks.sort_by(|a, b| (a.ws_id, &a.hash).cmp(&(b.ws_id, &b.hash)));
+        locks.dedup_by(|a, b| (a.ws_id, &a.hash) == (b.ws_id, &b.hash));
+        for file in locks {
+            FileRepo::lock(file, &mut tx).await?;
+        }
+
+        // verify files exist, and the sender may share them
+        let mut files = Vec::with_capacity(input.files.len());
+        for (s, file) in input.files.iter().zip(&chat_files) {
+            let meta = match FileRepo::can_access(file, user_id, &mut *tx).await? {
+                true => FileRepo::find(file, &mut *tx).await?,
+                false => None,
+            };
+            let Some(meta) = meta else {
                 return Err(AppError::CreateMessageError(format!(
                     "File {} doesn't exist",
                     s
                 )));
-            }
+            };
+            files.push(meta.attachment());
         }
 
         // create message
@@ -67,9 +79,10 @@ impl MessageRepo {
         .bind(chat_id as i64)
         .bind(user_id as i64)
         .bind(input.content)
-        .bind(&input.files)
-        .fetch_one(pool)
+        .bind(Json(files))
+        .fetch_one(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(message)
     }
@@ -105,7 +118,7 @@ impl MessageRepo {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::AppState;
+    use crate::{AppState, Storage};
     use anyhow::Result;
 
     #[tokio::test]
@@ -136,7 +149,7 @@ mod tests {
         assert_eq!(err.to_string(), "Invalid chat file path: 1");
 
         // valid files should work
-        let url = upload_dummy_file(&state)?;
+        let url = upload_dummy_file(&state).await?;
         let input = CreateMessage {
             content: "hello".to_string(),
             files: vec![url],
@@ -149,6 +162,8 @@ mod tests {
             .expect("create message failed");
         assert_eq!(message.content, "hello");
         assert_eq!(message.files.len(), 1);
+        assert_eq!(message.files[0].name, "test.txt");
+        assert_eq!(message.files[0].size, 11);
 
         Ok(())
     }
@@ -177,11 +192,10 @@ mod tests {
         Ok(())
     }
 
-    fn upload_dummy_file(state: &AppState) -> Result<String> {
+    async fn upload_dummy_file(state: &AppState) -> Result<String> {
         let file = ChatFile::new(1, "test.txt", b"hello world");
-        let path = file.path(&state.config.server.base_dir);
-        std::fs::create_dir_all(path.parent().expect("file path parent should exists"))?;
-        std::fs::write(&path, b"hello world")?;
+        state.storage.put(&file.key(), "hello world".into()).await?;
+        FileRepo::create(&file, "test.txt", 11, 1, &state.pool).await?;
 
         Ok(file.url())
     }
@@ -1,18 +1,55 @@
+mod api_token;
 mod chat;
 mod chat_file;
+mod file;
+mod incoming_webhook;
 mod message;
+mod presence;
+mod slash_command;
+mod stream_ticket;
+mod upload_session;
 mod user;
+mod webhook;
 mod workspace;
 
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+pub use api_token::{ApiToken, ApiTokenRepo, CreateApiToken, API_TOKEN_PREFIX};
 pub use chat::{ChatRepo, CreateChat};
+pub use file::{FileMeta, FileQuery, FileRepo, ThumbnailMeta};
+pub use incoming_webhook::{
+    Attachment, AttachmentField, CreateIncomingWebhook, IncomingMessage, IncomingWebhook,
+    IncomingWebhookRepo,
+};
 pub use message::{CreateMessage, ListMessages, MessageRepo};
-pub use user::{CreateUser, SigninUser, UserRepo};
+pub use presence::PresenceRepo;
+pub(crate) use slash_command::is_valid_name;
+pub use slash_command::{CreateSlashCommand, SlashCommand, SlashCommandRepo};
+pub use stream_ticket::{StreamTicket, StreamTicketRepo, STREAM_TICKET_TTL};
+pub use upload_session::{CreateUpload, UploadSession, UploadSessionRepo};
+pub use user::{CreateBot, CreateUser, SigninUser, UserRepo};
+pub use webhook::{
+    CreateWebhook, DeliveryStatus, Webhook, WebhookDelivery, WebhookRepo, WEBHOOK_EVENTS,
+};
+pub use workspace::{StoragePolicy, StorageUsage, WorkspaceRepo, WorkspaceSettings};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ChatFile {
     pub ws_id: u64,
     pub ext: String, // extract ext from filename or mime type
     pub hash: String,
 }
+
+/// A random 256-bit secret, hex encoded.
+pub(crate) fn random_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hex sha256 of a token, which is all that's stored of it.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
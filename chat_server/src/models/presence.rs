@@ -0,0 +1,50 @@
+use crate::AppError;
+use chat_core::Presence;
+use sqlx::PgPool;
+
+pub struct PresenceRepo;
+
+impl PresenceRepo {
+    /// Presence of every user in the workspace, as last announced by notify_server.
+    pub async fn fetch_all(ws_id: u64, pool: &PgPool) -> Result<Vec<Presence>, AppError> {
+        let presences = sqlx::query_as(
+            r#"
+        SELECT u.id AS user_id,
+          COALESCE(p.status, 'offline') AS status,
+          COALESCE(p.updated_at, u.created_at, now()) AS updated_at
+        FROM users u
+        LEFT JOIN user_presence p ON p.user_id = u.id
+        WHERE u.ws_id = $1
+        ORDER BY u.id
+        "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(presences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+    use chat_core::PresenceStatus;
+
+    #[tokio::test]
+    async fn fetch_all_presence_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query("INSERT INTO user_presence (user_id, status) VALUES (2, 'away')")
+            .execute(&state.pool)
+            .await?;
+
+        let presences = PresenceRepo::fetch_all(1, &state.pool).await?;
+        assert_eq!(presences.len(), 5);
+        assert_eq!(presences[0].status, PresenceStatus::Offline);
+        assert_eq!(presences[1].user_id, 2);
+        assert_eq!(presences[1].status, PresenceStatus::Away);
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::models::{random_secret, UserRepo};
+use crate::{AppError, OutboundConfig};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+
+/// Commands handled by chat_server itself, which can't be registered.
+pub const BUILTIN_COMMANDS: [&str; 5] = ["me", "topic", "invite", "leave", "mute"];
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SlashCommand {
+    pub id: i64,
+    pub ws_id: i64,
+    /// Invoked as `/{name}`.
+    pub name: String,
+    pub url: String,
+    /// Only returned on creation, requests to the url are signed with it.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// The bot user replies in the channel are posted as.
+    pub bot_id: i64,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateSlashCommand {
+    pub name: String,
+    pub url: String,
+}
+
+pub struct SlashCommandRepo;
+
+impl SlashCommandRepo {
+    /// Register `/{input.name}` in workspace `ws_id`, along with the bot replying for it.
+    pub async fn create(
+        input: CreateSlashCommand,
+        ws_id: u64,
+        user_id: u64,
+        outbound: &OutboundConfig,
+        pool: &PgPool,
+    ) -> Result<SlashCommand, AppError> {
+        let name = input.name.trim_start_matches('/');
+        if !is_valid_name(name) {
+            return Err(AppError::CreateCommandError(format!(
+                "invalid name: {}",
+                input.name
+            )));
+        }
+        if BUILTIN_COMMANDS.contains(&name) {
+            return Err(AppError::CreateCommandError(format!(
+                "/{name} is a builtin command"
+            )));
+        }
+        outbound
+            .check_url(&input.url)
+            .await
+            .map_err(AppError::CreateCommandError)?;
+        if Self::find_by_name(name, ws_id, pool).await?.is_some() {
+            return Err(AppError::CreateCommandError(format!(
+                "/{name} already exists"
+            )));
+        }
+
+        let mut tx = pool.begin().await?;
+        let bot = UserRepo::create_bot(name, ws_id, user_id, &mut *tx).await?;
+        let command = sqlx::query_as(
+            r#"
+        INSERT INTO slash_commands (ws_id, name, url, secret, bot_id, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, ws_id, name, url, secret, bot_id, created_by, created_at
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(input.url)
+        .bind(random_secret())
+        .bind(bot.id)
+        .bind(user_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(command)
+    }
+
+    pub async fn fetch_all(ws_id: u64, pool: &PgPool) -> Result<Vec<SlashCommand>, AppError> {
+        let commands = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, name, url, bot_id, created_by, created_at
+        FROM slash_commands
+        WHERE ws_id = $1
+        ORDER BY name
+        "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(commands)
+    }
+
+    /// Find `/{name}` of workspace `ws_id`, with its secret.
+    pub async fn find_by_name(
+        name: &str,
+        ws_id: u64,
+        pool: &PgPool,
+    ) -> Result<Option<SlashCommand>, AppError> {
+        let command = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, name, url, secret, bot_id, created_by, created_at
+        FROM slash_commands
+        WHERE ws_id = $1 AND name = $2
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .fetch_optional(pool)
+        .await?;
+        Ok(command)
+    }
+
+    pub async fn delete(id: u64, ws_id: u64, pool: &PgPool) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM slash_commands WHERE id = $1 AND ws_id = $2")
+            .bind(id as i64)
+            .bind(ws_id as i64)
+            .execute(pool)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+}
+
+/// Lowercase letters, digits, `-` and `_`, at most 32 of them.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    impl CreateSlashCommand {
+        pub fn new(name: &str, url: &str) -> Self {
+            Self {
+                name: name.to_string(),
+                url: url.to_string(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn create_slash_command_should_validate_input() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let command = SlashCommandRepo::create(
+            CreateSlashCommand::new("/deploy", "https://ci.example.com/deploy"),
+            1,
+            1,
+            &state.config.outbound,
+            &state.pool,
+        )
+        .await?;
+        assert_eq!(command.name, "deploy");
+        assert!(command.secret.is_some());
+
+        for input in [
+            CreateSlashCommand::new("Deploy!", "https://ci.example.com"),
+            CreateSlashCommand::new("me", "https://ci.example.com"),
+            CreateSlashCommand::new("build", "ftp://ci.example.com"),
+            CreateSlashCommand::new("deploy", "https://ci.example.com"),
+        ] {
+            let ret =
+                SlashCommandRepo::create(input, 1, 1, &state.config.outbound, &state.pool).await;
+            assert!(matches!(ret, Err(AppError::CreateCommandError(_))));
+        }
+        let input = CreateSlashCommand::new("build", "http://169.254.169.254/latest");
+        let ret =
+            SlashCommandRepo::create(input, 1, 1, &OutboundConfig::default(), &state.pool).await;
+        assert!(matches!(ret, Err(AppError::CreateCommandError(_))));
+
+        let found = SlashCommandRepo::find_by_name("deploy", 1, &state.pool)
+            .await?
+            .expect("command should exist");
+        assert_eq!(found.secret, command.secret);
+        assert!(SlashCommandRepo::find_by_name("deploy", 2, &state.pool)
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use crate::models::random_secret;
+use crate::AppError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// How long a ticket can be redeemed for, it only has to outlive opening the stream.
+pub const STREAM_TICKET_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize)]
+pub struct StreamTicket {
+    pub ticket: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct StreamTicketRepo;
+
+impl StreamTicketRepo {
+    /// Issue a single-use ticket for `user_id` to open a notify_server stream with.
+    pub async fn create(user_id: u64, pool: &PgPool) -> Result<StreamTicket, AppError> {
+        let ticket = random_secret();
+
+        // tickets are deleted when redeemed, sweep the ones that never were
+        sqlx::query("DELETE FROM stream_tickets WHERE expires_at < now()")
+            .execute(pool)
+            .await?;
+
+        let ticket = sqlx::query_as(
+            r#"
+        INSERT INTO stream_tickets (ticket, user_id, expires_at)
+        VALUES ($1, $2, now() + $3::interval)
+        RETURNING ticket, expires_at
+        "#,
+        )
+        .bind(ticket)
+        .bind(user_id as i64)
+        .bind(STREAM_TICKET_TTL)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn create_stream_ticket_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let ticket = StreamTicketRepo::create(1, &state.pool).await?;
+        assert_eq!(ticket.ticket.len(), 64);
+        assert!(ticket.expires_at > Utc::now());
+
+        let other = StreamTicketRepo::create(1, &state.pool).await?;
+        assert_ne!(ticket.ticket, other.ticket);
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use crate::AppError;
+use chat_core::User;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// A resumable upload in progress.
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UploadSession {
+    #[schema(value_type = String)]
+    pub id: Uuid,
+    pub ws_id: i64,
+    pub user_id: i64,
+    pub filename: String,
+    /// Total size of the file, in bytes.
+    pub size: i64,
+    /// Bytes received so far, the next chunk starts there.
+    #[sqlx(rename = "upload_offset")]
+    pub offset: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Pushed back by each chunk received.
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateUpload {
+    pub filename: String,
+    pub size: u64,
+}
+
+pub struct UploadSessionRepo;
+
+impl UploadSessionRepo {
+    /// Start an upload, kept for `ttl` unless it advances.
+    pub async fn create<'e>(
+        input: CreateUpload,
+        user: &User,
+        ttl: Duration,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<UploadSession, AppError> {
+        if input.filename.is_empty() || input.filename.len() > 255 {
+            return Err(AppError::UploadError(
+                "filename must be 1 to 255 bytes".to_string(),
+            ));
+        }
+
+        let session = sqlx::query_as(
+            r#"
+        INSERT INTO upload_sessions (id, ws_id, user_id, filename, size, expires_at)
+        VALUES ($1, $2, $3, $4, $5, now() + $6::interval)
+        RETURNING id, ws_id, user_id, filename, size, upload_offset, created_at, updated_at,
+          expires_at
+        "#,
+        )
+        .bind(Uuid::now_v7())
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(input.filename)
+        .bind(input.size as i64)
+        .bind(ttl)
+        .fetch_one(executor)
+        .await?;
+        Ok(session)
+    }
+
+    /// Find an unexpired upload `user_id` started.
+    pub async fn find(
+        id: Uuid,
+        user_id: u64,
+        pool: &PgPool,
+    ) -> Result<Option<UploadSession>, AppError> {
+        let session = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, user_id, filename, size, upload_offset, created_at, updated_at,
+          expires_at
+        FROM upload_sessions
+        WHERE id = $1 AND user_id = $2 AND expires_at > now()
+        "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(session)
+    }
+
+    /// Like [`UploadSessionRepo::find`], holding the upload until the end of the transaction so
+    /// its chunks are written one at a time, and it's completed or cancelled after them.
+    pub async fn lock(
+        id: Uuid,
+        user_id: u64,
+        tx: &mut PgConnection,
+    ) -> Result<Option<UploadSession>, AppError> {
+        let session = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, user_id, filename, size, upload_offset, created_at, updated_at,
+          expires_at
+        FROM upload_sessions
+        WHERE id = $1 AND user_id = $2 AND expires_at > now()
+        FOR UPDATE
+        "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(tx)
+        .await?;
+        Ok(session)
+    }
+
+    /// Record `len` more bytes received from `offset`, unless another chunk got there first,
+    /// and keep the upload for `ttl` more.
+    pub async fn advance<'e>(
+        id: Uuid,
+        offset: i64,
+        len: i64,
+        ttl: Duration,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+        UPDATE upload_sessions
+        SET upload_offset = upload_offset + $3, updated_at = now(), expires_at = now() + $4::interval
+        WHERE id = $1 AND upload_offset = $2 AND expires_at > now()
+        "#,
+        )
+        .bind(id)
+        .bind(offset)
+        .bind(len)
+        .bind(ttl)
+        .execute(executor)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Remove an unexpired upload `user_id` started, returning it. Within a transaction, a
+    /// concurrent call waits for it and finds nothing once it commits.
+    pub async fn take<'e>(
+        id: Uuid,
+        user_id: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<Option<UploadSession>, AppError> {
+        let session = sqlx::query_as(
+            r#"
+        DELETE FROM upload_sessions
+        WHERE id = $1 AND user_id = $2 AND expires_at > now()
+        RETURNING id, ws_id, user_id, filename, size, upload_offset, created_at, updated_at,
+          expires_at
+        "#,
+        )
+        .bind(id)
+        .bind(user_id as i64)
+        .fetch_optional(executor)
+        .await?;
+        Ok(session)
+    }
+
+    /// Whether upload `id` is there, expired or not.
+    pub async fn exists(id: Uuid, pool: &PgPool) -> Result<bool, AppError> {
+        let (exists,): (bool,) =
+            sqlx::query_as("SELECT EXISTS (SELECT 1 FROM upload_sessions WHERE id = $1)")
+                .bind(id)
+                .fetch_one(pool)
+                .await?;
+        Ok(exists)
+    }
+
+    /// Ids of expired uploads, removed unless it's a `dry_run`.
+    pub async fn purge_expired(
+        limit: i64,
+        dry_run: bool,
+        pool: &PgPool,
+    ) -> Result<Vec<Uuid>, AppError> {
+        let sql = if dry_run {
+            "SELECT id FROM upload_sessions WHERE expires_at <= now() ORDER BY id LIMIT $1"
+        } else {
+            r#"
+        DELETE FROM upload_sessions
+        WHERE id IN (
+          SELECT id FROM upload_sessions WHERE expires_at <= now() ORDER BY id LIMIT $1
+        )
+        RETURNING id
+        "#
+        };
+        let ids: Vec<(Uuid,)> = sqlx::query_as(sql).bind(limit).fetch_all(pool).await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn upload_session_should_only_advance_from_its_offset() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let input = CreateUpload {
+            filename: "video.mp4".to_string(),
+            size: 10,
+        };
+        let ttl = state.config.uploads.ttl();
+        let session = UploadSessionRepo::create(input, &user, ttl, &state.pool).await?;
+        assert_eq!(session.offset, 0);
+
+        assert!(UploadSessionRepo::advance(session.id, 0, 4, ttl, &state.pool).await?);
+        assert!(!UploadSessionRepo::advance(session.id, 0, 4, ttl, &state.pool).await?);
+        // past its size
+        assert!(
+            UploadSessionRepo::advance(session.id, 4, 7, ttl, &state.pool)
+                .await
+                .is_err()
+        );
+
+        let found = UploadSessionRepo::find(session.id, user.id as _, &state.pool)
+            .await?
+            .expect("session should exist");
+        assert_eq!(found.offset, 4);
+        assert!(UploadSessionRepo::find(session.id, 2, &state.pool)
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_uploads_should_be_purged() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.test_user("cae@cae.org").await?;
+        let input = CreateUpload {
+            filename: "video.mp4".to_string(),
+            size: 10,
+        };
+        let ttl = state.config.uploads.ttl();
+        let live = UploadSessionRepo::create(input.clone(), &user, ttl, &state.pool).await?;
+        let expired = UploadSessionRepo::create(input, &user, Duration::ZERO, &state.pool).await?;
+
+        assert!(
+            UploadSessionRepo::find(expired.id, user.id as _, &state.pool)
+                .await?
+                .is_none()
+        );
+        assert!(!UploadSessionRepo::advance(expired.id, 0, 4, ttl, &state.pool).await?);
+        let ids = UploadSessionRepo::purge_expired(100, true, &state.pool).await?;
+        assert_eq!(ids, [expired.id]);
+        let ids = UploadSessionRepo::purge_expired(100, false, &state.pool).await?;
+        assert_eq!(ids, [expired.id]);
+        assert!(UploadSessionRepo::purge_expired(100, false, &state.pool)
+            .await?
+            .is_empty());
+        assert!(UploadSessionRepo::find(live.id, user.id as _, &state.pool)
+            .await?
+            .is_some());
+        Ok(())
+    }
+}
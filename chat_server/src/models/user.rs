@@ -1,4 +1,4 @@
-use crate::models::workspace::WorkspaceRepo;
+use crate::models::{random_secret, WorkspaceRepo};
 use crate::AppError;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
@@ -6,7 +6,7 @@ use argon2::{
 };
 use chat_core::{ChatUser, User};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgExecutor, PgPool};
 use std::mem;
 use utoipa::ToSchema;
 
@@ -24,6 +24,11 @@ pub struct SigninUser {
     pub password: String,
 }
 
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct CreateBot {
+    pub name: String,
+}
+
 pub struct UserRepo;
 
 impl UserRepo {
@@ -38,6 +43,16 @@ impl UserRepo {
         Ok(user)
     }
 
+    pub async fn find_by_id(id: u64, pool: &PgPool) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1",
+        )
+        .bind(id as i64)
+        .fetch_optional(pool)
+        .await?;
+        Ok(user)
+    }
+
     /// Create a new user
     pub async fn create(input: &CreateUser, pool: &PgPool) -> Result<User, AppError> {
         // check if email exists
@@ -76,10 +91,79 @@ impl UserRepo {
         Ok(user)
     }
 
+    /// Create a bot user of workspace `ws_id`, it has no password and can't sign in.
+    pub async fn create_bot<'e>(
+        name: &str,
+        ws_id: u64,
+        created_by: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<User, AppError> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > 64 {
+            return Err(AppError::CreateBotError(
+                "name must be 1 to 64 characters".to_string(),
+            ));
+        }
+
+        let user = sqlx::query_as(
+            r#"
+            INSERT INTO users (ws_id, fullname, email, password_hash, is_bot, created_by)
+            VALUES ($1, $2, $3, '', TRUE, $4)
+            RETURNING id, ws_id, fullname, email, created_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(name)
+        .bind(format!("bot-{}@bots.invalid", &random_secret()[..16]))
+        .bind(created_by as i64)
+        .fetch_one(executor)
+        .await?;
+        Ok(user)
+    }
+
+    /// Find a user of workspace `ws_id` by `@handle`, either their email or its local part.
+    pub async fn find_by_handle(
+        handle: &str,
+        ws_id: u64,
+        pool: &PgPool,
+    ) -> Result<Option<User>, AppError> {
+        let handle = handle.trim_start_matches('@');
+        let user = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, created_at
+            FROM users
+            WHERE ws_id = $1 AND NOT is_bot AND (email = $2 OR split_part(email, '@', 1) = $2)
+            ORDER BY id
+            LIMIT 1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(handle)
+        .fetch_optional(pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// Bots of workspace `ws_id`.
+    pub async fn fetch_bots(ws_id: u64, pool: &PgPool) -> Result<Vec<User>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, fullname, email, created_at
+            FROM users
+            WHERE ws_id = $1 AND is_bot
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+        Ok(users)
+    }
+
     /// Verify email and password
     pub async fn verify(input: &SigninUser, pool: &PgPool) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1",
+            "SELECT id, ws_id, fullname, email, password_hash, created_at FROM users WHERE email = $1 AND NOT is_bot",
         )
         .bind(&input.email)
         .fetch_optional(pool)
@@ -0,0 +1,276 @@
+use crate::models::{random_secret, WorkspaceRepo};
+use crate::{AppError, OutboundConfig};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+
+/// Events a webhook can subscribe to, the ephemeral ones aren't delivered.
+pub const WEBHOOK_EVENTS: [&str; 4] = ["NewChat", "AddToChat", "RemoveFromChat", "NewMessage"];
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Webhook {
+    pub id: i64,
+    pub ws_id: i64,
+    pub url: String,
+    /// Only returned on creation, deliveries are signed with it.
+    #[sqlx(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub events: Vec<String>,
+    pub chat_ids: Vec<i64>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    /// Event types to deliver, all of [`WEBHOOK_EVENTS`] if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Chats to deliver events of, the creator must be a member of each. Every chat of the
+    /// workspace if empty, which only its owner can subscribe to.
+    #[serde(default)]
+    pub chat_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "webhook_delivery_status", rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+pub struct WebhookRepo;
+
+impl WebhookRepo {
+    /// Subscribe `input.url` to events of workspace `ws_id`, on behalf of `user_id`.
+    pub async fn create(
+        input: CreateWebhook,
+        ws_id: u64,
+        user_id: u64,
+        outbound: &OutboundConfig,
+        pool: &PgPool,
+    ) -> Result<Webhook, AppError> {
+        outbound
+            .check_url(&input.url)
+            .await
+            .map_err(AppError::CreateWebhookError)?;
+        if let Some(event) = input
+            .events
+            .iter()
+            .find(|event| !WEBHOOK_EVENTS.contains(&event.as_str()))
+        {
+            return Err(AppError::CreateWebhookError(format!(
+                "unsupported event: {event}"
+            )));
+        }
+
+        let mut chat_ids = input.chat_ids;
+        chat_ids.sort_unstable();
+        chat_ids.dedup();
+        if chat_ids.is_empty() {
+            let owner_id = WorkspaceRepo::find_by_id(ws_id, pool)
+                .await?
+                .map(|ws| ws.owner_id);
+            if owner_id != Some(user_id as i64) {
+                return Err(AppError::Forbidden(
+                    "only the workspace owner can subscribe to every chat".to_string(),
+                ));
+            }
+        }
+        let (found,): (i64,) = sqlx::query_as(
+            "SELECT count(*) FROM chats WHERE ws_id = $1 AND id = ANY($2) AND $3 = ANY(members)",
+        )
+        .bind(ws_id as i64)
+        .bind(&chat_ids)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+        if found != chat_ids.len() as i64 {
+            return Err(AppError::CreateWebhookError(
+                "some chats aren't in the workspace or you aren't a member of them".to_string(),
+            ));
+        }
+
+        let webhook = sqlx::query_as(
+            r#"
+        INSERT INTO webhooks (ws_id, url, secret, events, chat_ids, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, ws_id, url, secret, events, chat_ids, created_by, created_at
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.url)
+        .bind(random_secret())
+        .bind(input.events)
+        .bind(chat_ids)
+        .bind(user_id as i64)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(webhook)
+    }
+
+    pub async fn fetch_all(ws_id: u64, pool: &PgPool) -> Result<Vec<Webhook>, AppError> {
+        let webhooks = sqlx::query_as(
+            r#"
+        SELECT id, ws_id, url, events, chat_ids, created_by, created_at
+        FROM webhooks
+        WHERE ws_id = $1
+        ORDER BY id
+        "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(webhooks)
+    }
+
+    /// Delete a webhook of the workspace along with its delivery log, returns whether it existed.
+    /// Only its creator or the workspace owner can.
+    pub async fn delete(
+        id: u64,
+        ws_id: u64,
+        user_id: u64,
+        pool: &PgPool,
+    ) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+        DELETE FROM webhooks w
+        WHERE w.id = $1 AND w.ws_id = $2
+          AND (w.created_by = $3 OR EXISTS (
+            SELECT 1 FROM workspaces ws WHERE ws.id = w.ws_id AND ws.owner_id = $3))
+        "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// The latest deliveries of a webhook of the workspace, newest first. Their events are of
+    /// the creator's chats, so only the creator or the workspace owner sees them.
+    pub async fn fetch_deliveries(
+        id: u64,
+        ws_id: u64,
+        user_id: u64,
+        limit: i64,
+        pool: &PgPool,
+    ) -> Result<Vec<WebhookDelivery>, AppError> {
+        let deliveries = sqlx::query_as(
+            r#"
+        SELECT d.id, d.webhook_id, d.event, d.status, d.attempts, d.response_status, d.last_error,
+          d.next_attempt_at, d.created_at, d.delivered_at
+        FROM webhook_deliveries d
+        JOIN webhooks w ON w.id = d.webhook_id
+        WHERE w.id = $1 AND w.ws_id = $2
+          AND (w.created_by = $3 OR EXISTS (
+            SELECT 1 FROM workspaces ws WHERE ws.id = w.ws_id AND ws.owner_id = $3))
+        ORDER BY d.created_at DESC, d.id DESC
+        LIMIT $4
+        "#,
+        )
+        .bind(id as i64)
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use anyhow::Result;
+
+    impl CreateWebhook {
+        pub fn new(url: &str, events: &[&str], chat_ids: &[i64]) -> Self {
+            Self {
+                url: url.to_string(),
+                events: events.iter().map(|e| e.to_string()).collect(),
+                chat_ids: chat_ids.to_vec(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn create_and_list_webhooks_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateWebhook::new("https://ci.example.com/hook", &["NewMessage"], &[1, 1]);
+        let webhook = WebhookRepo::create(input, 1, 1, &state.config.outbound, &state.pool).await?;
+        assert_eq!(webhook.secret.as_ref().map(|s| s.len()), Some(64));
+        assert_eq!(webhook.chat_ids, vec![1]);
+
+        let webhooks = WebhookRepo::fetch_all(1, &state.pool).await?;
+        assert_eq!(webhooks.len(), 1);
+        assert_eq!(webhooks[0].secret, None);
+        assert!(WebhookRepo::fetch_all(2, &state.pool).await?.is_empty());
+
+        assert!(!WebhookRepo::delete(webhook.id as _, 2, 1, &state.pool).await?);
+        assert!(WebhookRepo::delete(webhook.id as _, 1, 1, &state.pool).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_webhook_should_validate_input() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for input in [
+            CreateWebhook::new("ftp://example.com", &[], &[]),
+            CreateWebhook::new("https://example.com", &["Typing"], &[]),
+            CreateWebhook::new("https://example.com", &[], &[1, 100]),
+        ] {
+            let ret = WebhookRepo::create(input, 1, 1, &state.config.outbound, &state.pool).await;
+            assert!(matches!(ret, Err(AppError::CreateWebhookError(_))));
+        }
+
+        // loopback isn't reachable by default
+        let input = CreateWebhook::new("http://127.0.0.1:8080/hook", &[], &[1]);
+        let ret = WebhookRepo::create(input, 1, 1, &OutboundConfig::default(), &state.pool).await;
+        assert!(matches!(ret, Err(AppError::CreateWebhookError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhooks_should_only_see_chats_of_their_creator() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let outbound = &state.config.outbound;
+        // daisy isn't a member of the private channel
+        let input = CreateWebhook::new("https://example.com", &[], &[2]);
+        let ret = WebhookRepo::create(input, 1, 5, outbound, &state.pool).await;
+        assert!(matches!(ret, Err(AppError::CreateWebhookError(_))));
+
+        // every chat is the owner's to subscribe to
+        let input = CreateWebhook::new("https://example.com", &[], &[]);
+        let ret = WebhookRepo::create(input.clone(), 1, 2, outbound, &state.pool).await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+        WorkspaceRepo::update_owner(1, 2, &state.pool).await?;
+        WebhookRepo::create(input, 1, 2, outbound, &state.pool).await?;
+        Ok(())
+    }
+}
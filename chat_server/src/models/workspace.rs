@@ -1,6 +1,184 @@
 use crate::AppError;
 use chat_core::{ChatUser, Workspace};
-use sqlx::PgPool;
+use image::{ImageFormat, ImageReader};
+use mime_guess::Mime;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+use std::io::Cursor;
+use utoipa::ToSchema;
+
+/// Settings of a workspace, changed by its owner.
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct WorkspaceSettings {
+    pub storage: StoragePolicy,
+}
+
+/// Limits on the files of a workspace, on top of the server's.
+#[derive(Debug, Clone, Default, ToSchema, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct StoragePolicy {
+    /// Total size of its files in bytes, the same content uploaded twice counts once.
+    pub quota_bytes: Option<u64>,
+    /// Size of a file in bytes.
+    pub max_file_size: Option<u64>,
+    /// Types of files which may be uploaded, e.g. `application/pdf` or `image/*`. Any when empty.
+    pub allowed_mime_types: Vec<String>,
+    /// Extensions of files which may not be uploaded, without the dot.
+    pub blocked_extensions: Vec<String>,
+}
+
+/// What the files of a workspace take.
+#[derive(Debug, Clone, ToSchema, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct StorageUsage {
+    pub files: i64,
+    /// Size of its files, each content once, thumbnails aside. This is what the quota limits.
+    pub bytes: i64,
+    /// Size of its files once per user who uploaded them, what they'd take without deduplication.
+    pub uploaded_bytes: i64,
+    /// Size of the resumable uploads in progress, which count towards the quota until they
+    /// complete or expire.
+    pub pending_bytes: i64,
+    #[sqlx(skip)]
+    pub quota_bytes: Option<u64>,
+}
+
+impl StorageUsage {
+    /// Bytes counted towards the quota.
+    pub fn used(&self) -> u64 {
+        (self.bytes + self.pending_bytes) as u64
+    }
+}
+
+impl StoragePolicy {
+    /// Check a file named `name` may be uploaded, its type is guessed from the name.
+    pub fn check_name(&self, name: &str) -> Result<(), AppError> {
+        if let Some((_, ext)) = name.rsplit_once('.') {
+            if self
+                .blocked_extensions
+                .iter()
+                .any(|blocked| blocked.eq_ignore_ascii_case(ext))
+            {
+                return Err(AppError::UnsupportedFileType(format!(
+                    ".{ext} files aren't allowed"
+                )));
+            }
+        }
+        if self.allowed_mime_types.is_empty() {
+            return Ok(());
+        }
+        let mime = mime_guess::from_path(name).first_or_octet_stream();
+        if !self.allows(&mime) {
+            return Err(AppError::UnsupportedFileType(format!(
+                "{mime} files aren't allowed, only {}",
+                self.allowed_mime_types.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check the content of a file named `name` starting with `head` is of an allowed type, so a
+    /// restricted workspace can't be sent anything under an allowed name.
+    pub fn check_content(&self, name: &str, head: &[u8]) -> Result<(), AppError> {
+        if self.allowed_mime_types.is_empty() {
+            return Ok(());
+        }
+        match sniff(head) {
+            Some(mime) if !self.allows(&mime) => Err(AppError::UnsupportedFileType(format!(
+                "{mime} files aren't allowed, only {}",
+                self.allowed_mime_types.join(", ")
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                // types we recognize must be recognized, the others are left to the name
+                let mime = mime_guess::from_path(name).first_or_octet_stream();
+                let sniffable = ImageFormat::from_mime_type(mime.essence_str()).is_some()
+                    || MAGIC.iter().any(|(_, magic)| *magic == mime.essence_str());
+                if sniffable {
+                    return Err(AppError::UnsupportedFileType(format!(
+                        "the content isn't {mime}"
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn allows(&self, mime: &Mime) -> bool {
+        self.allowed_mime_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(kind) => mime.type_() == kind,
+                None => mime.essence_str() == allowed,
+            })
+    }
+
+    /// Max size of a file, `server_max` at most.
+    pub fn max_file_size(&self, server_max: u64) -> u64 {
+        self.max_file_size
+            .map_or(server_max, |max| max.min(server_max))
+    }
+
+    /// Check `size` more bytes fit in the quota, with `used` bytes stored or pending.
+    pub fn check_quota(&self, used: u64, size: u64) -> Result<(), AppError> {
+        match self.quota_bytes {
+            Some(quota) if used + size > quota => Err(AppError::QuotaExceeded(format!(
+                "{used} of {quota} bytes used, the file is {size} bytes"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lowercase extensions without their dot, and check the types are `type/subtype` or
+    /// `type/*`.
+    fn normalize(&mut self) -> Result<(), AppError> {
+        for ext in self.blocked_extensions.iter_mut() {
+            *ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+        }
+        self.blocked_extensions.retain(|ext| !ext.is_empty());
+        for mime in self.allowed_mime_types.iter_mut() {
+            *mime = mime.trim().to_ascii_lowercase();
+            let valid = match mime.split_once('/') {
+                Some((kind, subtype)) => {
+                    !kind.is_empty() && !subtype.is_empty() && !subtype.contains('/')
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(AppError::UpdateWorkspaceError(format!(
+                    "invalid mime type: {mime}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Signatures of the types which aren't images, see [`sniff`].
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"MZ", "application/x-msdownload"),
+];
+
+/// Type of content starting with `head`, from its signature, if known.
+fn sniff(head: &[u8]) -> Option<Mime> {
+    let mime = match ImageReader::new(Cursor::new(head))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.format())
+    {
+        Some(format) => format.to_mime_type(),
+        None => MAGIC
+            .iter()
+            .find(|(magic, _)| head.starts_with(magic))
+            .map(|(_, mime)| *mime)?,
+    };
+    mime.parse().ok()
+}
 
 pub struct WorkspaceRepo;
 
@@ -75,6 +253,70 @@ impl WorkspaceRepo {
         Ok(ws)
     }
 
+    pub async fn settings<'e>(
+        id: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<WorkspaceSettings, AppError> {
+        let settings: Option<(Json<WorkspaceSettings>,)> =
+            sqlx::query_as("SELECT settings FROM workspaces WHERE id = $1")
+                .bind(id as i64)
+                .fetch_optional(executor)
+                .await?;
+        let Some((Json(settings),)) = settings else {
+            return Err(AppError::NotFound(format!("workspace id {id}")));
+        };
+        Ok(settings)
+    }
+
+    pub async fn update_settings(
+        id: u64,
+        mut settings: WorkspaceSettings,
+        pool: &PgPool,
+    ) -> Result<WorkspaceSettings, AppError> {
+        settings.storage.normalize()?;
+        sqlx::query("UPDATE workspaces SET settings = $2 WHERE id = $1")
+            .bind(id as i64)
+            .bind(Json(&settings))
+            .execute(pool)
+            .await?;
+        Ok(settings)
+    }
+
+    /// What the files of workspace `id` take, deduplicated content counted once, and its uploads
+    /// in progress.
+    pub async fn storage_usage<'e>(
+        id: u64,
+        executor: impl PgExecutor<'e>,
+    ) -> Result<StorageUsage, AppError> {
+        let usage = sqlx::query_as(
+            r#"
+        SELECT
+          count(*) AS files,
+          coalesce(sum(size), 0)::bigint AS bytes,
+          coalesce(sum(size * (SELECT count(*) FROM file_owners o WHERE o.file_id = f.id)), 0)::bigint
+            AS uploaded_bytes,
+          (SELECT coalesce(sum(size), 0)::bigint FROM upload_sessions u
+            WHERE u.ws_id = $1 AND u.expires_at > now()) AS pending_bytes
+        FROM files f
+        WHERE ws_id = $1
+        "#,
+        )
+        .bind(id as i64)
+        .fetch_one(executor)
+        .await?;
+        Ok(usage)
+    }
+
+    /// Hold the storage of workspace `id` until the end of the transaction, so its quota is
+    /// checked against what's stored one upload at a time. Taken before any file's lock.
+    pub async fn lock_storage(id: u64, tx: &mut PgConnection) -> Result<(), AppError> {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+            .bind(format!("workspace/{id}"))
+            .execute(tx)
+            .await?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub async fn fetch_all_chat_users(id: u64, pool: &PgPool) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
@@ -142,4 +384,66 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn workspace_settings_should_be_normalized() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        assert_eq!(
+            WorkspaceRepo::settings(1, &pool).await?,
+            WorkspaceSettings::default()
+        );
+
+        let mut settings = WorkspaceSettings::default();
+        settings.storage.quota_bytes = Some(1024);
+        settings.storage.blocked_extensions = vec![".EXE".to_string(), " ".to_string()];
+        settings.storage.allowed_mime_types = vec!["Image/*".to_string()];
+        let settings = WorkspaceRepo::update_settings(1, settings, &pool).await?;
+        assert_eq!(settings.storage.blocked_extensions, ["exe"]);
+        assert_eq!(settings.storage.allowed_mime_types, ["image/*"]);
+        assert_eq!(WorkspaceRepo::settings(1, &pool).await?, settings);
+
+        let mut invalid = settings.clone();
+        invalid.storage.allowed_mime_types = vec!["image".to_string()];
+        assert!(WorkspaceRepo::update_settings(1, invalid, &pool)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn storage_policy_should_check_files() {
+        let policy = StoragePolicy {
+            quota_bytes: Some(100),
+            max_file_size: Some(50),
+            allowed_mime_types: vec!["image/*".to_string(), "application/pdf".to_string()],
+            blocked_extensions: vec!["svg".to_string()],
+        };
+        assert!(policy.check_name("cat.PNG").is_ok());
+        assert!(policy.check_name("report.pdf").is_ok());
+        assert!(policy.check_name("logo.SVG").is_err());
+        assert!(policy.check_name("notes.txt").is_err());
+        assert!(policy.check_name("README").is_err());
+
+        let png = [
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0, 0, 0, 0,
+        ];
+        assert!(policy.check_content("cat.png", &png).is_ok());
+        assert!(policy.check_content("report.pdf", b"%PDF-1.7\n").is_ok());
+        assert!(policy.check_content("cat.png", b"MZ\x90\x00").is_err());
+        assert!(policy.check_content("cat.png", b"hello").is_err());
+        assert!(policy.check_content("report.pdf", b"hello").is_err());
+        assert!(StoragePolicy::default()
+            .check_content("cat.png", b"MZ\x90\x00")
+            .is_ok());
+
+        assert_eq!(policy.max_file_size(1000), 50);
+        assert_eq!(policy.max_file_size(10), 10);
+        assert_eq!(StoragePolicy::default().max_file_size(1000), 1000);
+
+        assert!(policy.check_quota(60, 40).is_ok());
+        assert!(policy.check_quota(60, 41).is_err());
+        assert!(StoragePolicy::default()
+            .check_quota(u64::MAX / 2, 1)
+            .is_ok());
+    }
 }
@@ -1,11 +1,17 @@
+use crate::file_url::{SignFile, SignedFileUrl};
 use crate::handlers::*;
 use crate::ErrorOutput;
 use crate::{
-    models::{CreateUser, SigninUser},
+    models::{
+        ApiToken, Attachment, AttachmentField, CreateApiToken, CreateBot, CreateIncomingWebhook,
+        CreateSlashCommand, CreateUpload, CreateUser, CreateWebhook, DeliveryStatus,
+        IncomingMessage, IncomingWebhook, SigninUser, SlashCommand, StoragePolicy, StorageUsage,
+        StreamTicket, UploadSession, Webhook, WebhookDelivery, WorkspaceSettings,
+    },
     AppState,
 };
 use axum::Router;
-use chat_core::{Chat, ChatType};
+use chat_core::{Chat, ChatType, CommandReply, Presence, PresenceStatus, Scope};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
     Modify, OpenApi, ToSchema,
@@ -29,17 +35,51 @@ pub(crate) struct FileForm {
     paths(
         signup_handler,
         signin_handler,
+        create_stream_ticket_handler,
         list_chat_handler,
         create_chat_handler,
         get_chat_handler,
+        list_muted_chat_handler,
         upload_handler,
+        create_upload_handler,
+        get_upload_handler,
+        patch_upload_handler,
+        complete_upload_handler,
+        cancel_upload_handler,
+        sign_file_handler,
+        signed_file_handler,
+        list_presence_handler,
+        get_workspace_settings_handler,
+        update_workspace_settings_handler,
+        get_storage_usage_handler,
+        list_webhook_handler,
+        create_webhook_handler,
+        delete_webhook_handler,
+        list_webhook_delivery_handler,
+        list_incoming_webhook_handler,
+        create_incoming_webhook_handler,
+        delete_incoming_webhook_handler,
+        post_incoming_webhook_handler,
+        list_bot_handler,
+        create_bot_handler,
+        list_api_token_handler,
+        create_api_token_handler,
+        delete_api_token_handler,
+        list_command_handler,
+        create_command_handler,
+        delete_command_handler,
     ),
     components(
-        schemas(Chat, ChatType, SigninUser, CreateUser, AuthOutput, ErrorOutput, FileForm),
+        schemas(Chat, ChatType, Presence, PresenceStatus, SigninUser, CreateUser, AuthOutput, StreamTicket, ErrorOutput, FileForm, Webhook, CreateWebhook, WebhookDelivery, DeliveryStatus, IncomingWebhook, CreateIncomingWebhook, IncomingMessage, Attachment, AttachmentField, ApiToken, CreateApiToken, CreateBot, Scope, SlashCommand, CreateSlashCommand, CommandReply, UploadSession, CreateUpload, SignFile, SignedFileUrl, WorkspaceSettings, StoragePolicy, StorageUsage),
     ),
     modifiers(&SecurityAddon),
     tags(
         (name = "chat", description = "Chat related operations"),
+        (name = "presence", description = "Presence of workspace users"),
+        (name = "workspace", description = "Workspace settings and storage usage"),
+        (name = "webhook", description = "Outgoing and incoming webhooks"),
+        (name = "token", description = "Bot users and scoped API tokens"),
+        (name = "command", description = "Slash commands"),
     )
 )]
 pub struct ApiDoc;
@@ -0,0 +1,178 @@
+//! Requests to the urls users register, for outgoing webhooks and slash commands.
+//!
+//! Such a url could point at services only the server reaches, the cloud metadata endpoint
+//! among them. The addresses its host resolves to must be public when it's registered, and
+//! again on each request since DNS answers change. The client resolves hosts to public
+//! addresses only and doesn't follow redirects, so a check can't be bypassed between the two.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{redirect, Url};
+use tokio::net::lookup_host;
+
+use crate::OutboundConfig;
+
+impl OutboundConfig {
+    /// Parse `url`, checking it's http(s) and its host resolves to public addresses only.
+    pub(crate) async fn check_url(&self, url: &str) -> Result<Url, String> {
+        let parsed = match Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+            _ => return Err(format!("invalid url: {url}")),
+        };
+        let Some(host) = parsed.host_str() else {
+            return Err(format!("invalid url: {url}"));
+        };
+        if self.allow_private_networks {
+            return Ok(parsed);
+        }
+        // ipv6 hosts are bracketed in urls
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = lookup_host((host, port))
+            .await
+            .map_err(|e| format!("can't resolve {host}: {e}"))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("can't resolve {host}"));
+        }
+        if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+            return Err(format!(
+                "{host} resolves to a non-public address {}",
+                addr.ip()
+            ));
+        }
+        Ok(parsed)
+    }
+
+    /// A client for the registered urls.
+    pub(crate) fn client(&self) -> reqwest::Client {
+        let builder = reqwest::Client::builder().redirect(redirect::Policy::none());
+        let builder = if self.allow_private_networks {
+            builder
+        } else {
+            builder.dns_resolver(Arc::new(PublicResolver))
+        };
+        builder.build().expect("client config should be valid")
+    }
+}
+
+/// Resolves hosts to their public addresses, failing if they have none.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether `ip` is reachable on the internet, not loopback, private, link-local and the like.
+pub(crate) fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        // 169.254.0.0/16, the metadata endpoint included
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // 0.0.0.0/8
+        || a == 0
+        // shared address space, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking, 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local, fc00::/7
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link-local, fe80::/10, and the deprecated site-local, fec0::/10
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] & 0xffc0) == 0xfec0
+        // documentation, 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // ipv4-compatible and NAT64, which embed an ipv4 address
+        || (segments[..6] == [0; 6])
+        || (segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_should_be_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip} shouldn't be public");
+        }
+        for ip in ["93.184.215.14", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip} should be public");
+        }
+    }
+
+    #[tokio::test]
+    async fn urls_to_private_networks_should_be_rejected() {
+        let config = OutboundConfig::default();
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "ftp://93.184.215.14/hook",
+        ] {
+            assert!(config.check_url(url).await.is_err(), "{url} should fail");
+        }
+        assert!(config.check_url("https://93.184.215.14/hook").await.is_ok());
+
+        let trusted = OutboundConfig {
+            allow_private_networks: true,
+        };
+        assert!(trusted
+            .check_url("http://127.0.0.1:8080/hook")
+            .await
+            .is_ok());
+    }
+}
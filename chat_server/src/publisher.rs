@@ -0,0 +1,83 @@
+use chat_core::{
+    AppEvent, InMemoryPubSub, Notification, PgPublisher, Publisher, APP_EVENT_CHANNEL,
+};
+#[cfg(feature = "amqp")]
+use chat_core::{RabbitMqPubSub, CHAT_EXCHANGE};
+use sqlx::PgPool;
+use tracing::warn;
+
+use crate::config::PublisherConfig;
+use crate::AppError;
+
+/// Publishes domain events once the change they describe has been committed.
+pub enum EventPublisher {
+    /// The database triggers notify listeners, nothing to publish.
+    Trigger,
+    Postgres(PgPublisher),
+    #[cfg(feature = "amqp")]
+    Amqp(RabbitMqPubSub),
+    InMemory(InMemoryPubSub),
+}
+
+impl EventPublisher {
+    /// Create the publisher selected by `config`, postgres events go through `pool`.
+    pub async fn try_new(config: &PublisherConfig, pool: &PgPool) -> Result<Self, AppError> {
+        let publisher = match config {
+            PublisherConfig::Trigger => Self::Trigger,
+            PublisherConfig::Postgres => Self::Postgres(PgPublisher::with_pool(pool.clone())),
+            #[cfg(feature = "amqp")]
+            PublisherConfig::Amqp { url } => Self::Amqp(RabbitMqPubSub::connect(url).await?),
+            #[cfg(not(feature = "amqp"))]
+            PublisherConfig::Amqp { .. } => {
+                return Err(anyhow::anyhow!(
+                    "amqp publisher requires chat-server to be built with the `amqp` feature"
+                )
+                .into())
+            }
+        };
+
+        Ok(publisher)
+    }
+
+    /// Whether the database triggers are expected to notify listeners.
+    pub fn is_trigger(&self) -> bool {
+        matches!(self, Self::Trigger)
+    }
+
+    /// Publish `event` to `user_ids`.
+    ///
+    /// The change is already committed by then, so a failure is logged rather than failing the
+    /// request.
+    pub async fn publish(&self, user_ids: impl IntoIterator<Item = i64>, event: AppEvent) {
+        let notification = Notification::new(user_ids, event);
+        let ret = match self {
+            Self::Trigger => Ok(()),
+            Self::Postgres(publisher) => publisher.publish(APP_EVENT_CHANNEL, notification).await,
+            #[cfg(feature = "amqp")]
+            Self::Amqp(publisher) => publisher.publish(CHAT_EXCHANGE, notification).await,
+            Self::InMemory(publisher) => publisher.publish("", notification).await,
+        };
+
+        if let Err(e) = ret {
+            warn!("failed to publish event: {:?}", e);
+        }
+    }
+
+    /// Publish an event no database trigger covers, e.g. a command reply, even in trigger mode.
+    pub async fn publish_relayed(
+        &self,
+        pool: &PgPool,
+        user_ids: impl IntoIterator<Item = i64>,
+        event: AppEvent,
+    ) {
+        if !self.is_trigger() {
+            return self.publish(user_ids, event).await;
+        }
+
+        let notification = Notification::new(user_ids, event);
+        let publisher = PgPublisher::with_pool(pool.clone());
+        if let Err(e) = publisher.publish(APP_EVENT_CHANNEL, notification).await {
+            warn!("failed to publish event: {:?}", e);
+        }
+    }
+}
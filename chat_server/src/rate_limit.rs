@@ -0,0 +1,58 @@
+//! Fixed-window rate limiting, per key and per process.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub(crate) struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    hits: Mutex<HashMap<i64, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            hits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a hit of `key`, returning whether it is within the limit.
+    pub(crate) fn check(&self, key: i64) -> bool {
+        let now = Instant::now();
+        let mut hits = self.hits.lock().expect("poisoned lock");
+        // forget the keys whose window is over, so the map only holds active ones
+        if hits.len() > 1024 {
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+        let (start, count) = hits.entry(key).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_should_limit_each_key_per_window() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(50));
+        assert!(limiter.check(1));
+        assert!(limiter.check(1));
+        assert!(!limiter.check(1));
+        assert!(limiter.check(2));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(1));
+    }
+}
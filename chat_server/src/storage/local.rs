@@ -0,0 +1,206 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use axum::body::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, Storage, StoredObject};
+use crate::AppError;
+
+/// Files kept under a directory of the local disk.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    base_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(base_dir: PathBuf) -> Self {
+        Self { base_dir }
+    }
+
+    /// Where `key` is kept, keys climbing out of the base dir are refused.
+    fn path(&self, key: &str) -> Result<PathBuf, AppError> {
+        let key = Path::new(key);
+        if !key.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(AppError::StorageError(format!(
+                "invalid key: {}",
+                key.display()
+            )));
+        }
+        Ok(self.base_dir.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+
+    /// An atomic rename, `path` must be on the same filesystem.
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        let dest = self.path(key)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(path, dest).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ByteStream>, AppError> {
+        match fs::File::open(self.path(key)?).await {
+            Ok(file) => Ok(Some(ReaderStream::new(file).boxed())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Option<ByteStream>, AppError> {
+        let mut file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let reader = file.take(range.end - range.start);
+        Ok(Some(ReaderStream::new(reader).boxed()))
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match fs::metadata(self.path(key)?).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(fs::try_exists(self.path(key)?).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whatever is under the base dir, a directory at a time.
+    fn list(&self) -> BoxStream<'static, Result<StoredObject, AppError>> {
+        let base_dir = self.base_dir.clone();
+        stream::try_unfold(vec![base_dir.clone()], move |mut dirs| {
+            let base_dir = base_dir.clone();
+            async move {
+                let Some(dir) = dirs.pop() else {
+                    return Ok::<_, AppError>(None);
+                };
+                let mut objects = Vec::new();
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some((objects, dirs))),
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let metadata = entry.metadata().await?;
+                    let path = entry.path();
+                    if metadata.is_dir() {
+                        dirs.push(path);
+                        continue;
+                    }
+                    // keys are utf-8, anything else isn't one
+                    let key = path.strip_prefix(&base_dir).ok().and_then(|key| {
+                        let parts: Option<Vec<_>> =
+                            key.components().map(|c| c.as_os_str().to_str()).collect();
+                        Some(parts?.join("/"))
+                    });
+                    if let (Some(key), true) = (key, metadata.is_file()) {
+                        objects.push(StoredObject {
+                            key,
+                            size: metadata.len(),
+                            modified: metadata.modified()?.into(),
+                        });
+                    }
+                }
+                Ok(Some((objects, dirs)))
+            }
+        })
+        .map_ok(|objects| stream::iter(objects.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn local_storage_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat-{}", uuid::Uuid::now_v7()));
+        let storage = LocalStorage::new(base_dir.clone());
+        let key = "1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt";
+
+        assert!(!storage.exists(key).await?);
+        assert!(storage.get(key).await?.is_none());
+        storage.put(key, Bytes::from_static(b"hello")).await?;
+        assert!(storage.exists(key).await?);
+
+        let stream = storage.get(key).await?.expect("file should exist");
+        let chunks: Vec<_> = stream.collect().await;
+        let data: Vec<u8> = chunks.into_iter().collect::<Result<Vec<_>, _>>()?.concat();
+        assert_eq!(data, b"hello");
+
+        assert_eq!(storage.size(key).await?, Some(5));
+        let stream = storage
+            .get_range(key, 1..4)
+            .await?
+            .expect("file should exist");
+        let chunks: Vec<_> = stream.collect().await;
+        let data: Vec<u8> = chunks.into_iter().collect::<Result<Vec<_>, _>>()?.concat();
+        assert_eq!(data, b"ell");
+
+        storage.delete(key).await?;
+        storage.delete(key).await?;
+        assert!(!storage.exists(key).await?);
+
+        let tmp = base_dir.join("upload");
+        std::fs::write(&tmp, b"hello")?;
+        storage.put_file(key, &tmp).await?;
+        assert!(storage.exists(key).await?);
+        assert!(!tmp.exists());
+
+        let objects: Vec<_> = storage.list().try_collect().await?;
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].key, key);
+        assert_eq!(objects[0].size, 5);
+
+        std::fs::remove_dir_all(base_dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keys_out_of_the_base_dir_should_be_refused() {
+        let storage = LocalStorage::new(std::env::temp_dir().join("chat-traversal"));
+        for key in ["../etc/passwd", "1/../../etc/passwd", "/etc/passwd", "./x"] {
+            assert!(
+                storage.exists(key).await.is_err(),
+                "{key} should be refused"
+            );
+            assert!(storage.get(key).await.is_err(), "{key} should be refused");
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Where uploaded files are kept, addressed by their [`ChatFile`](crate::models::ChatFile) key.
+mod local;
+mod s3;
+
+use std::future::Future;
+use std::ops::Range;
+use std::path::Path;
+
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+
+use crate::config::{ServerConfig, StorageConfig};
+use crate::AppError;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+/// The content of a stored file.
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// A file in the storage, as listed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredObject {
+    pub key: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// A store of files by key, e.g. `1/339/807/e635afbeab088ce33206fdf4223a6bb156.png`.
+pub trait Storage {
+    /// Store `data` at `key`, replacing what's there.
+    fn put(&self, key: &str, data: Bytes) -> impl Future<Output = Result<(), AppError>> + Send;
+    /// Move the local file at `path` to `key`, without reading it into memory.
+    fn put_file(&self, key: &str, path: &Path)
+        -> impl Future<Output = Result<(), AppError>> + Send;
+    /// Stream the file at `key`, if any.
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<ByteStream>, AppError>> + Send;
+    /// Stream `range` of the file at `key`, if any. The range must be within the file.
+    fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> impl Future<Output = Result<Option<ByteStream>, AppError>> + Send;
+    fn exists(&self, key: &str) -> impl Future<Output = Result<bool, AppError>> + Send;
+    /// Size in bytes of the file at `key`, if any.
+    fn size(&self, key: &str) -> impl Future<Output = Result<Option<u64>, AppError>> + Send;
+    /// Remove the file at `key`, whether or not it exists.
+    fn delete(&self, key: &str) -> impl Future<Output = Result<(), AppError>> + Send;
+    /// Every stored file, in no particular order.
+    fn list(&self) -> BoxStream<'static, Result<StoredObject, AppError>>;
+}
+
+/// The storage selected in the config.
+#[derive(Debug, Clone)]
+pub enum FileStorage {
+    Local(LocalStorage),
+    S3(S3Storage),
+}
+
+impl FileStorage {
+    /// Create the storage selected by `config`, local files are kept in `server.base_dir`.
+    pub fn try_new(config: &StorageConfig, server: &ServerConfig) -> Result<Self, AppError> {
+        let storage = match config {
+            StorageConfig::Local => Self::Local(LocalStorage::new(server.base_dir.clone())),
+            StorageConfig::S3(config) => Self::S3(S3Storage::try_new(config.clone())?),
+        };
+        Ok(storage)
+    }
+}
+
+impl Storage for FileStorage {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        match self {
+            Self::Local(storage) => storage.put(key, data).await,
+            Self::S3(storage) => storage.put(key, data).await,
+        }
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        match self {
+            Self::Local(storage) => storage.put_file(key, path).await,
+            Self::S3(storage) => storage.put_file(key, path).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<ByteStream>, AppError> {
+        match self {
+            Self::Local(storage) => storage.get(key).await,
+            Self::S3(storage) => storage.get(key).await,
+        }
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        range: Range<u64>,
+    ) -> Result<Option<ByteStream>, AppError> {
+        match self {
+            Self::Local(storage) => storage.get_range(key, range).await,
+            Self::S3(storage) => storage.get_range(key, range).await,
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match self {
+            Self::Local(storage) => storage.size(key).await,
+            Self::S3(storage) => storage.size(key).await,
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        match self {
+            Self::Local(storage) => storage.exists(key).await,
+            Self::S3(storage) => storage.exists(key).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match self {
+            Self::Local(storage) => storage.delete(key).await,
+            Self::S3(storage) => storage.delete(key).await,
+        }
+    }
+
+    fn list(&self) -> BoxStream<'static, Result<StoredObject, AppError>> {
+        match self {
+            Self::Local(storage) => storage.list(),
+            Self::S3(storage) => storage.list(),
+        }
+    }
+}
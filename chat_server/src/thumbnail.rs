@@ -0,0 +1,167 @@
+//! Thumbnails of uploaded images.
+//!
+//! Thumbnails are re-encoded from the decoded pixels, so none of the original's metadata, EXIF
+//! location included, makes it into them. The EXIF orientation is applied first, for photos to
+//! be shown the right way up.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use image::{
+    codecs::jpeg::JpegEncoder, DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader,
+    Limits,
+};
+
+use crate::models::ThumbnailMeta;
+
+/// Images larger than this, in either dimension, aren't decoded.
+const MAX_DIMENSION: u32 = 16_384;
+/// Memory a decoded image may take.
+const MAX_ALLOC: u64 = 512 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug)]
+pub(crate) struct Thumbnails {
+    pub width: u32,
+    pub height: u32,
+    /// Each thumbnail, with its encoded content.
+    pub thumbnails: Vec<(ThumbnailMeta, Vec<u8>)>,
+}
+
+/// Scale the image at `path` down to fit each of `sizes`. `None` if it isn't an image of a
+/// supported format. This decodes the whole image, run it on a blocking thread.
+pub(crate) fn generate(path: &Path, sizes: &[u32]) -> Result<Option<Thumbnails>, ImageError> {
+    let mut reader = ImageReader::open(path)?.with_guessed_format()?;
+    if reader.format().is_none() {
+        return Ok(None);
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_ALLOC);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let (width, height) = (image.width(), image.height());
+    let mut thumbnails = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        // never scaled up
+        if width <= size && height <= size {
+            continue;
+        }
+        let thumbnail = image.thumbnail(size, size);
+        let (ext, data) = encode(&thumbnail)?;
+        let meta = ThumbnailMeta {
+            size,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            ext: ext.to_string(),
+        };
+        thumbnails.push((meta, data));
+    }
+    Ok(Some(Thumbnails {
+        width,
+        height,
+        thumbnails,
+    }))
+}
+
+/// PNG if it has transparency, JPEG otherwise.
+fn encode(image: &DynamicImage) -> Result<(&'static str, Vec<u8>), ImageError> {
+    let mut data = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut data, ImageFormat::Png)?;
+        return Ok(("png", data.into_inner()));
+    }
+    let encoder = JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY);
+    image.to_rgb8().write_with_encoder(encoder)?;
+    Ok(("jpg", data.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use image::{ImageBuffer, Rgb, Rgba};
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("chat-{}-{name}", uuid::Uuid::now_v7()))
+    }
+
+    #[test]
+    fn thumbnails_should_fit_their_size() -> Result<()> {
+        let path = temp_file("photo.jpg");
+        let photo = ImageBuffer::from_pixel(800, 400, Rgb([200u8, 10, 10]));
+        photo.save_with_format(&path, ImageFormat::Jpeg)?;
+
+        let ret = generate(&path, &[160, 640, 1024])?.expect("photo should be an image");
+        assert_eq!((ret.width, ret.height), (800, 400));
+        let sizes: Vec<_> = ret.thumbnails.iter().map(|(t, _)| t.size).collect();
+        assert_eq!(sizes, [160, 640]);
+        let (meta, data) = &ret.thumbnails[0];
+        assert_eq!((meta.width, meta.height), (160, 80));
+        assert_eq!(meta.ext, "jpg");
+        assert_eq!(image::guess_format(data)?, ImageFormat::Jpeg);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn transparent_images_should_get_png_thumbnails() -> Result<()> {
+        let path = temp_file("sticker.png");
+        let sticker = ImageBuffer::from_pixel(300, 300, Rgba([0u8, 0, 0, 0]));
+        sticker.save_with_format(&path, ImageFormat::Png)?;
+
+        let ret = generate(&path, &[160])?.expect("sticker should be an image");
+        let (meta, data) = &ret.thumbnails[0];
+        assert_eq!(meta.ext, "png");
+        assert_eq!(image::load_from_memory(data)?.width(), 160);
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn exif_should_be_applied_and_stripped() -> Result<()> {
+        let path = temp_file("rotated.jpg");
+        let photo = ImageBuffer::from_pixel(400, 200, Rgb([10u8, 200, 10]));
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg).encode_image(&photo)?;
+        // an APP1 segment right after SOI, with orientation "rotate 90"
+        let exif: &[u8] = &[
+            b'E', b'x', b'i', b'f', 0, 0, // header
+            b'M', b'M', 0, 42, 0, 0, 0, 8, // big endian TIFF, IFD at 8
+            0, 1, // 1 entry
+            0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // orientation = 6
+            0, 0, 0, 0, // no next IFD
+        ];
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(exif);
+        data.extend_from_slice(&jpeg[2..]);
+        std::fs::write(&path, &data)?;
+
+        let ret = generate(&path, &[100])?.expect("photo should be an image");
+        assert_eq!((ret.width, ret.height), (200, 400));
+        let (meta, data) = &ret.thumbnails[0];
+        assert_eq!((meta.width, meta.height), (50, 100));
+        assert!(!data.windows(4).any(|w| w == b"Exif"));
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn other_files_should_be_skipped() -> Result<()> {
+        let path = temp_file("notes.txt");
+        std::fs::write(&path, "not an image")?;
+        assert!(generate(&path, &[160])?.is_none());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+//! Delivery of workspace events to outgoing webhooks.
+//!
+//! One instance at a time, holding a postgres advisory lock, follows the notifications
+//! [`PgSubscriber`] receives and queues a delivery per matching webhook, as long as its creator
+//! can still see the chat. Every instance then delivers due ones, signing the body with the
+//! webhook secret and retrying failures with exponential backoff. Deliveries are kept as the
+//! webhook's log.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chat_core::{AppEvent, Notification, PgSubscriber};
+use chrono::Utc;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{Connection, FromRow, PgConnection, PgPool};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::models::WEBHOOK_EVENTS;
+use crate::{AppError, OutboundConfig};
+
+/// Advisory lock held by the instance queueing deliveries.
+const LEADER_LOCK: i64 = 0x7765_6268_6f6f_6b73;
+/// How often other instances check whether the leader is gone.
+const LEADER_RETRY: Duration = Duration::from_secs(10);
+/// How often due deliveries are looked for, besides when some are queued here.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Deliveries claimed at once.
+const BATCH_SIZE: i64 = 20;
+/// A claimed delivery isn't retried by another instance before this, unless it's finished.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(60);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries failing this many times are given up.
+pub const MAX_ATTEMPTS: i32 = 8;
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+pub const EVENT_HEADER: &str = "x-chat-event";
+pub const DELIVERY_HEADER: &str = "x-chat-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-chat-timestamp";
+/// `sha256=` followed by the hex HMAC-SHA256 of `{timestamp}.{body}`, keyed with the secret.
+pub const SIGNATURE_HEADER: &str = "x-chat-signature";
+
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    db_url: String,
+    pool: PgPool,
+    outbound: OutboundConfig,
+    client: reqwest::Client,
+    /// Wakes the delivery loop when deliveries are queued.
+    queued: Arc<Notify>,
+}
+
+#[derive(Debug, FromRow)]
+struct DueDelivery {
+    id: i64,
+    event: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+impl WebhookDispatcher {
+    pub fn new(db_url: impl Into<String>, pool: PgPool, outbound: OutboundConfig) -> Self {
+        Self {
+            db_url: db_url.into(),
+            pool,
+            client: outbound.client(),
+            outbound,
+            queued: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queue and deliver events until the process exits.
+    pub async fn run(self) {
+        tokio::join!(self.follow_events(), self.deliver());
+    }
+
+    /// Queue deliveries of received events while holding the leader lock.
+    async fn follow_events(&self) {
+        loop {
+            if let Err(e) = self.lead().await {
+                warn!("failed to follow events for webhooks: {:?}", e);
+            }
+            sleep(LEADER_RETRY).await;
+        }
+    }
+
+    async fn lead(&self) -> Result<(), AppError> {
+        // a dedicated connection, so the lock goes away with it
+        let mut conn = PgConnection::connect(&self.db_url).await?;
+        let (leader,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+            .bind(LEADER_LOCK)
+            .fetch_one(&mut conn)
+            .await?;
+        if !leader {
+            return Ok(());
+        }
+
+        info!("queueing webhook deliveries");
+        let subscriber = PgSubscriber::new(&self.db_url).await?;
+        let mut notifications = Box::pin(subscriber.notifications());
+        while let Some(notification) = notifications.next().await {
+            if let Err(e) = self.enqueue(&notification).await {
+                warn!("failed to queue webhook deliveries: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a delivery of the event to every webhook of its workspace subscribed to it, whose
+    /// creator is still a member of the chat, or still the owner for webhooks of every chat.
+    pub async fn enqueue(&self, notification: &Notification) -> Result<u64, AppError> {
+        let event = &notification.event;
+        let name = event.name();
+        if !WEBHOOK_EVENTS.contains(&name) {
+            return Ok(0);
+        }
+        let (ws_id, chat_id) = match event {
+            AppEvent::NewChat(chat)
+            | AppEvent::AddToChat(chat)
+            | AppEvent::RemoveFromChat(chat) => (chat.ws_id, chat.id),
+            AppEvent::NewMessage(message) => {
+                let ws_id: Option<(i64,)> = sqlx::query_as("SELECT ws_id FROM chats WHERE id = $1")
+                    .bind(message.chat_id)
+                    .fetch_optional(&self.pool)
+                    .await?;
+                match ws_id {
+                    Some((ws_id,)) => (ws_id, message.chat_id),
+                    None => return Ok(0),
+                }
+            }
+            _ => return Ok(0),
+        };
+
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT w.id, $2, $3
+            FROM webhooks w
+            WHERE w.ws_id = $1
+              AND (cardinality(w.events) = 0 OR $2 = ANY(w.events))
+              AND CASE
+                WHEN cardinality(w.chat_ids) = 0 THEN EXISTS (
+                  SELECT 1 FROM workspaces ws WHERE ws.id = w.ws_id AND ws.owner_id = w.created_by
+                )
+                ELSE $4 = ANY(w.chat_ids) AND EXISTS (
+                  SELECT 1 FROM chats c WHERE c.id = $4 AND w.created_by = ANY(c.members)
+                )
+              END
+            "#,
+        )
+        .bind(ws_id)
+        .bind(name)
+        .bind(serde_json::to_value(event).map_err(anyhow::Error::from)?)
+        .bind(chat_id)
+        .execute(&self.pool)
+        .await?;
+
+        let queued = ret.rows_affected();
+        if queued > 0 {
+            self.queued.notify_one();
+        }
+        Ok(queued)
+    }
+
+    async fn deliver(&self) {
+        loop {
+            let delivered = match self.deliver_due().await {
+                Ok(delivered) => delivered,
+                Err(e) => {
+                    warn!("failed to deliver webhooks: {:?}", e);
+                    0
+                }
+            };
+            if delivered < BATCH_SIZE as usize {
+                tokio::select! {
+                    _ = self.queued.notified() => {},
+                    _ = sleep(POLL_INTERVAL) => {},
+                }
+            }
+        }
+    }
+
+    /// Attempt a batch of due deliveries, returning how many were attempted.
+    pub async fn deliver_due(&self) -> Result<usize, AppError> {
+        let due: Vec<DueDelivery> = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries d
+            SET attempts = d.attempts + 1, next_attempt_at = now() + $2::interval
+            FROM webhooks w
+            WHERE w.id = d.webhook_id AND d.id IN (
+              SELECT id FROM webhook_deliveries
+              WHERE status = 'pending' AND next_attempt_at <= now()
+              ORDER BY next_attempt_at
+              LIMIT $1
+              FOR UPDATE SKIP LOCKED
+            )
+            RETURNING d.id, d.event, d.payload, d.attempts, w.url, w.secret
+            "#,
+        )
+        .bind(BATCH_SIZE)
+        .bind(CLAIM_TIMEOUT)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let count = due.len();
+        let mut attempts = JoinSet::new();
+        for delivery in due {
+            let dispatcher = self.clone();
+            attempts.spawn(async move { dispatcher.attempt(delivery).await });
+        }
+        while let Some(ret) = attempts.join_next().await {
+            match ret {
+                Ok(Err(e)) => warn!("failed to record webhook delivery: {:?}", e),
+                Err(e) => warn!("webhook delivery panicked: {:?}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+        Ok(count)
+    }
+
+    async fn attempt(&self, delivery: DueDelivery) -> Result<(), AppError> {
+        if let Err(e) = self.outbound.check_url(&delivery.url).await {
+            return self.record_failure(&delivery, None, e).await;
+        }
+        let body = serde_json::to_vec(&delivery.payload).map_err(anyhow::Error::from)?;
+        let timestamp = Utc::now().timestamp();
+        let signature = sign(&delivery.secret, timestamp, &body);
+
+        let ret = self
+            .client
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &delivery.event)
+            .header(DELIVERY_HEADER, delivery.id)
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, format!("sha256={signature}"))
+            .timeout(DELIVERY_TIMEOUT)
+            .body(body)
+            .send()
+            .await;
+
+        let (status, error) = match ret {
+            Ok(res) if res.status().is_success() => {
+                sqlx::query(
+                    r#"
+                    UPDATE webhook_deliveries
+                    SET status = 'delivered', response_status = $2, last_error = NULL,
+                      delivered_at = now()
+                    WHERE id = $1
+                    "#,
+                )
+                .bind(delivery.id)
+                .bind(res.status().as_u16() as i32)
+                .execute(&self.pool)
+                .await?;
+                return Ok(());
+            }
+            Ok(res) => (
+                Some(res.status().as_u16() as i32),
+                format!("unexpected status: {}", res.status()),
+            ),
+            Err(e) => (e.status().map(|s| s.as_u16() as i32), e.to_string()),
+        };
+        self.record_failure(&delivery, status, error).await
+    }
+
+    /// Schedule a retry of the delivery, or give it up after [`MAX_ATTEMPTS`].
+    async fn record_failure(
+        &self,
+        delivery: &DueDelivery,
+        status: Option<i32>,
+        error: String,
+    ) -> Result<(), AppError> {
+        warn!(
+            "webhook delivery {} failed, attempt {}: {}",
+            delivery.id, delivery.attempts, error
+        );
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = CASE WHEN $2 THEN 'failed' ELSE 'pending' END::webhook_delivery_status,
+              next_attempt_at = now() + $3::interval, response_status = $4, last_error = $5
+            WHERE id = $1
+            "#,
+        )
+        .bind(delivery.id)
+        .bind(delivery.attempts >= MAX_ATTEMPTS)
+        .bind(backoff(delivery.attempts))
+        .bind(status)
+        .bind(error)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Hex HMAC-SHA256 of `{timestamp}.{body}` keyed with `secret`.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// How long to wait before retrying a delivery that failed `attempts` times.
+fn backoff(attempts: i32) -> Duration {
+    let exp = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    (BASE_BACKOFF * 2u32.pow(exp)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ChatRepo, CreateWebhook, DeliveryStatus, WebhookRepo, WorkspaceRepo};
+    use crate::AppState;
+    use anyhow::Result;
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use chat_core::{Chat, ChatType};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    /// Serve a receiver answering `status`, sending back what it received.
+    async fn receiver(status: StatusCode) -> Result<(String, mpsc::Receiver<(HeaderMap, String)>)> {
+        let (tx, rx) = mpsc::channel(10);
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    move |State(tx): State<mpsc::Sender<(HeaderMap, String)>>,
+                          headers: HeaderMap,
+                          body: String| async move {
+                        tx.send((headers, body)).await.unwrap();
+                        status
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((format!("http://{addr}/hook"), rx))
+    }
+
+    fn new_chat(ws_id: i64, id: i64) -> Notification {
+        Notification::new(
+            [1, 2],
+            AppEvent::NewChat(Chat {
+                id,
+                ws_id,
+                name: None,
+                r#type: ChatType::Single,
+                members: vec![1, 2],
+                created_at: Utc::now(),
+                topic: None,
+            }),
+        )
+    }
+
+    #[test]
+    fn backoff_should_double_up_to_max() {
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(4), BASE_BACKOFF * 8);
+        assert_eq!(backoff(MAX_ATTEMPTS * 4), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn deliveries_should_be_signed_and_logged() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let outbound = &state.config.outbound;
+        let (url, mut rx) = receiver(StatusCode::OK).await?;
+        let webhook = WebhookRepo::create(
+            CreateWebhook::new(&url, &[], &[1]),
+            1,
+            1,
+            outbound,
+            &state.pool,
+        )
+        .await?;
+        let dispatcher = WebhookDispatcher::new("", state.pool.clone(), outbound.clone());
+
+        // other chats and workspaces aren't delivered
+        assert_eq!(dispatcher.enqueue(&new_chat(1, 2)).await?, 0);
+        assert_eq!(dispatcher.enqueue(&new_chat(2, 1)).await?, 0);
+        let notification = new_chat(1, 1);
+        assert_eq!(dispatcher.enqueue(&notification).await?, 1);
+        assert_eq!(dispatcher.deliver_due().await?, 1);
+
+        let (headers, body) = rx.recv().await.unwrap();
+        assert_eq!(headers[EVENT_HEADER], "NewChat");
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str()?.parse()?;
+        let secret = webhook.secret.unwrap();
+        let expected = format!("sha256={}", sign(&secret, timestamp, body.as_bytes()));
+        assert_eq!(headers[SIGNATURE_HEADER], expected.as_str());
+        let event: AppEvent = serde_json::from_str(&body)?;
+        assert_eq!(event, notification.event);
+
+        let deliveries =
+            WebhookRepo::fetch_deliveries(webhook.id as _, 1, 1, 10, &state.pool).await?;
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+        assert_eq!(deliveries[0].response_status, Some(200));
+        assert_eq!(
+            headers[DELIVERY_HEADER],
+            deliveries[0].id.to_string().as_str()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_should_be_retried_later() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let outbound = &state.config.outbound;
+        let (url, mut rx) = receiver(StatusCode::INTERNAL_SERVER_ERROR).await?;
+        WorkspaceRepo::update_owner(1, 1, &state.pool).await?;
+        let webhook = WebhookRepo::create(
+            CreateWebhook::new(&url, &[], &[]),
+            1,
+            1,
+            outbound,
+            &state.pool,
+        )
+        .await?;
+        let dispatcher = WebhookDispatcher::new("", state.pool.clone(), outbound.clone());
+
+        dispatcher.enqueue(&new_chat(1, 1)).await?;
+        assert_eq!(dispatcher.deliver_due().await?, 1);
+        rx.recv().await.unwrap();
+
+        let deliveries =
+            WebhookRepo::fetch_deliveries(webhook.id as _, 1, 1, 10, &state.pool).await?;
+        assert_eq!(deliveries[0].status, DeliveryStatus::Pending);
+        assert_eq!(deliveries[0].attempts, 1);
+        assert_eq!(deliveries[0].response_status, Some(500));
+        assert!(deliveries[0].next_attempt_at > Utc::now() + BASE_BACKOFF / 2);
+        // not due before the backoff
+        assert_eq!(dispatcher.deliver_due().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deliveries_should_follow_what_the_creator_can_see() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let outbound = &state.config.outbound;
+        let (url, mut rx) = receiver(StatusCode::OK).await?;
+        // bob is in the group, until he leaves it
+        let input = CreateWebhook::new(&url, &[], &[4]);
+        let webhook = WebhookRepo::create(input, 1, 3, outbound, &state.pool).await?;
+        let dispatcher = WebhookDispatcher::new("", state.pool.clone(), outbound.clone());
+        assert_eq!(dispatcher.enqueue(&new_chat(1, 4)).await?, 1);
+        ChatRepo::remove_member(4, 3, &state.pool).await?;
+        assert_eq!(dispatcher.enqueue(&new_chat(1, 4)).await?, 0);
+
+        // the url is checked again on delivery, loopback isn't reachable by default
+        let strict = WebhookDispatcher::new("", state.pool.clone(), OutboundConfig::default());
+        assert_eq!(strict.deliver_due().await?, 1);
+        assert!(rx.try_recv().is_err());
+        let deliveries =
+            WebhookRepo::fetch_deliveries(webhook.id as _, 1, 3, 10, &state.pool).await?;
+        assert_eq!(deliveries[0].status, DeliveryStatus::Pending);
+        assert!(deliveries[0]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("non-public"));
+        Ok(())
+    }
+}
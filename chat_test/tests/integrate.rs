@@ -1,6 +1,9 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use chat_core::PgSubscriber;
-use chat_core::{Chat, ChatType, Message};
+use chat_core::{
+    AppEvent, AppMessage, Chat, ChatType, InMemoryPubSub, Message, Publisher, Subscriber,
+};
+use chat_server::EventPublisher;
 use futures::StreamExt;
 use reqwest::{
     multipart::{Form, Part},
@@ -10,7 +13,7 @@ use reqwest_eventsource::{Event, EventSource};
 use serde::Deserialize;
 use serde_json::json;
 use std::{net::SocketAddr, time::Duration};
-use tokio::{net::TcpListener, time::sleep};
+use tokio::{net::TcpListener, sync::mpsc, time::sleep, time::timeout};
 use tracing::info;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::fmt::Layer;
@@ -48,7 +51,110 @@ async fn chat_server_should_work() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn events_should_flow_through_in_memory_publisher() -> Result<()> {
+    let pubsub = InMemoryPubSub::new();
+    let (_tdb, state) = chat_server::AppState::new_for_test_with_publisher(
+        EventPublisher::InMemory(pubsub.clone()),
+    )
+    .await?;
+    let chat_server = ChatServer::new(state).await?;
+    let events = NotifyServer::listen(pubsub, &chat_server.token).await?;
+
+    assert_chat_events(&chat_server, events).await
+}
+
+#[cfg(feature = "amqp")]
+#[tokio::test]
+async fn events_should_flow_through_amqp_publisher() -> Result<()> {
+    const AMQP_URL: &str = "amqp://localhost:5672";
+
+    let publisher = chat_core::RabbitMqPubSub::connect(AMQP_URL).await?;
+    let (_tdb, state) =
+        chat_server::AppState::new_for_test_with_publisher(EventPublisher::Amqp(publisher)).await?;
+    let chat_server = ChatServer::new(state).await?;
+    // notify_server consumes through its own connection, as a separate node would
+    let subscriber = chat_core::RabbitMqPubSub::connect(AMQP_URL).await?;
+    let events = NotifyServer::listen(subscriber, &chat_server.token).await?;
+
+    assert_chat_events(&chat_server, events).await
+}
+
+/// Create a chat and post a message to it, both should reach the signed in user's event stream.
+async fn assert_chat_events(
+    chat_server: &ChatServer,
+    mut events: mpsc::Receiver<AppMessage>,
+) -> Result<()> {
+    let chat = chat_server.create_chat().await?;
+    let msg = chat_server.create_message(chat.id as u64).await?;
+
+    let message = recv(&mut events).await?;
+    assert_eq!(message.user_id, 1);
+    assert_eq!(message.event, AppEvent::NewChat(chat));
+
+    let message = recv(&mut events).await?;
+    match message.event {
+        AppEvent::NewMessage(event) => {
+            assert_eq!(event.id, msg.id);
+            assert_eq!(event.files, msg.files);
+            assert_eq!(event.sender_name.as_deref(), Some("Cae Chen"));
+        }
+        event => return Err(anyhow!("unexpected event: {:?}", event)),
+    }
+    Ok(())
+}
+
+async fn recv(events: &mut mpsc::Receiver<AppMessage>) -> Result<AppMessage> {
+    timeout(Duration::from_secs(5), events.recv())
+        .await
+        .map_err(|_| anyhow!("no event received in time"))?
+        .ok_or_else(|| anyhow!("event stream closed"))
+}
+
 impl NotifyServer {
+    /// Serve notify_server on top of `subscriber` and forward the events streamed to `token`.
+    ///
+    /// Returns once the stream is open, so events published afterwards are received.
+    async fn listen<T>(subscriber: T, token: &str) -> Result<mpsc::Receiver<AppMessage>>
+    where
+        T: Subscriber + Publisher + Clone + Send + Sync + 'static,
+    {
+        let config = notify_server::AppConfig::load()?;
+        let state = notify_server::AppState::new(config, subscriber);
+        let app = notify_server::get_router(state).await;
+        let listener = TcpListener::bind(WILD_ADDR).await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let builder = reqwest::Client::builder()
+            .no_proxy()
+            .build()?
+            .get(format!("http://{}/events", addr))
+            .header("Authorization", format!("Bearer {}", token));
+        let mut es = EventSource::new(builder)?;
+        match es.next().await {
+            Some(Ok(Event::Open)) => {}
+            event => return Err(anyhow!("failed to open event stream: {:?}", event)),
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(Ok(Event::Message(message))) = es.next().await {
+                let message = serde_json::from_str(&message.data).expect("invalid event");
+                if tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
     async fn new(db_url: &str, token: &str) -> Result<Self> {
         let mut config = notify_server::AppConfig::load()?;
         config.server.db_url = db_url.to_string();
@@ -79,16 +185,15 @@ impl NotifyServer {
                     Ok(Event::Open) => info!("connection open!"),
                     Ok(Event::Message(message)) => {
                         info!("received event: {:?}", message);
-                        match message.event.as_str() {
-                            "NewChat" => {
-                                let chat: Chat = serde_json::from_str(&message.data).unwrap();
+                        let data: AppMessage = serde_json::from_str(&message.data).unwrap();
+                        assert_eq!(message.event, data.event.name());
+                        match data.event {
+                            AppEvent::NewChat(chat) => {
                                 assert_eq!(chat.name.as_ref().unwrap(), "test");
                                 assert_eq!(chat.members, vec![1, 2]);
                                 assert_eq!(chat.r#type, ChatType::PrivateChannel);
                             }
-
-                            "NewMessage" => {
-                                let msg: Message = serde_json::from_str(&message.data).unwrap();
+                            AppEvent::NewMessage(msg) => {
                                 assert_eq!(msg.content, "hello");
                                 assert_eq!(msg.files.len(), 1);
                                 assert_eq!(msg.sender_id, 1);
@@ -214,7 +319,10 @@ impl ChatServer {
         assert_eq!(res.status(), StatusCode::CREATED);
         let message: Message = res.json().await?;
         assert_eq!(message.content, "hello");
-        assert_eq!(message.files, ret);
+        let urls: Vec<_> = message.files.iter().map(|file| file.url.clone()).collect();
+        assert_eq!(urls, ret);
+        assert_eq!(message.files[0].name, "Cargo.toml");
+        assert_eq!(message.files[0].size, data.len() as i64);
         assert_eq!(message.sender_id, 1);
         assert_eq!(message.chat_id, chat_id as i64);
         Ok(message)
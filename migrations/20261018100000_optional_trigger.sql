@@ -0,0 +1,39 @@
+-- Add migration script here
+-- chat_server publishes events itself unless it runs in trigger mode, in which case it leaves
+-- chat.notify_trigger unset on its connections and the triggers keep notifying listeners
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  IF current_setting('chat.notify_trigger', TRUE) IS DISTINCT FROM 'off' THEN
+    RAISE NOTICE 'add_to_chat: %', NEW;
+    PERFORM
+      pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' AND current_setting('chat.notify_trigger', TRUE) IS DISTINCT FROM 'off' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    -- select chat with chat_id in NEW
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,25 @@
+-- Add migration script here
+CREATE TYPE presence_status AS ENUM(
+  'online',
+  'away',
+  'offline'
+);
+
+-- the presence last announced for a user
+CREATE TABLE IF NOT EXISTS user_presence(
+  user_id bigint PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+  status presence_status NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- live connections of a user, one row per notify_server instance
+CREATE TABLE IF NOT EXISTS presence_sessions(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  instance_id varchar(64) NOT NULL,
+  connections integer NOT NULL,
+  active_at timestamptz NOT NULL,
+  heartbeat_at timestamptz NOT NULL,
+  PRIMARY KEY (user_id, instance_id)
+);
+
+CREATE INDEX IF NOT EXISTS presence_sessions_heartbeat_at_idx ON presence_sessions(heartbeat_at);
@@ -0,0 +1,9 @@
+-- single-use tickets to open notify_server streams where a bearer header can't be set
+CREATE TABLE IF NOT EXISTS stream_tickets(
+  ticket char(64) PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  expires_at timestamptz NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS stream_tickets_expires_at_idx ON stream_tickets(expires_at);
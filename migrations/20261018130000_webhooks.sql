@@ -0,0 +1,40 @@
+-- outgoing webhook subscriptions of a workspace, empty events / chat_ids match everything
+CREATE TABLE IF NOT EXISTS webhooks(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  url text NOT NULL,
+  secret char(64) NOT NULL,
+  events varchar(32)[] NOT NULL DEFAULT '{}',
+  chat_ids bigint[] NOT NULL DEFAULT '{}',
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_ws_id_idx ON webhooks(ws_id);
+
+CREATE TYPE webhook_delivery_status AS ENUM(
+  'pending',
+  'delivered',
+  'failed'
+);
+
+-- every event sent to a webhook, and how it went
+CREATE TABLE IF NOT EXISTS webhook_deliveries(
+  id bigserial PRIMARY KEY,
+  webhook_id bigint NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+  event varchar(32) NOT NULL,
+  payload jsonb NOT NULL,
+  status webhook_delivery_status NOT NULL DEFAULT 'pending',
+  attempts int NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  response_status int,
+  last_error text,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  delivered_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due_idx ON webhook_deliveries(next_attempt_at)
+WHERE
+  status = 'pending';
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_webhook_id_idx ON webhook_deliveries(webhook_id, created_at DESC);
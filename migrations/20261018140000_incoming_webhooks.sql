@@ -0,0 +1,16 @@
+-- users acting on behalf of an integration, they can't sign in
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS is_bot boolean NOT NULL DEFAULT FALSE;
+
+-- urls posting messages into a chat as their bot user, only the sha256 of the token is kept
+CREATE TABLE IF NOT EXISTS incoming_webhooks(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  bot_id bigint NOT NULL REFERENCES users(id),
+  name varchar(64) NOT NULL,
+  token_hash char(64) NOT NULL UNIQUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS incoming_webhooks_chat_id_idx ON incoming_webhooks(chat_id);
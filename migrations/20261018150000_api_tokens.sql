@@ -0,0 +1,23 @@
+-- who created a bot user, the only one managing its tokens
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS created_by bigint REFERENCES users(id);
+
+CREATE TYPE api_token_scope AS ENUM(
+  'read_messages',
+  'post_messages',
+  'manage_chats'
+);
+
+-- tokens authenticating as a user or bot with limited scopes, only the sha256 of the token is kept
+CREATE TABLE IF NOT EXISTS api_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  name varchar(64) NOT NULL,
+  token_hash char(64) NOT NULL UNIQUE,
+  scopes api_token_scope[] NOT NULL,
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS api_tokens_user_id_idx ON api_tokens(user_id);
@@ -0,0 +1,24 @@
+-- set with /topic
+ALTER TABLE chats
+  ADD COLUMN IF NOT EXISTS topic text;
+
+-- chats a user muted with /mute
+CREATE TABLE IF NOT EXISTS chat_mutes(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
+
+-- commands of a workspace handled by posting to a url, replies in the channel come from its bot
+CREATE TABLE IF NOT EXISTS slash_commands(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  name varchar(32) NOT NULL,
+  url text NOT NULL,
+  secret char(64) NOT NULL,
+  bot_id bigint NOT NULL REFERENCES users(id),
+  created_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, name)
+);
@@ -0,0 +1,13 @@
+-- resumable uploads in progress, their content is appended to under server.base_dir/uploads
+CREATE TABLE IF NOT EXISTS upload_sessions(
+  id uuid PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  filename varchar(255) NOT NULL,
+  size bigint NOT NULL CHECK (size >= 0),
+  upload_offset bigint NOT NULL DEFAULT 0 CHECK (upload_offset <= size),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS upload_sessions_user_id_idx ON upload_sessions(user_id);
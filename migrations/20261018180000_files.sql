@@ -0,0 +1,80 @@
+-- metadata of uploaded files, one row per content-addressed key
+CREATE TABLE IF NOT EXISTS files(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  hash char(40) NOT NULL,
+  ext varchar(64) NOT NULL,
+  -- the name of the first upload of the content
+  name varchar(255) NOT NULL,
+  -- NULL until the file collector measures content uploaded before this table
+  size bigint,
+  mime varchar(255) NOT NULL,
+  uploaded_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (ws_id, hash, ext)
+);
+
+-- files attached to messages before this table, their name and size weren't kept, their
+-- mime type is guessed from the extension as for new uploads
+INSERT INTO files (ws_id, hash, ext, name, size, mime, uploaded_by, created_at)
+SELECT DISTINCT ON (u.ws_id, u.hash, u.ext)
+  u.ws_id, u.hash, u.ext, u.name, NULL,
+  CASE lower(u.ext)
+    WHEN 'png' THEN 'image/png'
+    WHEN 'jpg' THEN 'image/jpeg'
+    WHEN 'jpeg' THEN 'image/jpeg'
+    WHEN 'gif' THEN 'image/gif'
+    WHEN 'webp' THEN 'image/webp'
+    WHEN 'bmp' THEN 'image/bmp'
+    WHEN 'svg' THEN 'image/svg+xml'
+    WHEN 'ico' THEN 'image/x-icon'
+    WHEN 'mp4' THEN 'video/mp4'
+    WHEN 'webm' THEN 'video/webm'
+    WHEN 'mov' THEN 'video/quicktime'
+    WHEN 'mp3' THEN 'audio/mpeg'
+    WHEN 'wav' THEN 'audio/wav'
+    WHEN 'ogg' THEN 'audio/ogg'
+    WHEN 'pdf' THEN 'application/pdf'
+    WHEN 'zip' THEN 'application/zip'
+    WHEN 'json' THEN 'application/json'
+    WHEN 'txt' THEN 'text/plain'
+    WHEN 'md' THEN 'text/markdown'
+    WHEN 'csv' THEN 'text/csv'
+    WHEN 'html' THEN 'text/html'
+    ELSE 'application/octet-stream'
+  END,
+  u.sender_id, u.created_at
+FROM (
+  SELECT
+    split_part(url, '/', 3)::bigint AS ws_id,
+    split_part(url, '/', 4) || split_part(url, '/', 5) || split_part(split_part(url, '/', 6), '.', 1) AS hash,
+    substring(split_part(url, '/', 6) FROM position('.' IN split_part(url, '/', 6)) + 1) AS ext,
+    split_part(url, '/', 6) AS name,
+    m.sender_id,
+    m.created_at
+  FROM messages m, unnest(m.files) AS url
+  WHERE url ~ '^/files/[0-9]+/[0-9a-f]{3}/[0-9a-f]{3}/[0-9a-f]{34}\.'
+) u
+ORDER BY u.ws_id, u.hash, u.ext, u.created_at
+ON CONFLICT DO NOTHING;
+
+-- messages carry their attachments rather than bare urls
+ALTER TABLE messages
+  ADD COLUMN attachments jsonb NOT NULL DEFAULT '[]';
+
+UPDATE messages m
+SET attachments = (
+  SELECT coalesce(jsonb_agg(jsonb_build_object(
+    'id', f.id, 'name', f.name, 'size', coalesce(f.size, 0), 'mime', f.mime, 'url', u.url
+  ) ORDER BY u.ord), '[]')
+  FROM unnest(m.files) WITH ORDINALITY AS u(url, ord)
+  JOIN files f ON u.url = '/files/' || f.ws_id || '/' || substr(f.hash, 1, 3) || '/'
+    || substr(f.hash, 4, 3) || '/' || substr(f.hash, 7) || '.' || f.ext
+)
+WHERE cardinality(m.files) > 0;
+
+ALTER TABLE messages
+  DROP COLUMN files;
+
+ALTER TABLE messages
+  RENAME COLUMN attachments TO files;
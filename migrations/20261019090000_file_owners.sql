@@ -0,0 +1,15 @@
+-- who uploaded each file: a content is stored once, but may be uploaded by several users
+CREATE TABLE IF NOT EXISTS file_owners(
+  file_id bigint NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (file_id, user_id)
+);
+
+INSERT INTO file_owners (file_id, user_id, created_at)
+SELECT id, uploaded_by, created_at
+FROM files
+ON CONFLICT DO NOTHING;
+
+-- find the messages a file is attached to
+CREATE INDEX IF NOT EXISTS messages_files_idx ON messages USING gin (files jsonb_path_ops);
@@ -0,0 +1,5 @@
+-- dimensions of images, and the thumbnails generated for them
+ALTER TABLE files
+  ADD COLUMN width int,
+  ADD COLUMN height int,
+  ADD COLUMN thumbnails jsonb NOT NULL DEFAULT '[]';
@@ -0,0 +1,6 @@
+-- when the content of a file was last uploaded, unreferenced files are only collected a while
+-- after it
+ALTER TABLE files
+  ADD COLUMN last_uploaded_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+UPDATE files SET last_uploaded_at = created_at;
@@ -0,0 +1,3 @@
+-- settings of workspaces, e.g. their storage quota
+ALTER TABLE workspaces
+  ADD COLUMN settings jsonb NOT NULL DEFAULT '{}';
@@ -0,0 +1,8 @@
+-- resumable uploads not advanced for a while expire, and are purged along with their content
+ALTER TABLE upload_sessions
+  ADD COLUMN expires_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP + interval '1 day';
+
+UPDATE upload_sessions SET expires_at = updated_at + interval '1 day';
+
+CREATE INDEX IF NOT EXISTS upload_sessions_expires_at_idx ON upload_sessions(expires_at);
+CREATE INDEX IF NOT EXISTS upload_sessions_ws_id_idx ON upload_sessions(ws_id);
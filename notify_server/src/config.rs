@@ -1,11 +1,17 @@
 use anyhow::Result;
-use chat_core::load_config;
+use chat_core::{load_config, APP_EVENT_CHANNEL, CHAT_EXCHANGE};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub pubsub: PubSubConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,8 +25,116 @@ pub struct ServerConfig {
     pub db_url: String,
 }
 
+/// The pubsub backend events are received from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum PubSubConfig {
+    /// Listen to postgres notifications on `server.db_url`.
+    #[default]
+    Postgres,
+    /// Consume from a RabbitMQ broker, requires the `amqp` feature.
+    Amqp { url: String },
+    /// Route events inside the process, for embedded setups where the publisher runs alongside.
+    InMemory,
+}
+
+/// How open connections are drained on shutdown.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Seconds connections get to go away before they're cut.
+    pub drain_timeout_secs: u64,
+    /// Milliseconds SSE clients are told to wait before reconnecting.
+    pub reconnect_retry_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 10,
+            reconnect_retry_ms: 1000,
+        }
+    }
+}
+
+/// Keep-alive and connection limits of `/events` and `/ws`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Seconds between SSE keep-alive comments on an idle stream.
+    pub keep_alive_secs: u64,
+    /// Milliseconds SSE clients are told to wait before reconnecting a dropped stream.
+    pub retry_ms: u64,
+    /// Max concurrent streams of one user, across both transports.
+    pub max_streams_per_user: usize,
+    /// Max concurrent streams of the whole instance.
+    pub max_connections: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive_secs: 15,
+            retry_ms: 3000,
+            max_streams_per_user: 10,
+            max_connections: 10_000,
+        }
+    }
+}
+
+impl PubSubConfig {
+    /// The topic events originating in notify_server, e.g. typing, are published to.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Self::Postgres => APP_EVENT_CHANNEL,
+            Self::Amqp { .. } => CHAT_EXCHANGE,
+            Self::InMemory => "",
+        }
+    }
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self> {
         load_config("NOTIFY_CONFIG", "notify.yaml")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_config_should_work() {
+        let cfg = AppConfig::load().unwrap();
+
+        assert_eq!(8888, cfg.server.port);
+        assert_eq!(PubSubConfig::Postgres, cfg.pubsub);
+        assert_eq!(ShutdownConfig::default(), cfg.shutdown);
+        assert_eq!(LimitsConfig::default(), cfg.limits);
+    }
+
+    #[test]
+    fn limits_config_should_fill_defaults() {
+        let cfg: LimitsConfig = serde_yaml::from_str("max_streams_per_user: 3").unwrap();
+        assert_eq!(3, cfg.max_streams_per_user);
+        assert_eq!(LimitsConfig::default().keep_alive_secs, cfg.keep_alive_secs);
+    }
+
+    #[test]
+    fn pubsub_config_should_deserialize() {
+        let cfg: PubSubConfig =
+            serde_yaml::from_str("backend: amqp\nurl: amqp://localhost:5672").unwrap();
+        assert_eq!(
+            PubSubConfig::Amqp {
+                url: "amqp://localhost:5672".to_string()
+            },
+            cfg
+        );
+
+        let cfg: PubSubConfig = serde_yaml::from_str("backend: postgres").unwrap();
+        assert_eq!(PubSubConfig::Postgres, cfg);
+
+        let cfg: PubSubConfig = serde_yaml::from_str("backend: in_memory").unwrap();
+        assert_eq!(PubSubConfig::InMemory, cfg);
+    }
+}
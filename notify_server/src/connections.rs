@@ -0,0 +1,127 @@
+//! Accounting of open `/events` and `/ws` connections, enforcing the configured limits.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::LimitsConfig;
+use crate::error::AppError;
+
+#[derive(Default)]
+pub(crate) struct ConnectionTracker {
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: AtomicUsize,
+    per_user: DashMap<i64, usize>,
+}
+
+/// Counts as an open connection of the user while alive.
+pub(crate) struct ConnectionGuard {
+    user_id: i64,
+    counts: Arc<Counts>,
+}
+
+/// Current connection counts, as served on `/connections`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub connections: usize,
+    pub users: usize,
+    pub max_connections: usize,
+    pub max_streams_per_user: usize,
+}
+
+impl ConnectionTracker {
+    /// Open a connection for `user_id`, unless it or the server is already at its limit.
+    pub(crate) fn acquire(
+        &self,
+        user_id: i64,
+        limits: &LimitsConfig,
+    ) -> Result<ConnectionGuard, AppError> {
+        let counts = &self.counts;
+        let ret = {
+            // the entry stays locked until the user's count is settled
+            let mut streams = counts.per_user.entry(user_id).or_insert(0);
+            if *streams >= limits.max_streams_per_user {
+                Err(AppError::TooManyStreams(limits.max_streams_per_user))
+            } else if counts
+                .total
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < limits.max_connections).then_some(n + 1)
+                })
+                .is_err()
+            {
+                Err(AppError::TooManyConnections(limits.max_connections))
+            } else {
+                *streams += 1;
+                Ok(ConnectionGuard {
+                    user_id,
+                    counts: counts.clone(),
+                })
+            }
+        };
+        counts
+            .per_user
+            .remove_if(&user_id, |_, streams| *streams == 0);
+        ret
+    }
+
+    pub(crate) fn stats(&self, limits: &LimitsConfig) -> ConnectionStats {
+        ConnectionStats {
+            connections: self.counts.total.load(Ordering::SeqCst),
+            users: self.counts.per_user.len(),
+            max_connections: limits.max_connections,
+            max_streams_per_user: limits.max_streams_per_user,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counts.total.fetch_sub(1, Ordering::SeqCst);
+        if let Some(mut streams) = self.counts.per_user.get_mut(&self.user_id) {
+            *streams -= 1;
+        }
+        self.counts
+            .per_user
+            .remove_if(&self.user_id, |_, streams| *streams == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acquire_should_enforce_limits() {
+        let tracker = ConnectionTracker::default();
+        let limits = LimitsConfig {
+            max_streams_per_user: 2,
+            max_connections: 3,
+            ..Default::default()
+        };
+
+        let a1 = tracker.acquire(1, &limits).unwrap();
+        let _a2 = tracker.acquire(1, &limits).unwrap();
+        assert!(matches!(
+            tracker.acquire(1, &limits),
+            Err(AppError::TooManyStreams(2))
+        ));
+        let _b1 = tracker.acquire(2, &limits).unwrap();
+        assert!(matches!(
+            tracker.acquire(3, &limits),
+            Err(AppError::TooManyConnections(3))
+        ));
+        assert_eq!(3, tracker.stats(&limits).connections);
+        assert_eq!(2, tracker.stats(&limits).users);
+
+        drop(a1);
+        let _c1 = tracker.acquire(3, &limits).unwrap();
+        assert_eq!(3, tracker.stats(&limits).connections);
+        assert_eq!(3, tracker.stats(&limits).users);
+    }
+}
@@ -16,6 +16,30 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("pubsub unavailable: {0}")]
+    PubSubUnavailable(anyhow::Error),
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+
+    #[error("too many streams: at most {0} per user")]
+    TooManyStreams(usize),
+
+    #[error("too many connections: server is at its limit of {0}")]
+    TooManyConnections(usize),
+
+    #[error("invalid or expired stream ticket")]
+    InvalidTicket,
 }
 
 impl ErrorOutput {
@@ -31,6 +55,14 @@ impl IntoResponse for AppError {
         let status = match &self {
             Self::JwtError(_) => StatusCode::FORBIDDEN,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::PubSubUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+            Self::TooManyStreams(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::TooManyConnections(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::InvalidTicket => StatusCode::UNAUTHORIZED,
         };
 
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
@@ -1,33 +1,215 @@
+use crate::error::AppError;
 use crate::AppState;
+use axum::extract::Query;
 use axum::response::Sse;
 use axum::Extension;
 use axum::{extract::State, response::sse::Event};
-use chat_core::{Subscriber, User};
-use futures::Stream;
+use chat_core::{AppEvent, Subscriber, User};
+use futures::{stream, Stream};
+use serde::Deserialize;
+use std::collections::HashSet;
 use std::convert::Infallible;
 use std::time::Duration;
 use tokio_stream::StreamExt as _;
 use tracing::info;
 
+/// Query parameters of `/events`, both comma separated, e.g. `?events=NewMessage&chat_ids=1,2`.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct EventsParams {
+    events: Option<String>,
+    chat_ids: Option<String>,
+}
+
+/// Which events a stream gets, everything by default.
+#[derive(Debug, Default, PartialEq)]
+struct EventFilter {
+    events: Option<HashSet<&'static str>>,
+    /// Only applies to events about a chat, others are up to `events`.
+    chat_ids: Option<HashSet<i64>>,
+}
+
 pub(crate) async fn sse_handler<T: Clone + Subscriber>(
     Extension(user): Extension<User>,
     State(state): State<AppState<T>>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(params): Query<EventsParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
     info!("user: {} connected", user.id,);
 
-    let user_id = user.id as u64;
+    let filter = EventFilter::try_from(params)?;
+    if let Some(chat_ids) = &filter.chat_ids {
+        state.check_membership(user.id, chat_ids).await?;
+    }
 
-    let stream = state
+    let connection = state.connections.acquire(user.id, &state.config.limits)?;
+    let user_id = user.id as u64;
+    let events = state
         .listener
         .subscribe(user_id)
         .await
-        .unwrap()
-        .map(|event| Event::default().data(serde_json::to_string(&event).unwrap()))
+        .map_err(AppError::PubSubUnavailable)?;
+    // dropped along with the stream once the client goes away
+    let presence = state.presence.connect(user.id, true);
+
+    let events = events
+        .filter(move |message| filter.matches(&message.event))
+        .map(move |message| {
+            let _ = (&presence, &connection);
+            Event::default()
+                .event(message.event.name())
+                .data(serde_json::to_string(&message).unwrap())
+        });
+    // the stream ends on shutdown, telling the client when to come back
+    let retry = Duration::from_millis(state.config.shutdown.reconnect_retry_ms);
+    let reconnect = Event::default()
+        .event("reconnect")
+        .retry(retry)
+        .data("server is shutting down");
+    let shutdown = state.shutdown.clone().cancelled_owned();
+    let limits = &state.config.limits;
+    let hint = Event::default().retry(Duration::from_millis(limits.retry_ms));
+    let stream = stream::once(async move { hint })
+        .chain(futures::StreamExt::take_until(events, shutdown))
+        .chain(stream::once(async move { reconnect }))
         .map(Ok::<_, Infallible>);
 
-    Sse::new(stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
-            .interval(Duration::from_secs(1))
-            .text("keep-alive-text"),
-    )
+    Ok(Sse::new(stream).keep_alive(
+        axum::response::sse::KeepAlive::new().interval(Duration::from_secs(limits.keep_alive_secs)),
+    ))
+}
+
+impl TryFrom<EventsParams> for EventFilter {
+    type Error = AppError;
+
+    fn try_from(params: EventsParams) -> Result<Self, Self::Error> {
+        let events = params
+            .events
+            .map(|events| {
+                split(&events)
+                    .map(|name| {
+                        AppEvent::NAMES
+                            .into_iter()
+                            .find(|known| *known == name)
+                            .ok_or_else(|| {
+                                AppError::InvalidFilter(format!("unknown event: {name}"))
+                            })
+                    })
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+        let chat_ids = params
+            .chat_ids
+            .map(|chat_ids| {
+                split(&chat_ids)
+                    .map(|id| {
+                        id.parse()
+                            .map_err(|_| AppError::InvalidFilter(format!("invalid chat id: {id}")))
+                    })
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+
+        Ok(Self { events, chat_ids })
+    }
+}
+
+impl EventFilter {
+    fn matches(&self, event: &AppEvent) -> bool {
+        if let Some(events) = &self.events {
+            if !events.contains(event.name()) {
+                return false;
+            }
+        }
+        match (&self.chat_ids, event.chat_id()) {
+            (Some(chat_ids), Some(chat_id)) => chat_ids.contains(&chat_id),
+            _ => true,
+        }
+    }
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+impl<T: Clone> AppState<T> {
+    /// Make sure `user_id` is a member of every chat in `chat_ids`.
+    async fn check_membership(
+        &self,
+        user_id: i64,
+        chat_ids: &HashSet<i64>,
+    ) -> Result<(), AppError> {
+        let chat_ids: Vec<i64> = chat_ids.iter().copied().collect();
+        let joined: Vec<(i64,)> =
+            sqlx::query_as("SELECT id FROM chats WHERE id = ANY($1) AND $2 = ANY(members)")
+                .bind(&chat_ids)
+                .bind(user_id)
+                .fetch_all(&self.pool)
+                .await?;
+
+        let joined: HashSet<i64> = joined.into_iter().map(|(id,)| id).collect();
+        match chat_ids.iter().find(|id| !joined.contains(id)) {
+            Some(chat_id) => Err(AppError::NotFound(format!("chat id {chat_id}"))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{Presence, PresenceStatus, Typing};
+
+    fn params(events: Option<&str>, chat_ids: Option<&str>) -> EventsParams {
+        EventsParams {
+            events: events.map(str::to_string),
+            chat_ids: chat_ids.map(str::to_string),
+        }
+    }
+
+    fn typing(chat_id: i64) -> AppEvent {
+        AppEvent::Typing(Typing {
+            chat_id,
+            user_id: 2,
+            typing: true,
+        })
+    }
+
+    #[test]
+    fn event_filter_should_parse_params() {
+        assert_eq!(
+            EventFilter::default(),
+            EventFilter::try_from(params(None, None)).unwrap()
+        );
+
+        let filter =
+            EventFilter::try_from(params(Some("NewMessage, Typing"), Some("1,2"))).unwrap();
+        assert_eq!(Some(HashSet::from(["NewMessage", "Typing"])), filter.events);
+        assert_eq!(Some(HashSet::from([1, 2])), filter.chat_ids);
+
+        assert!(matches!(
+            EventFilter::try_from(params(Some("Unknown"), None)),
+            Err(AppError::InvalidFilter(_))
+        ));
+        assert!(matches!(
+            EventFilter::try_from(params(None, Some("1,x"))),
+            Err(AppError::InvalidFilter(_))
+        ));
+    }
+
+    #[test]
+    fn event_filter_should_match_types_and_chats() {
+        let presence = AppEvent::PresenceChanged(Presence {
+            user_id: 2,
+            status: PresenceStatus::Online,
+            updated_at: Default::default(),
+        });
+
+        let filter = EventFilter::try_from(params(None, Some("1"))).unwrap();
+        assert!(filter.matches(&typing(1)));
+        assert!(!filter.matches(&typing(2)));
+        assert!(filter.matches(&presence));
+
+        let filter = EventFilter::try_from(params(Some("Typing"), None)).unwrap();
+        assert!(filter.matches(&typing(2)));
+        assert!(!filter.matches(&presence));
+    }
 }
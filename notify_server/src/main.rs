@@ -1,8 +1,11 @@
 use anyhow::Result;
-use chat_core::PgSubscriber;
-use notify_server::{get_router, AppConfig, AppState};
+use chat_core::{InMemoryPubSub, PgSubscriber, Publisher, Subscriber};
+use notify_server::{get_router, AppConfig, AppState, PubSubConfig};
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
+use tokio::signal;
+use tokio::time::timeout;
+use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
 #[tokio::main]
@@ -11,18 +14,91 @@ async fn main() -> Result<()> {
     tracing_subscriber::registry().with(layer).init();
 
     let config = AppConfig::load()?;
-    let listener = PgSubscriber::new(&config.server.db_url).await?;
-    let state = AppState::new(config, listener);
+    info!("Using {:?} pubsub backend", config.pubsub);
+
+    match config.pubsub.clone() {
+        PubSubConfig::Postgres => {
+            let listener = PgSubscriber::new(&config.server.db_url).await?;
+            serve(AppState::new(config, listener)).await
+        }
+        #[cfg(feature = "amqp")]
+        PubSubConfig::Amqp { url } => {
+            let listener = chat_core::RabbitMqPubSub::connect(&url).await?;
+            serve(AppState::new(config, listener)).await
+        }
+        #[cfg(not(feature = "amqp"))]
+        PubSubConfig::Amqp { .. } => {
+            anyhow::bail!("amqp pubsub backend requires the `amqp` feature")
+        }
+        PubSubConfig::InMemory => serve(AppState::new(config, InMemoryPubSub::new())).await,
+    }
+}
+
+async fn serve<T>(state: AppState<T>) -> Result<()>
+where
+    T: Subscriber + Publisher + Clone + Send + Sync + 'static,
+{
     let port = state.config.server.port;
+    let drain_timeout = Duration::from_secs(state.config.shutdown.drain_timeout_secs);
 
-    let app = get_router(state).await;
+    state.track_presence();
+    let app = get_router(state.clone()).await;
 
     let addr = format!("0.0.0.0:{}", port);
 
     let listener = TcpListener::bind(&addr).await?;
     info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    let cloned_state = state.clone();
+    let mut server = tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service())
+            .with_graceful_shutdown(async move { cloned_state.shutdown_requested().await })
+            .await
+    });
+
+    tokio::select! {
+        ret = &mut server => return Ok(ret??),
+        _ = shutdown_signal() => {},
+    }
+
+    info!(
+        "shutting down, draining connections for up to {:?}",
+        drain_timeout
+    );
+    state.shutdown();
+    match timeout(drain_timeout, server).await {
+        Ok(ret) => ret??,
+        Err(_) => warn!(
+            "connections not drained within {:?}, closing them",
+            drain_timeout
+        ),
+    }
+    state.close().await;
+    info!("shutdown complete");
 
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
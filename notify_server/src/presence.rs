@@ -0,0 +1,409 @@
+//! Presence tracking from live connections.
+//!
+//! Every instance keeps a row per connected user in `presence_sessions` and heartbeats it. A user
+//! is online with a live session active within [`AWAY_AFTER`], away with only idle ones and offline
+//! without any. Frames sent over a websocket are activity, and so is an open event stream, which
+//! can't send anything. Changes are recorded in `user_presence` and announced to users who share a
+//! chat. On shutdown the instance drops its sessions right away rather than letting them time out.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chat_core::{AppEvent, Notification, Presence, Publisher};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Instant};
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// How often an instance refreshes its sessions and sweeps everyone else's.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+/// Sessions not refreshed for this long belong to an instance that's gone.
+const SESSION_TTL: Duration = Duration::from_secs(45);
+/// Users without activity for this long are away.
+const AWAY_AFTER: Duration = Duration::from_secs(300);
+/// Activity is written at most this often per user, unless it brings the user back from away.
+const ACTIVITY_WRITE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+enum Command {
+    Connect { user_id: i64, stream: bool },
+    Disconnect { user_id: i64, stream: bool },
+    Activity(i64),
+}
+
+pub(crate) struct PresenceTracker {
+    instance_id: String,
+    tx: mpsc::UnboundedSender<Command>,
+    rx: Mutex<Option<mpsc::UnboundedReceiver<Command>>>,
+}
+
+/// Keeps the user connected while alive.
+pub(crate) struct PresenceGuard {
+    user_id: i64,
+    stream: bool,
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+/// A user's connections to this instance.
+struct Session {
+    connections: i32,
+    /// Event streams among the connections, they keep the user active.
+    streams: i32,
+    active_at: Instant,
+    written_at: Instant,
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            instance_id: uuid::Uuid::now_v7().to_string(),
+            tx,
+            rx: Mutex::new(Some(rx)),
+        }
+    }
+}
+
+impl PresenceTracker {
+    /// Record a new connection of `user_id`, which lasts until the returned guard is dropped.
+    /// A `stream` of events is activity as long as it's open.
+    pub(crate) fn connect(&self, user_id: i64, stream: bool) -> PresenceGuard {
+        self.send(Command::Connect { user_id, stream });
+        PresenceGuard {
+            user_id,
+            stream,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Record that `user_id` did something, keeping it from going away.
+    pub(crate) fn activity(&self, user_id: i64) {
+        self.send(Command::Activity(user_id));
+    }
+
+    fn send(&self, command: Command) {
+        if let Err(e) = self.tx.send(command) {
+            warn!("presence tracking stopped, dropping {:?}", e.0);
+        }
+    }
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Command::Disconnect {
+            user_id: self.user_id,
+            stream: self.stream,
+        });
+    }
+}
+
+impl<T> AppState<T>
+where
+    T: Publisher + Clone + Send + Sync + 'static,
+{
+    /// Track presence in the background until the state is closed, only the first call on a
+    /// state does anything.
+    pub fn track_presence(&self) {
+        self.tasks.spawn(self.clone().run_presence());
+    }
+
+    async fn run_presence(self) {
+        let Some(mut rx) = self.presence.rx.lock().expect("poisoned lock").take() else {
+            return;
+        };
+        let mut sessions = HashMap::new();
+        let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            let ret = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                command = rx.recv() => match command {
+                    Some(command) => self.handle_presence(&mut sessions, command).await,
+                    None => break,
+                },
+                _ = heartbeat.tick() => self.heartbeat(&mut sessions).await,
+            };
+
+            if let Err(e) = ret {
+                warn!("failed to track presence: {:?}", e);
+            }
+        }
+
+        // settle what connections did before shutdown, so their users are cleared as well
+        while let Ok(command) = rx.try_recv() {
+            if let Err(e) = self.handle_presence(&mut sessions, command).await {
+                warn!("failed to track presence: {:?}", e);
+            }
+        }
+        if let Err(e) = self.clear_sessions(sessions).await {
+            warn!("failed to clear presence sessions: {:?}", e);
+        }
+    }
+
+    async fn handle_presence(
+        &self,
+        sessions: &mut HashMap<i64, Session>,
+        command: Command,
+    ) -> Result<(), AppError> {
+        let now = Instant::now();
+        match command {
+            Command::Connect { user_id, stream } => {
+                let session = sessions.entry(user_id).or_insert(Session {
+                    connections: 0,
+                    streams: 0,
+                    active_at: now,
+                    written_at: now,
+                });
+                session.connections += 1;
+                session.streams += stream as i32;
+                session.active_at = now;
+                session.written_at = now;
+                let connections = session.connections;
+
+                sqlx::query(
+                    r#"
+                    INSERT INTO presence_sessions (user_id, instance_id, connections, active_at, heartbeat_at)
+                    VALUES ($1, $2, $3, now(), now())
+                    ON CONFLICT (user_id, instance_id) DO UPDATE
+                    SET connections = EXCLUDED.connections,
+                        active_at = EXCLUDED.active_at,
+                        heartbeat_at = EXCLUDED.heartbeat_at
+                    "#,
+                )
+                .bind(user_id)
+                .bind(&self.presence.instance_id)
+                .bind(connections)
+                .execute(&self.pool)
+                .await?;
+                self.refresh_presence(user_id).await
+            }
+            Command::Disconnect { user_id, stream } => {
+                let Some(session) = sessions.get_mut(&user_id) else {
+                    return Ok(());
+                };
+                session.connections -= 1;
+                session.streams -= stream as i32;
+                if session.connections > 0 {
+                    sqlx::query(
+                        "UPDATE presence_sessions SET connections = $3 WHERE user_id = $1 AND instance_id = $2",
+                    )
+                    .bind(user_id)
+                    .bind(&self.presence.instance_id)
+                    .bind(session.connections)
+                    .execute(&self.pool)
+                    .await?;
+                    return Ok(());
+                }
+
+                sessions.remove(&user_id);
+                sqlx::query(
+                    "DELETE FROM presence_sessions WHERE user_id = $1 AND instance_id = $2",
+                )
+                .bind(user_id)
+                .bind(&self.presence.instance_id)
+                .execute(&self.pool)
+                .await?;
+                self.refresh_presence(user_id).await
+            }
+            Command::Activity(user_id) => {
+                let Some(session) = sessions.get_mut(&user_id) else {
+                    return Ok(());
+                };
+                let idle = now - session.active_at >= AWAY_AFTER;
+                session.active_at = now;
+                if !idle && now - session.written_at < ACTIVITY_WRITE_INTERVAL {
+                    return Ok(());
+                }
+                session.written_at = now;
+
+                sqlx::query(
+                    "UPDATE presence_sessions SET active_at = now() WHERE user_id = $1 AND instance_id = $2",
+                )
+                .bind(user_id)
+                .bind(&self.presence.instance_id)
+                .execute(&self.pool)
+                .await?;
+                if idle {
+                    self.refresh_presence(user_id).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Refresh this instance's sessions, drop the ones of dead instances and announce the
+    /// presence of users whose sessions changed meanwhile, or went idle.
+    async fn heartbeat(&self, sessions: &mut HashMap<i64, Session>) -> Result<(), AppError> {
+        let now = Instant::now();
+        let mut user_ids = Vec::with_capacity(sessions.len());
+        let mut connections = Vec::with_capacity(sessions.len());
+        let mut streaming = Vec::new();
+        for (user_id, session) in sessions.iter_mut() {
+            user_ids.push(*user_id);
+            connections.push(session.connections);
+            if session.streams > 0 {
+                session.active_at = now;
+                session.written_at = now;
+                streaming.push(*user_id);
+            }
+        }
+        // sessions dropped meanwhile, as if this instance were gone, are back
+        let mut changed: BTreeSet<i64> = sqlx::query_scalar(
+            r#"
+            WITH upserted AS (
+              INSERT INTO presence_sessions (user_id, instance_id, connections, active_at, heartbeat_at)
+              SELECT user_id, $1, connections, now(), now()
+              FROM unnest($2::bigint[], $3::int[]) AS t(user_id, connections)
+              ON CONFLICT (user_id, instance_id) DO UPDATE
+              SET connections = EXCLUDED.connections,
+                  active_at = CASE
+                    WHEN presence_sessions.user_id = ANY($4) THEN EXCLUDED.active_at
+                    ELSE presence_sessions.active_at
+                  END,
+                  heartbeat_at = EXCLUDED.heartbeat_at
+              RETURNING user_id, xmax = 0 AS inserted
+            )
+            SELECT user_id FROM upserted WHERE inserted
+            "#,
+        )
+        .bind(&self.presence.instance_id)
+        .bind(user_ids)
+        .bind(connections)
+        .bind(streaming)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect();
+
+        let expired: Vec<i64> = sqlx::query_scalar(
+            "DELETE FROM presence_sessions WHERE heartbeat_at < now() - $1::interval RETURNING user_id",
+        )
+        .bind(SESSION_TTL)
+        .fetch_all(&self.pool)
+        .await?;
+        changed.extend(expired);
+
+        // online users whose sessions all went idle are away
+        let idle: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT user_id FROM user_presence p
+            WHERE status = 'online' AND NOT EXISTS (
+              SELECT 1 FROM presence_sessions s
+              WHERE s.user_id = p.user_id AND s.active_at > now() - $1::interval
+            )
+            "#,
+        )
+        .bind(AWAY_AFTER)
+        .fetch_all(&self.pool)
+        .await?;
+        changed.extend(idle);
+
+        for user_id in changed {
+            self.refresh_presence(user_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Drop the sessions of this instance on shutdown, so its users don't stay online until the
+    /// sessions time out.
+    async fn clear_sessions(&self, sessions: HashMap<i64, Session>) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM presence_sessions WHERE instance_id = $1")
+            .bind(&self.presence.instance_id)
+            .execute(&self.pool)
+            .await?;
+        for user_id in sessions.into_keys() {
+            self.refresh_presence(user_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Recompute the presence of `user_id` from its sessions, announcing it if it changed.
+    async fn refresh_presence(&self, user_id: i64) -> Result<(), AppError> {
+        let presence: Option<Presence> = sqlx::query_as(
+            r#"
+            WITH live AS (
+              SELECT max(active_at) AS active_at
+              FROM presence_sessions
+              WHERE user_id = $1 AND heartbeat_at > now() - $2::interval
+            )
+            INSERT INTO user_presence (user_id, status, updated_at)
+            SELECT $1,
+              CASE
+                WHEN active_at IS NULL THEN 'offline'
+                WHEN active_at > now() - $3::interval THEN 'online'
+                ELSE 'away'
+              END::presence_status,
+              now()
+            FROM live
+            ON CONFLICT (user_id) DO UPDATE
+            SET status = EXCLUDED.status, updated_at = EXCLUDED.updated_at
+            WHERE user_presence.status <> EXCLUDED.status
+            RETURNING user_id, status, updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(SESSION_TTL)
+        .bind(AWAY_AFTER)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(presence) = presence else {
+            return Ok(());
+        };
+
+        let peers: Vec<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT DISTINCT member
+            FROM chats, unnest(members) AS member
+            WHERE $1 = ANY(members) AND member <> $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let notification = Notification::new(
+            peers.into_iter().map(|(id,)| id),
+            AppEvent::PresenceChanged(presence),
+        );
+        self.listener
+            .publish(self.config.pubsub.topic(), notification)
+            .await
+            .map_err(AppError::PubSubUnavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_db_state;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn open_streams_should_keep_users_active() -> Result<()> {
+        let (_tdb, state) = test_db_state().await?;
+        let mut sessions = HashMap::new();
+        for (user_id, stream) in [(1, true), (2, false)] {
+            let command = Command::Connect { user_id, stream };
+            state.handle_presence(&mut sessions, command).await?;
+        }
+        // neither did anything for a while
+        sqlx::query("UPDATE presence_sessions SET active_at = now() - interval '10 minutes'")
+            .execute(&state.pool)
+            .await?;
+
+        state.heartbeat(&mut sessions).await?;
+        let statuses: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT user_id, status::text FROM user_presence WHERE user_id IN (1, 2) ORDER BY user_id",
+        )
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(
+            statuses,
+            [(1, "online".to_string()), (2, "away".to_string())]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+//! Stream tickets, for clients like `EventSource` that can't set an `Authorization` header.
+//!
+//! chat_server issues them, they're redeemed here from the `ticket` query parameter or the
+//! [`STREAM_TICKET_COOKIE`] cookie, and can only be used once before they expire.
+
+use axum::{
+    extract::{Query, Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::headers::{Cookie, HeaderMapExt};
+use chat_core::middlewares::verify_token;
+use chat_core::{User, STREAM_TICKET_COOKIE};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::error::AppError;
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+struct TicketParams {
+    ticket: Option<String>,
+}
+
+/// Authenticate with a stream ticket if there is one and no bearer token, with the token otherwise.
+pub(crate) async fn verify_ticket<T>(
+    State(state): State<AppState<T>>,
+    mut req: Request,
+    next: Next,
+) -> Response
+where
+    T: Clone + Send + Sync + 'static,
+{
+    let ticket = if req.headers().contains_key(AUTHORIZATION) {
+        None
+    } else {
+        ticket_from(&req)
+    };
+    let Some(ticket) = ticket else {
+        return verify_token::<AppState<T>>(State(state), req, next).await;
+    };
+
+    match state.redeem_ticket(&ticket).await {
+        Ok(user) => {
+            req.extensions_mut().insert(user);
+            next.run(req).await
+        }
+        Err(e) => {
+            warn!("redeem stream ticket failed: {:?}", e);
+            e.into_response()
+        }
+    }
+}
+
+fn ticket_from(req: &Request) -> Option<String> {
+    let query = Query::<TicketParams>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(params)| params.ticket);
+    query.or_else(|| {
+        req.headers()
+            .typed_get::<Cookie>()
+            .and_then(|cookie| cookie.get(STREAM_TICKET_COOKIE).map(str::to_string))
+    })
+}
+
+impl<T: Clone> AppState<T> {
+    /// Consume `ticket`, returning the user it was issued to.
+    async fn redeem_ticket(&self, ticket: &str) -> Result<User, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            WITH redeemed AS (
+              DELETE FROM stream_tickets WHERE ticket = $1
+              RETURNING user_id, expires_at
+            )
+            SELECT u.id, u.ws_id, u.fullname, u.email, u.created_at
+            FROM redeemed r
+            JOIN users u ON u.id = r.user_id
+            WHERE r.expires_at > now()
+            "#,
+        )
+        .bind(ticket)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        user.ok_or(AppError::InvalidTicket)
+    }
+}
@@ -0,0 +1,245 @@
+//! Typing indicators, relayed to the other members of a chat without touching the database.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chat_core::{AppEvent, Notification, Publisher, Typing};
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::time::{sleep_until, Instant};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// How long an indicator lasts unless the client refreshes it.
+const TYPING_TTL: Duration = Duration::from_secs(6);
+/// Refreshes within this interval only extend the indicator, they're not relayed again.
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+/// Max typing frames a user may send per [`RATE_WINDOW`].
+const RATE_LIMIT: u32 = 20;
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub(crate) struct TypingTracker {
+    /// Active indicators by user and chat id.
+    active: DashMap<(i64, i64), Indicator>,
+    /// Connections typing, by user and chat id. An indicator is only stopped once none is.
+    typists: DashMap<(i64, i64), HashSet<Uuid>>,
+    windows: DashMap<i64, RateWindow>,
+}
+
+struct Indicator {
+    members: Vec<i64>,
+    expires_at: Instant,
+    published_at: Instant,
+}
+
+struct RateWindow {
+    started_at: Instant,
+    count: u32,
+}
+
+impl TypingTracker {
+    fn check_rate(&self, user_id: i64) -> Result<(), AppError> {
+        let now = Instant::now();
+        let mut window = self.windows.entry(user_id).or_insert(RateWindow {
+            started_at: now,
+            count: 0,
+        });
+        if now - window.started_at >= RATE_WINDOW {
+            window.started_at = now;
+            window.count = 0;
+        }
+
+        window.count += 1;
+        if window.count > RATE_LIMIT {
+            return Err(AppError::RateLimited(format!(
+                "more than {} typing frames in {:?}",
+                RATE_LIMIT, RATE_WINDOW
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<T> AppState<T>
+where
+    T: Publisher + Clone + Send + Sync + 'static,
+{
+    /// Relay that `user_id` started or stopped typing in `chat_id` on connection `conn_id`.
+    pub(crate) async fn typing(
+        &self,
+        user_id: i64,
+        conn_id: Uuid,
+        chat_id: i64,
+        typing: bool,
+    ) -> Result<(), AppError> {
+        self.typing.check_rate(user_id)?;
+        if typing {
+            self.start_typing(user_id, chat_id).await?;
+            self.typing
+                .typists
+                .entry((user_id, chat_id))
+                .or_default()
+                .insert(conn_id);
+        } else {
+            self.leave_typing(user_id, conn_id, chat_id).await;
+        }
+        Ok(())
+    }
+
+    /// Stop the indicators connection `conn_id` of `user_id` has in `chat_ids` once it's gone,
+    /// those its other connections keep up aside.
+    pub(crate) async fn clear_typing(
+        &self,
+        user_id: i64,
+        conn_id: Uuid,
+        chat_ids: impl IntoIterator<Item = i64>,
+    ) {
+        for chat_id in chat_ids {
+            self.leave_typing(user_id, conn_id, chat_id).await;
+        }
+        self.typing.windows.remove_if(&user_id, |_, window| {
+            window.started_at.elapsed() >= RATE_WINDOW
+        });
+    }
+
+    async fn start_typing(&self, user_id: i64, chat_id: i64) -> Result<(), AppError> {
+        let now = Instant::now();
+        if let Some(mut indicator) = self.typing.active.get_mut(&(user_id, chat_id)) {
+            indicator.expires_at = now + TYPING_TTL;
+            if now - indicator.published_at < TYPING_REFRESH {
+                return Ok(());
+            }
+            indicator.published_at = now;
+            let members = indicator.members.clone();
+            drop(indicator);
+
+            self.publish_typing(user_id, chat_id, members, true).await;
+            return Ok(());
+        }
+
+        let members = self.chat_members(user_id, chat_id).await?;
+        match self.typing.active.entry((user_id, chat_id)) {
+            // started from another connection in the meantime
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().expires_at = now + TYPING_TTL;
+                return Ok(());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Indicator {
+                    members: members.clone(),
+                    expires_at: now + TYPING_TTL,
+                    published_at: now,
+                });
+            }
+        }
+        self.publish_typing(user_id, chat_id, members, true).await;
+
+        let state = self.clone();
+        tokio::spawn(async move { state.expire_typing(user_id, chat_id).await });
+        Ok(())
+    }
+
+    /// Connection `conn_id` stopped typing in `chat_id`, the indicator too unless another one
+    /// of the user's is still typing there.
+    async fn leave_typing(&self, user_id: i64, conn_id: Uuid, chat_id: i64) {
+        let key = (user_id, chat_id);
+        if let Some(mut typists) = self.typing.typists.get_mut(&key) {
+            typists.remove(&conn_id);
+        }
+        let last = self
+            .typing
+            .typists
+            .remove_if(&key, |_, typists| typists.is_empty())
+            .is_some()
+            || !self.typing.typists.contains_key(&key);
+        if last {
+            self.stop_typing(user_id, chat_id).await;
+        }
+    }
+
+    async fn stop_typing(&self, user_id: i64, chat_id: i64) {
+        if let Some((_, indicator)) = self.typing.active.remove(&(user_id, chat_id)) {
+            self.publish_typing(user_id, chat_id, indicator.members, false)
+                .await;
+        }
+    }
+
+    /// Wait for the indicator to expire, clearing it unless it's stopped or refreshed meanwhile.
+    async fn expire_typing(&self, user_id: i64, chat_id: i64) {
+        let key = (user_id, chat_id);
+        loop {
+            let Some(expires_at) = self.typing.active.get(&key).map(|i| i.expires_at) else {
+                return;
+            };
+            sleep_until(expires_at).await;
+
+            let expired = self
+                .typing
+                .active
+                .remove_if(&key, |_, indicator| indicator.expires_at <= Instant::now());
+            if let Some((_, indicator)) = expired {
+                self.publish_typing(user_id, chat_id, indicator.members, false)
+                    .await;
+                return;
+            }
+        }
+    }
+
+    /// Members of `chat_id`, which `user_id` has to be one of.
+    async fn chat_members(&self, user_id: i64, chat_id: i64) -> Result<Vec<i64>, AppError> {
+        let members: Option<(Vec<i64>,)> =
+            sqlx::query_as("SELECT members FROM chats WHERE id = $1 AND $2 = ANY(members)")
+                .bind(chat_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        members
+            .map(|(members,)| members)
+            .ok_or_else(|| AppError::NotFound(format!("chat id {chat_id}")))
+    }
+
+    async fn publish_typing(&self, user_id: i64, chat_id: i64, members: Vec<i64>, typing: bool) {
+        let event = AppEvent::Typing(Typing {
+            chat_id,
+            user_id,
+            typing,
+        });
+        let others = members.into_iter().filter(|id| *id != user_id);
+        let ret = self
+            .listener
+            .publish(self.config.pubsub.topic(), Notification::new(others, event))
+            .await;
+        if let Err(e) = ret {
+            warn!(
+                "failed to publish typing of user: {}, err: {:?}",
+                user_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn check_rate_should_limit_per_user() {
+        let tracker = TypingTracker::default();
+
+        for _ in 0..RATE_LIMIT {
+            assert!(tracker.check_rate(1).is_ok());
+        }
+        assert!(matches!(
+            tracker.check_rate(1),
+            Err(AppError::RateLimited(_))
+        ));
+        assert!(tracker.check_rate(2).is_ok());
+
+        tokio::time::advance(RATE_WINDOW).await;
+        assert!(tracker.check_rate(1).is_ok());
+    }
+}
@@ -0,0 +1,352 @@
+//! WebSocket transport on `/ws`, streaming the same [`AppMessage`]s as `/events`.
+//!
+//! Every frame is a JSON text message tagged by `type`. Clients may send:
+//!
+//! - `{"type":"subscribe","chat_ids":[1,2]}` resumes events of the given chats.
+//! - `{"type":"unsubscribe","chat_ids":[1]}` stops events of the given chats on this connection.
+//! - `{"type":"typing","chat_id":1,"typing":true}` tells the other chat members the user started or
+//!   stopped typing. Starts have to be repeated every few seconds, or the indicator expires.
+//! - `{"type":"ack","seq":3}` acknowledges every event up to `seq`.
+//! - `{"type":"ping","nonce":"abc"}` is answered with a `pong` carrying the same `nonce`.
+//!
+//! And receive:
+//!
+//! - `{"type":"event","seq":1,"user_id":1,"event":{"event":"NewChat",...}}`, `seq` counts up from 1
+//!   on every connection.
+//! - `{"type":"pong","nonce":"abc"}`.
+//! - `{"type":"error","message":"..."}` for a frame that couldn't be handled, the connection stays
+//!   open.
+//!
+//! A connection starts subscribed to every chat of the user. On shutdown the server closes it with
+//! code 1012 (service restart), clients should reconnect.
+
+use std::collections::HashSet;
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Extension;
+use chat_core::{AppMessage, Publisher, Subscriber, User};
+use futures::{pin_mut, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::error::AppError;
+use crate::AppState;
+
+/// A frame sent by the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Subscribe {
+        chat_ids: Vec<i64>,
+    },
+    Unsubscribe {
+        chat_ids: Vec<i64>,
+    },
+    Typing {
+        chat_id: i64,
+        typing: bool,
+    },
+    Ack {
+        seq: u64,
+    },
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+    },
+}
+
+/// A frame sent by the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Event {
+        seq: u64,
+        #[serde(flatten)]
+        message: AppMessage,
+    },
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub(crate) async fn ws_handler<T>(
+    Extension(user): Extension<User>,
+    State(state): State<AppState<T>>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, AppError>
+where
+    T: Subscriber + Publisher + Clone + Send + Sync + 'static,
+{
+    // subscribe before upgrading, so limits and a failing backend are reported as plain http errors
+    let connection = state.connections.acquire(user.id, &state.config.limits)?;
+    let events = state
+        .listener
+        .subscribe(user.id as u64)
+        .await
+        .map_err(AppError::PubSubUnavailable)?;
+    Ok(ws.on_upgrade(move |socket| async move {
+        serve_socket(socket, state, user, events).await;
+        drop(connection);
+    }))
+}
+
+async fn serve_socket<T>(
+    mut socket: WebSocket,
+    state: AppState<T>,
+    user: User,
+    events: impl Stream<Item = AppMessage> + Send,
+) where
+    T: Publisher + Clone + Send + Sync + 'static,
+{
+    info!("user: {} connected over websocket", user.id);
+    let _presence = state.presence.connect(user.id, false);
+    pin_mut!(events);
+    let mut conn = Connection::default();
+
+    loop {
+        let frame = tokio::select! {
+            _ = state.shutdown.cancelled() => {
+                let close = CloseFrame {
+                    code: close_code::RESTART,
+                    reason: "server is shutting down".into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                break;
+            }
+            event = events.next() => match event {
+                Some(message) => conn.deliver(message),
+                None => break,
+            },
+            frame = socket.recv() => match frame {
+                Some(Ok(Message::Text(text))) => handle_text(&state, &user, &mut conn, &text).await,
+                // pings are answered by axum, anything else but text is ignored
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => None,
+            },
+        };
+
+        let Some(frame) = frame else {
+            continue;
+        };
+        let text = serde_json::to_string(&frame).expect("server frame should serialize");
+        if let Err(e) = socket.send(Message::Text(text)).await {
+            warn!("failed to send frame to user: {}, err: {}", user.id, e);
+            break;
+        }
+    }
+
+    state
+        .clear_typing(user.id, conn.id, conn.typing.drain())
+        .await;
+    info!(
+        "user: {} disconnected from websocket, {} events unacknowledged",
+        user.id,
+        conn.seq - conn.acked
+    );
+}
+
+async fn handle_text<T>(
+    state: &AppState<T>,
+    user: &User,
+    conn: &mut Connection,
+    text: &str,
+) -> Option<ServerFrame>
+where
+    T: Publisher + Clone + Send + Sync + 'static,
+{
+    state.presence.activity(user.id);
+    match parse(text) {
+        Ok(ClientFrame::Typing { chat_id, typing }) => {
+            match state.typing(user.id, conn.id, chat_id, typing).await {
+                Ok(()) => conn.handle(ClientFrame::Typing { chat_id, typing }),
+                Err(e) => Some(error(e.to_string())),
+            }
+        }
+        Ok(frame) => conn.handle(frame),
+        Err(e) => Some(error(format!("invalid frame: {e}"))),
+    }
+}
+
+/// The state of one websocket connection.
+#[derive(Debug)]
+struct Connection {
+    /// Tells the user's connections apart, see [`AppState::clear_typing`].
+    id: Uuid,
+    unsubscribed: HashSet<i64>,
+    /// Chats the user is typing in, cleared when the connection goes away.
+    typing: HashSet<i64>,
+    /// Sequence number of the last event sent.
+    seq: u64,
+    /// Sequence number of the last event acknowledged by the client.
+    acked: u64,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            id: Uuid::now_v7(),
+            unsubscribed: HashSet::new(),
+            typing: HashSet::new(),
+            seq: 0,
+            acked: 0,
+        }
+    }
+}
+
+impl Connection {
+    /// Turn an event into a frame, unless the client unsubscribed from its chat.
+    fn deliver(&mut self, message: AppMessage) -> Option<ServerFrame> {
+        if let Some(chat_id) = message.event.chat_id() {
+            if self.unsubscribed.contains(&chat_id) {
+                return None;
+            }
+        }
+
+        self.seq += 1;
+        Some(ServerFrame::Event {
+            seq: self.seq,
+            message,
+        })
+    }
+
+    /// Handle a client frame, returning the reply if there is one.
+    ///
+    /// Typing frames are only recorded here, relaying them is up to the caller.
+    fn handle(&mut self, frame: ClientFrame) -> Option<ServerFrame> {
+        match frame {
+            ClientFrame::Subscribe { chat_ids } => {
+                for chat_id in chat_ids {
+                    self.unsubscribed.remove(&chat_id);
+                }
+                None
+            }
+            ClientFrame::Unsubscribe { chat_ids } => {
+                self.unsubscribed.extend(chat_ids);
+                None
+            }
+            ClientFrame::Typing { chat_id, typing } => {
+                if typing {
+                    self.typing.insert(chat_id);
+                } else {
+                    self.typing.remove(&chat_id);
+                }
+                None
+            }
+            ClientFrame::Ack { seq } if seq > self.seq => {
+                Some(error(format!("ack for unsent event: {seq}")))
+            }
+            ClientFrame::Ack { seq } => {
+                self.acked = self.acked.max(seq);
+                None
+            }
+            ClientFrame::Ping { nonce } => Some(ServerFrame::Pong { nonce }),
+        }
+    }
+}
+
+fn parse(text: &str) -> Result<ClientFrame, serde_json::Error> {
+    serde_json::from_str(text)
+}
+
+fn error(message: impl Into<String>) -> ServerFrame {
+    ServerFrame::Error {
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{AppEvent, Chat, ChatType};
+
+    fn new_chat(id: i64) -> AppMessage {
+        AppMessage {
+            user_id: 1,
+            event: AppEvent::NewChat(Chat {
+                id,
+                ws_id: 1,
+                name: None,
+                r#type: ChatType::Single,
+                members: vec![1, 2],
+                created_at: Default::default(),
+                topic: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn frames_should_follow_schema() {
+        let frame: ClientFrame = serde_json::from_str(r#"{"type":"ping"}"#).unwrap();
+        assert_eq!(ClientFrame::Ping { nonce: None }, frame);
+
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"type":"typing","chat_id":1,"typing":true}"#).unwrap();
+        assert_eq!(
+            ClientFrame::Typing {
+                chat_id: 1,
+                typing: true
+            },
+            frame
+        );
+
+        let frame = ServerFrame::Event {
+            seq: 1,
+            message: new_chat(1),
+        };
+        let data = serde_json::to_string(&frame).unwrap();
+        assert!(
+            data.starts_with(r#"{"type":"event","seq":1,"user_id":1,"event":{"event":"NewChat""#)
+        );
+        assert_eq!(frame, serde_json::from_str(&data).unwrap());
+    }
+
+    #[test]
+    fn connection_should_skip_unsubscribed_chats() {
+        let mut conn = Connection::default();
+
+        assert!(conn
+            .handle(parse(r#"{"type":"unsubscribe","chat_ids":[1]}"#).unwrap())
+            .is_none());
+        assert!(conn.deliver(new_chat(1)).is_none());
+        assert!(matches!(
+            conn.deliver(new_chat(2)),
+            Some(ServerFrame::Event { seq: 1, .. })
+        ));
+
+        conn.handle(parse(r#"{"type":"subscribe","chat_ids":[1]}"#).unwrap());
+        assert!(matches!(
+            conn.deliver(new_chat(1)),
+            Some(ServerFrame::Event { seq: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn connection_should_reply_to_client_frames() {
+        let mut conn = Connection::default();
+
+        assert_eq!(
+            Some(ServerFrame::Pong {
+                nonce: Some("abc".to_string())
+            }),
+            conn.handle(parse(r#"{"type":"ping","nonce":"abc"}"#).unwrap())
+        );
+        assert!(matches!(
+            conn.handle(parse(r#"{"type":"ack","seq":1}"#).unwrap()),
+            Some(ServerFrame::Error { .. })
+        ));
+        assert!(parse("not json").is_err());
+
+        conn.deliver(new_chat(1));
+        assert!(conn
+            .handle(parse(r#"{"type":"ack","seq":1}"#).unwrap())
+            .is_none());
+        assert_eq!(1, conn.acked);
+    }
+}